@@ -0,0 +1,284 @@
+use anyhow::{Context, Result};
+use log::{error, info};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+
+use crate::flasher::{self, Credentials, UnitInfo};
+use crate::session::DeviceSession;
+use crate::history::Operation;
+use crate::recipe::Recipe;
+
+#[derive(Debug, Clone)]
+pub(crate) struct BatchDevice {
+    pub host: String,
+    pub port: u16,
+    pub password: Option<String>,
+}
+
+impl BatchDevice {
+    pub fn label(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct BatchResult {
+    pub device: BatchDevice,
+    pub soc: Option<String>,
+    pub firmware: Option<PathBuf>,
+    pub duration: Duration,
+    pub error: Option<String>,
+}
+
+// One device per line: `host[,port[,password]]`, a comma or whitespace between fields.
+// The password is the rest of the line, so it may hold commas and spaces; only the
+// whitespace around it is dropped. Empty lines and lines starting with '#' are skipped.
+pub(crate) fn parse_device_list(text: &str) -> Result<Vec<BatchDevice>> {
+    let mut devices = Vec::new();
+    for (no, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (host, rest) = next_field(line);
+        let (port, password) = match rest {
+            Some(rest) => next_field(rest),
+            None => ("", None),
+        };
+        let port = match port {
+            "" => 22,
+            port => port
+                .parse()
+                .with_context(|| format!("line {}: invalid port '{}'", no + 1, port))?,
+        };
+        devices.push(BatchDevice {
+            host: host.to_string(),
+            port,
+            password: password.map(|p| p.to_string()),
+        });
+    }
+    Ok(devices)
+}
+
+// The text up to the first separator and what follows it, if anything
+fn next_field(text: &str) -> (&str, Option<&str>) {
+    match text.find(|c: char| c == ',' || c.is_whitespace()) {
+        Some(end) => {
+            let rest = text[end..].trim_start();
+            let rest = rest.strip_prefix(',').map(str::trim_start).unwrap_or(rest);
+            (&text[..end], Some(rest).filter(|rest| !rest.is_empty()))
+        }
+        None => (text, None),
+    }
+}
+
+// Pick the image matching the recipe's firmware pattern from the folder, the newest one if there are several
+pub(crate) fn find_firmware(dir: &Path, soc: &str, recipe: &Recipe) -> Result<PathBuf> {
+    let mut best: Option<(std::time::SystemTime, PathBuf)> = None;
+    for entry in fs::read_dir(dir).with_context(|| format!("Failed to read {}", dir.display()))? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if !recipe.matches_firmware(&name, soc) {
+            continue;
+        }
+        let modified = entry.metadata().and_then(|m| m.modified()).unwrap_or(std::time::UNIX_EPOCH);
+        if best.as_ref().map(|(time, _)| modified > *time).unwrap_or(true) {
+            best = Some((modified, entry.path()));
+        }
+    }
+    best.map(|(_, path)| path)
+        .with_context(|| format!("No {} firmware in {}", recipe.firmware_pattern(soc), dir.display()))
+}
+
+// What happened to one device, filled in as far as the flash got
+#[derive(Default)]
+struct DeviceOutcome {
+    before: Option<UnitInfo>,
+    after: Option<UnitInfo>,
+    soc: Option<String>,
+    firmware: Option<PathBuf>,
+}
+
+async fn flash_device<F>(session: &DeviceSession, recipe: &Recipe, firmware_dir: &Path, outcome: &mut DeviceOutcome, mut status: F) -> Result<()>
+where F: FnMut(&str) {
+    let soc = flasher::detect_soc(session, &mut status).await?;
+    outcome.soc = Some(soc.clone());
+    let firmware = find_firmware(firmware_dir, &soc, recipe)?;
+    outcome.firmware = Some(firmware.clone());
+    let src = firmware.to_string_lossy().to_string();
+    status(&format!("Flashing {}", src));
+    let before = flasher::flash(session, recipe, &src, &mut status, |_| {}).await?;
+    outcome.before = Some(before.clone());
+    if recipe.reboot {
+        let after = flasher::wait_for_reboot(session, &before.soc, &mut status, |_| {}).await?;
+        outcome.after = Some(after);
+    }
+    Ok(())
+}
+
+// Flash every device with the firmware matching its SoC, at most `jobs` at a time.
+// `status` gets the index of the device and a log line; results keep the input order.
+pub(crate) async fn run_batch<C, F>(devices: Vec<BatchDevice>, recipe: Recipe, firmware_dir: PathBuf, jobs: usize, credentials_for: C, status: F) -> Vec<BatchResult>
+where
+    C: Fn(&BatchDevice) -> Credentials,
+    F: Fn(usize, &str) + Send + Sync + 'static,
+{
+    info!("Batch flashing {} devices, {} at a time", devices.len(), jobs);
+    let status = Arc::new(status);
+    let recipe = Arc::new(recipe);
+    let firmware_dir = Arc::new(firmware_dir);
+    let limit = Arc::new(Semaphore::new(jobs.max(1)));
+    let mut tasks = JoinSet::new();
+    for (index, device) in devices.into_iter().enumerate() {
+        let mut credentials = credentials_for(&device);
+        if let Some(password) = &device.password {
+            credentials.candidates.insert(0, password.clone());
+        }
+        let status = status.clone();
+        let recipe = recipe.clone();
+        let firmware_dir = firmware_dir.clone();
+        let limit = limit.clone();
+        tasks.spawn(async move {
+            let _permit = limit.acquire().await;
+            status(index, "Started");
+            let started = Instant::now();
+            let mut operation = Operation::new("flash", &device.host, device.port);
+            let mut outcome = DeviceOutcome::default();
+            // One connection per device, used from detection to the check after the reboot
+            let session = DeviceSession::new(&device.host, device.port, credentials);
+            let result = flash_device(&session, &recipe, &firmware_dir, &mut outcome, |msg| {
+                operation.log(msg);
+                status(index, msg)
+            })
+            .await;
+            session.close().await;
+            if let Some(soc) = &outcome.soc {
+                operation.set_soc(soc);
+            }
+            if let Some(firmware) = &outcome.firmware {
+                operation.set_firmware(&firmware.to_string_lossy());
+            }
+            if let Some(before) = &outcome.before {
+                operation.set_unit_before(before);
+            }
+            if let Some(after) = &outcome.after {
+                operation.set_unit_after(after);
+            }
+            operation.finish(result.as_ref().err());
+            let error = result.err().map(|e| {
+                error!("{}: {:?}", device.label(), e);
+                format!("{:#}", e)
+            });
+            status(index, if error.is_none() { "Done" } else { "Failed" });
+            (index, BatchResult {
+                device,
+                soc: outcome.soc,
+                firmware: outcome.firmware,
+                duration: started.elapsed(),
+                error,
+            })
+        });
+    }
+
+    let mut results = Vec::new();
+    while let Some(res) = tasks.join_next().await {
+        match res {
+            Ok(result) => results.push(result),
+            Err(e) => error!("batch task failed: {:?}", e),
+        }
+    }
+    results.sort_by_key(|(index, _)| *index);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+pub(crate) fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    format!("{}:{:02}", secs / 60, secs % 60)
+}
+
+// Plain text summary, one row per device
+pub(crate) fn summary_table(results: &[BatchResult]) -> String {
+    let rows: Vec<[String; 6]> = results
+        .iter()
+        .map(|r| {
+            [
+                r.device.label(),
+                r.soc.clone().unwrap_or_else(|| "-".to_string()),
+                r.firmware
+                    .as_ref()
+                    .and_then(|firmware| firmware.file_name())
+                    .map(|name| name.to_string_lossy().to_string())
+                    .unwrap_or_else(|| "-".to_string()),
+                if r.error.is_none() { "OK".to_string() } else { "FAILED".to_string() },
+                format_duration(r.duration),
+                r.error.clone().unwrap_or_default(),
+            ]
+        })
+        .collect();
+    let header = ["DEVICE", "SOC", "FIRMWARE", "RESULT", "TIME", "ERROR"].map(|h| h.to_string());
+    let mut widths = header.clone().map(|h| h.len());
+    for row in rows.iter() {
+        for (width, cell) in widths.iter_mut().zip(row.iter()) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    let mut out = String::new();
+    for row in std::iter::once(&header).chain(rows.iter()) {
+        let line = row
+            .iter()
+            .zip(widths.iter())
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect::<Vec<_>>()
+            .join("  ");
+        out.push_str(line.trim_end());
+        out.push('\n');
+    }
+    let failed = results.iter().filter(|r| r.error.is_some()).count();
+    out.push_str(&format!("{} devices, {} ok, {} failed\n", results.len(), results.len() - failed, failed));
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_hosts_ports_and_passwords() {
+        let devices = parse_device_list("# fleet\n\n192.168.1.10\ncam.local 2222\n10.0.0.5,,secret\n").unwrap();
+        assert_eq!(devices.len(), 3);
+        assert_eq!((devices[0].host.as_str(), devices[0].port, devices[0].password.as_deref()), ("192.168.1.10", 22, None));
+        assert_eq!((devices[1].host.as_str(), devices[1].port), ("cam.local", 2222));
+        assert_eq!((devices[2].port, devices[2].password.as_deref()), (22, Some("secret")));
+    }
+
+    #[test]
+    fn takes_the_password_from_the_rest_of_the_line() {
+        let devices = parse_device_list("10.0.0.5, 22, pass, with spaces  \n").unwrap();
+        assert_eq!(devices[0].password.as_deref(), Some("pass, with spaces"));
+    }
+
+    #[test]
+    fn reports_the_line_of_a_bad_port() {
+        let error = parse_device_list("10.0.0.5\n10.0.0.6,ssh").unwrap_err();
+        assert_eq!(error.to_string(), "line 2: invalid port 'ssh'");
+    }
+
+    #[test]
+    fn summary_shows_the_firmware_file() {
+        let device = BatchDevice { host: "10.0.0.5".to_string(), port: 22, password: None };
+        let table = summary_table(&[BatchResult {
+            device,
+            soc: Some("ssc338q".to_string()),
+            firmware: Some(PathBuf::from("/fw/ruby-ssc338q.tar")),
+            duration: Duration::from_secs(75),
+            error: None,
+        }]);
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines[0], "DEVICE       SOC      FIRMWARE          RESULT  TIME  ERROR");
+        assert_eq!(lines[1], "10.0.0.5:22  ssc338q  ruby-ssc338q.tar  OK      1:15");
+    }
+}
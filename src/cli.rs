@@ -0,0 +1,209 @@
+use clap::{Parser, Subcommand};
+use log::error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::batch;
+use crate::events::{self, Event};
+use crate::flasher::Credentials;
+use crate::history;
+use crate::recipe;
+use crate::server;
+#[cfg(feature = "tui")]
+use crate::tui;
+
+#[derive(Parser)]
+#[command(name = "ruby-flasher", version, about = "RubyFPV firmware flasher. Starts the GUI, or the terminal UI in builds without it, when run without a command.")]
+pub(crate) struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+impl Cli {
+    // Whether this run ends up in the terminal UI, asked for or as the only frontend built
+    #[cfg(feature = "tui")]
+    pub fn runs_tui(&self) -> bool {
+        match &self.command {
+            Some(Command::Tui) => true,
+            Some(_) => false,
+            None => !cfg!(feature = "gui"),
+        }
+    }
+}
+
+#[derive(Subcommand)]
+pub(crate) enum Command {
+    /// Flash a list of devices, each with the firmware matching its SoC
+    Batch {
+        /// File with one device per line: host[,port[,password]], the password is the rest of the line
+        #[arg(long)]
+        devices: PathBuf,
+        /// Folder with the firmware images, picked by the recipe's file pattern
+        #[arg(long)]
+        firmware_dir: PathBuf,
+        /// Flash recipe: the name of a known recipe or a recipe file
+        #[arg(long, default_value = "RubyFPV")]
+        recipe: String,
+        /// How many devices to flash at the same time
+        #[arg(long, short, default_value_t = 4)]
+        jobs: usize,
+        /// Password to try on every device, can be repeated
+        #[arg(long = "password")]
+        passwords: Vec<String>,
+        /// Print every event as a JSON line on stdout instead of the text log
+        #[arg(long)]
+        json: bool,
+    },
+    /// Serve the HTTP API on localhost for other programs to drive the flasher
+    Serve {
+        /// Port on 127.0.0.1 to listen on
+        #[arg(long, default_value_t = 8642)]
+        port: u16,
+        /// Flash recipe used when a request names none
+        #[arg(long, default_value = "RubyFPV")]
+        recipe: String,
+        /// Bearer token clients must send, a random one is printed if not given
+        #[arg(long)]
+        token: Option<String>,
+        /// Password to try on every device, can be repeated
+        #[arg(long = "password")]
+        passwords: Vec<String>,
+    },
+    /// List the known flash recipes, the built-in one and those in the config folder
+    Recipes,
+    /// Write the history of detect, flash and reset operations to a file
+    ExportHistory {
+        /// File to write, JSON lines if it ends in .jsonl and CSV otherwise
+        file: PathBuf,
+    },
+    /// Start the terminal UI, for machines without a display
+    #[cfg(feature = "tui")]
+    Tui,
+}
+
+// Runs a headless command and returns the process exit code
+pub(crate) async fn run(command: Command) -> i32 {
+    match command {
+        Command::Batch {
+            devices,
+            firmware_dir,
+            recipe,
+            jobs,
+            passwords,
+            json,
+        } => {
+            if json {
+                events::set_sink(Some(Box::new(std::io::stdout())));
+            }
+            run_batch(devices, firmware_dir, recipe, jobs, passwords, json).await
+        }
+        Command::Serve {
+            port,
+            recipe,
+            token,
+            passwords,
+        } => run_server(port, recipe, token, passwords).await,
+        Command::Recipes => {
+            for recipe in recipe::load_all() {
+                println!("{}", format!("{:20}  {}", recipe.name, recipe.description).trim_end());
+            }
+            0
+        }
+        Command::ExportHistory { file } => export_history(&file),
+        #[cfg(feature = "tui")]
+        Command::Tui => {
+            tui::run();
+            0
+        }
+    }
+}
+
+fn export_history(file: &Path) -> i32 {
+    let records = history::load();
+    let result = if file.extension().is_some_and(|ext| ext == "jsonl") {
+        history::export_jsonl(&records, file)
+    } else {
+        history::export_csv(&records, file)
+    };
+    match result {
+        Ok(()) => {
+            println!("Exported {} records to {}", records.len(), file.display());
+            0
+        }
+        Err(e) => {
+            error!("error: {:?}", e);
+            eprintln!("Error: {:#}", e);
+            2
+        }
+    }
+}
+
+async fn run_server(port: u16, recipe: String, token: Option<String>, passwords: Vec<String>) -> i32 {
+    let result = match recipe::find(&recipe) {
+        Ok(recipe) => server::serve(port, token, passwords, recipe).await,
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            error!("error: {:?}", e);
+            eprintln!("Error: {:#}", e);
+            2
+        }
+    }
+}
+
+async fn run_batch(devices: PathBuf, firmware_dir: PathBuf, recipe: String, jobs: usize, passwords: Vec<String>, json: bool) -> i32 {
+    let recipe = match recipe::find(&recipe) {
+        Ok(recipe) => recipe,
+        Err(e) => {
+            error!("error: {:?}", e);
+            eprintln!("Error: {:#}", e);
+            return 2;
+        }
+    };
+    let devices = match fs::read_to_string(&devices)
+        .map_err(anyhow::Error::from)
+        .and_then(|text| batch::parse_device_list(&text))
+    {
+        Ok(devices) => devices,
+        Err(e) => {
+            error!("error: {:?}", e);
+            eprintln!("Error: {}: {}", devices.display(), e);
+            return 2;
+        }
+    };
+    if devices.is_empty() {
+        eprintln!("Error: the device list is empty");
+        return 2;
+    }
+
+    let labels: Vec<String> = devices.iter().map(|d| d.label()).collect();
+    let results = batch::run_batch(
+        devices,
+        recipe,
+        firmware_dir,
+        jobs,
+        |_| Credentials::new(passwords.clone()),
+        move |index, msg| {
+            // In JSON mode the events carry the same information
+            if !json {
+                println!("[{}] {}", labels[index], msg)
+            }
+        },
+    )
+    .await;
+
+    let failed = results.iter().filter(|r| r.error.is_some()).count();
+    if json {
+        events::emit_global(Event::Summary { devices: results.len(), failed });
+    } else {
+        println!();
+        print!("{}", batch::summary_table(&results));
+    }
+    if failed == 0 {
+        0
+    } else {
+        1
+    }
+}
@@ -0,0 +1,104 @@
+use anyhow::{Context, Result};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+
+use crate::config_dir;
+
+const CONFIG_FILE: &str = "config.json";
+const MAX_RECENT_DEVICES: usize = 16;
+
+// How the app last authenticated against a device
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum AuthMethod {
+    #[default]
+    DefaultPassword,
+    Password,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct Device {
+    #[serde(default)]
+    pub nickname: String,
+    pub ip: String,
+    pub port: u16,
+    #[serde(default)]
+    pub last_soc: Option<String>,
+    #[serde(default)]
+    pub auth: AuthMethod,
+}
+
+impl Device {
+    // Text shown in the recent devices dropdown
+    pub fn label(&self) -> String {
+        if self.nickname.is_empty() {
+            format!("{}:{}", self.ip, self.port)
+        } else {
+            format!("{} ({}:{})", self.nickname, self.ip, self.port)
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct Config {
+    // Most recently used first
+    #[serde(default)]
+    pub devices: Vec<Device>,
+    // Passwords are remembered in the credential vault, never in this file
+    #[serde(default)]
+    pub remember_passwords: bool,
+    // File the GUI appends JSON events to, none when the event log is off
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub event_log: Option<PathBuf>,
+}
+
+impl Config {
+    fn path() -> Option<PathBuf> {
+        config_dir().map(|dir| dir.join(CONFIG_FILE))
+    }
+
+    // Load the config, falling back to defaults if it is missing or broken
+    pub fn load() -> Config {
+        let path = match Self::path() {
+            Some(path) => path,
+            None => return Config::default(),
+        };
+        match fs::read_to_string(&path) {
+            Ok(data) => match serde_json::from_str(&data) {
+                Ok(config) => {
+                    info!("Loaded config from {}", path.display());
+                    config
+                }
+                Err(e) => {
+                    error!("Failed to parse {}: {}", path.display(), e);
+                    Config::default()
+                }
+            },
+            Err(_) => Config::default(),
+        }
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let path = Self::path().context("No config directory on this platform")?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+        let data = serde_json::to_string_pretty(self)?;
+        fs::write(&path, data).with_context(|| format!("Failed to write {}", path.display()))?;
+        Ok(())
+    }
+
+    // Move the device to the top of the recent list, keeping its nickname
+    pub fn remember(&mut self, mut device: Device) {
+        if let Some(pos) = self.devices.iter().position(|d| d.ip == device.ip && d.port == device.port) {
+            let old = self.devices.remove(pos);
+            if device.nickname.is_empty() {
+                device.nickname = old.nickname;
+            }
+        }
+        self.devices.insert(0, device);
+        self.devices.truncate(MAX_RECENT_DEVICES);
+    }
+}
@@ -0,0 +1,391 @@
+use anyhow::Result;
+use log::error;
+use russh::{ChannelMsg, Sig};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+use crate::config_dir;
+use crate::remote::RemoteCommand;
+use crate::session::DeviceSession;
+use crate::shell::{self, Shell, ShellEvent};
+
+const HISTORY_FILE: &str = "command_history.txt";
+const MAX_HISTORY: usize = 500;
+// More candidates than this are not worth a menu
+const MAX_COMPLETIONS: usize = 200;
+// Limit for the helper commands run next to the shell
+const TIMEOUT_QUERY: u64 = 5;
+// Longest first line taken for the PID, anything longer is shell output
+const MAX_PID_LINE: usize = 32;
+
+// Ready-made commands for the manual mode, as (menu label, command)
+pub(crate) const SNIPPETS: &[(&str, &str)] = &[
+    ("System/Firmware environment", "fw_printenv"),
+    ("System/SoC", "fw_printenv -n soc"),
+    ("System/Firmware version", "cat /etc/os-release"),
+    ("System/Flash partitions", "cat /proc/mtd"),
+    ("System/Disk usage", "df -h"),
+    ("System/Memory", "free"),
+    ("System/Processes", "ps"),
+    ("System/Uptime and load", "uptime"),
+    ("Logs/System log", "logread | tail -n 100"),
+    ("Logs/Follow system log", "logread -f"),
+    ("Logs/Kernel log", "dmesg | tail -n 100"),
+    ("Logs/Ruby logs", "ls -l /tmp/ruby/logs/ && tail -n 50 /tmp/ruby/logs/*"),
+    ("Ruby/Stop Ruby", "ruby_stop.sh"),
+    ("Ruby/Start Ruby", "ruby_start.sh"),
+    ("Ruby/Ruby processes", "ps | grep -i ruby | grep -v grep"),
+    ("Wi-Fi/Interfaces", "ip addr"),
+    ("Wi-Fi/Wireless status", "iw dev"),
+    ("Wi-Fi/Link and signal", "for i in $(ls /sys/class/net | grep -E '^wl'); do echo \"== $i\"; iw dev $i link; iw dev $i info; done"),
+    ("Wi-Fi/Loaded drivers", "lsmod"),
+    ("Wi-Fi/USB devices", "lsusb"),
+    ("Wi-Fi/Regulatory domain", "iw reg get"),
+];
+
+// Commands typed in manual mode, oldest first, kept between sessions
+#[derive(Debug, Default)]
+pub(crate) struct History {
+    entries: Vec<String>,
+    // Entry shown while browsing with up/down, None when editing a new line
+    position: Option<usize>,
+    // What was typed before browsing started
+    draft: String,
+}
+
+impl History {
+    fn path() -> Option<PathBuf> {
+        config_dir().map(|dir| dir.join(HISTORY_FILE))
+    }
+
+    pub fn load() -> History {
+        let entries = Self::path()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .map(|text| text.lines().filter(|l| !l.trim().is_empty()).map(|l| l.to_string()).collect())
+            .unwrap_or_default();
+        History {
+            entries,
+            ..Default::default()
+        }
+    }
+
+    fn save(&self) {
+        let path = match Self::path() {
+            Some(path) => path,
+            None => return,
+        };
+        if let Some(dir) = path.parent() {
+            let _ = fs::create_dir_all(dir);
+        }
+        let mut text = self.entries.join("\n");
+        text.push('\n');
+        if let Err(e) = fs::write(&path, text) {
+            error!("Failed to write {}: {:?}", path.display(), e);
+        }
+    }
+
+    pub fn push(&mut self, command: &str) {
+        self.position = None;
+        self.draft.clear();
+        let command = command.trim();
+        if command.is_empty() || self.entries.last().map(|l| l == command).unwrap_or(false) {
+            return;
+        }
+        self.entries.push(command.to_string());
+        if self.entries.len() > MAX_HISTORY {
+            self.entries.remove(0);
+        }
+        self.save();
+    }
+
+    // Older entry, `current` is the line being edited
+    pub fn previous(&mut self, current: &str) -> Option<String> {
+        let position = match self.position {
+            Some(0) => return None,
+            Some(p) => p - 1,
+            None if self.entries.is_empty() => return None,
+            None => {
+                self.draft = current.to_string();
+                self.entries.len() - 1
+            }
+        };
+        self.position = Some(position);
+        Some(self.entries[position].clone())
+    }
+
+    // Newer entry, the draft again after the newest one
+    pub fn next(&mut self) -> Option<String> {
+        let position = self.position?;
+        if position + 1 < self.entries.len() {
+            self.position = Some(position + 1);
+            Some(self.entries[position + 1].clone())
+        } else {
+            self.position = None;
+            Some(std::mem::take(&mut self.draft))
+        }
+    }
+}
+
+// Called with a sent line once it is known the shell read it at its prompt
+type Entered = Box<dyn FnOnce(&str) + Send>;
+
+// The manual mode's shell: a login shell that also takes typed lines and can have its
+// foreground command stopped. Cheap to clone like the shell itself.
+#[derive(Clone)]
+pub(crate) struct Console {
+    shell: Shell,
+    session: Arc<DeviceSession>,
+    // The shell's PID on the device, once it reported it
+    pid: Arc<OnceLock<u32>>,
+    lines: mpsc::UnboundedSender<(String, Entered)>,
+}
+
+impl Console {
+    pub fn send(&self, data: &[u8]) {
+        self.shell.send(data);
+    }
+
+    pub fn resize(&self, cols: u32, rows: u32) {
+        self.shell.resize(cols, rows);
+    }
+
+    pub fn close(&self) {
+        self.shell.close();
+    }
+
+    pub fn interrupt(&self) {
+        self.shell.interrupt();
+    }
+
+    // Send a line and Enter. Lines go out in order; `entered` only gets the line when the
+    // shell read it at its prompt, not when a command was reading input such as a password.
+    pub fn send_line<F>(&self, line: &str, entered: F)
+    where
+        F: FnOnce(&str) + Send + 'static,
+    {
+        let _ = self.lines.send((line.to_string(), Box::new(entered)));
+    }
+
+    pub fn pid(&self) -> Option<u32> {
+        self.pid.get().copied()
+    }
+
+    // For commands that ignore Ctrl-C: SIGTERM for the shell's foreground process group.
+    // The shell itself and its channel stay, so the session goes on.
+    pub fn terminate(&self) {
+        self.shell.signal(Sig::TERM);
+        let pid = match self.pid() {
+            Some(pid) => pid,
+            None => return,
+        };
+        let command = format!(
+            "pg=$({}) && [ \"$pg\" -gt 0 ] && [ \"$pg\" != {pid} ] && kill -TERM -- -\"$pg\"",
+            foreground_group(pid),
+        );
+        let console = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = console.query(&command).await {
+                error!("Failed to terminate the foreground command: {:?}", e);
+            }
+        });
+    }
+
+    // Run a command on its own channel of the same connection and return its stdout.
+    // Nothing shows up in the shell.
+    pub async fn query(&self, command: &str) -> Result<String> {
+        query(&self.session, command).await
+    }
+}
+
+// Start a login shell for the manual mode. It first prints its PID, which is taken
+// out of the output. `event` is called from a background task.
+pub(crate) async fn open<F>(session: Arc<DeviceSession>, cols: u32, rows: u32, mut event: F) -> Result<Console>
+where
+    F: FnMut(ShellEvent) + Send + 'static,
+{
+    let pid = Arc::new(OnceLock::new());
+    let reported = pid.clone();
+    // Output up to the PID line, None once it was read
+    let mut first_line = Some(String::new());
+    let command = format!("echo $$; {}", shell::LOGIN_SHELL);
+    let shell = shell::open(&session, &command, cols, rows, move |shell_event| match shell_event {
+        ShellEvent::Output(text) => {
+            let text = match first_line.take() {
+                Some(line) => match take_pid(line + text.as_str(), &reported) {
+                    Ok(text) => text,
+                    Err(line) => {
+                        first_line = Some(line);
+                        return;
+                    }
+                },
+                None => text,
+            };
+            if !text.is_empty() {
+                event(ShellEvent::Output(text));
+            }
+        }
+        closed => event(closed),
+    })
+    .await?;
+    let (lines, lines_rx) = mpsc::unbounded_channel();
+    tokio::spawn(send_lines(session.clone(), pid.clone(), shell.clone(), lines_rx));
+    Ok(Console { shell, session, pid, lines })
+}
+
+// The output after a complete PID line, or Err with the start of a line still short
+// enough to be one. A first line that is no number is shell output.
+fn take_pid(line: String, pid: &OnceLock<u32>) -> std::result::Result<String, String> {
+    match line.split_once('\n') {
+        Some((head, rest)) => match head.trim().parse() {
+            Ok(number) => {
+                let _ = pid.set(number);
+                Ok(rest.to_string())
+            }
+            Err(_) => Ok(line),
+        },
+        None if line.len() < MAX_PID_LINE => Err(line),
+        None => Ok(line),
+    }
+}
+
+async fn query(session: &DeviceSession, command: &str) -> Result<String> {
+    let mut channel = session.channel().await?;
+    channel.exec(true, command).await?;
+    let mut output = Vec::new();
+    let collect = async {
+        while let Some(msg) = channel.wait().await {
+            match msg {
+                ChannelMsg::Data { data } => output.extend_from_slice(&data),
+                ChannelMsg::Eof | ChannelMsg::Close => break,
+                _ => {}
+            }
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(TIMEOUT_QUERY), collect).await?;
+    let _ = channel.close().await;
+    Ok(String::from_utf8_lossy(&output).to_string())
+}
+
+// Command printing the terminal's foreground process group, field 8 of stat.
+// That is the shell's own group while it waits at its prompt.
+fn foreground_group(pid: u32) -> String {
+    format!("awk '{{sub(/.*\\) /, \"\"); print $6}}' /proc/{}/stat", pid)
+}
+
+// Whether the shell waits at its prompt. Unknown counts as no.
+async fn at_prompt(session: &DeviceSession, pid: Option<u32>) -> bool {
+    let pid = match pid {
+        Some(pid) => pid,
+        None => return false,
+    };
+    match query(session, &foreground_group(pid)).await {
+        Ok(group) => group.trim() == pid.to_string(),
+        Err(e) => {
+            error!("Failed to read the shell's foreground group: {:?}", e);
+            false
+        }
+    }
+}
+
+// Sends the lines of `send_line` one after the other, each after checking who reads it
+async fn send_lines(
+    session: Arc<DeviceSession>,
+    pid: Arc<OnceLock<u32>>,
+    shell: Shell,
+    mut lines: mpsc::UnboundedReceiver<(String, Entered)>,
+) {
+    while let Some((line, entered)) = lines.recv().await {
+        let prompt = at_prompt(&session, pid.get().copied()).await;
+        shell.send(format!("{}\n", line).as_bytes());
+        if prompt {
+            entered(&line);
+        }
+    }
+}
+
+// Where the interactive shell is, so relative paths complete like they would there.
+// Read from /proc with the PID the shell reported, empty if it did not.
+async fn shell_cwd(shell: &Console) -> String {
+    let pid = match shell.pid() {
+        Some(pid) => pid,
+        None => return String::new(),
+    };
+    let command = format!("readlink /proc/{}/cwd", pid);
+    shell.query(&command).await.map(|out| out.trim().to_string()).unwrap_or_default()
+}
+
+// Candidates for the last word of `line`, each the complete word.
+// The first word completes to commands on the device's PATH, anything with a '/' to paths.
+pub(crate) async fn complete(shell: &Console, line: &str) -> Result<Vec<String>> {
+    let word = line.rsplit(char::is_whitespace).next().unwrap_or("");
+    let first_word = !line.trim_start().contains(char::is_whitespace);
+    let mut candidates: Vec<String> = if first_word && !word.contains('/') {
+        let command = "for d in $(echo \"$PATH\" | tr ':' ' '); do ls -1 \"$d\" 2>/dev/null; done";
+        shell
+            .query(command)
+            .await?
+            .lines()
+            .filter(|name| name.starts_with(word))
+            .map(|name| name.to_string())
+            .collect()
+    } else {
+        let (dir, prefix) = match word.rfind('/') {
+            Some(i) => (&word[..=i], &word[i + 1..]),
+            None => ("", word),
+        };
+        let listing_dir = if dir.starts_with('/') {
+            dir.to_string()
+        } else {
+            let cwd = shell_cwd(shell).await;
+            if cwd.is_empty() {
+                format!("./{}", dir)
+            } else {
+                format!("{}/{}", cwd, dir)
+            }
+        };
+        // -p marks directories with a trailing slash
+        let command = format!("{} 2>/dev/null", RemoteCommand::new("ls").arg("-1ap").arg(&listing_dir));
+        shell
+            .query(&command)
+            .await?
+            .lines()
+            .filter(|name| name.starts_with(prefix) && *name != "./" && *name != "../")
+            .map(|name| format!("{}{}", dir, name))
+            .collect()
+    };
+    candidates.sort();
+    candidates.dedup();
+    candidates.truncate(MAX_COMPLETIONS);
+    Ok(candidates)
+}
+
+// Longest start shared by all candidates
+pub(crate) fn common_prefix(candidates: &[String]) -> String {
+    let mut prefix = match candidates.first() {
+        Some(first) => first.clone(),
+        None => return String::new(),
+    };
+    for candidate in &candidates[1..] {
+        while !candidate.starts_with(&prefix) {
+            prefix.pop();
+        }
+    }
+    prefix
+}
+
+// `line` with its last word replaced, a space added after a finished non-directory word
+pub(crate) fn replace_last_word(line: &str, word: &str, finished: bool) -> String {
+    let start = line
+        .char_indices()
+        .rfind(|(_, c)| c.is_whitespace())
+        .map(|(i, c)| i + c.len_utf8())
+        .unwrap_or(0);
+    let mut result = format!("{}{}", &line[..start], word);
+    if finished && !word.ends_with('/') {
+        result.push(' ');
+    }
+    result
+}
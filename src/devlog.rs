@@ -0,0 +1,133 @@
+use anyhow::{Context, Error, Result};
+use log::info;
+use russh::{ChannelMsg, Sig};
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+use tokio::sync::watch;
+
+use crate::flasher;
+use crate::session::DeviceSession;
+
+// Lines kept in the viewer, the oldest go first
+pub(crate) const MAX_LINES: usize = 20000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum LogSource {
+    System,
+    Kernel,
+}
+
+impl LogSource {
+    pub fn command(&self) -> &'static str {
+        match self {
+            LogSource::System => "logread -f",
+            // -r keeps the <priority> prefix so lines can be filtered by level
+            LogSource::Kernel => "dmesg -w -r",
+        }
+    }
+
+    pub fn tag(&self) -> &'static str {
+        match self {
+            LogSource::System => "sys",
+            LogSource::Kernel => "kern",
+        }
+    }
+}
+
+// Syslog levels, most severe first
+pub(crate) const LEVELS: [&str; 8] = ["emerg", "alert", "crit", "err", "warn", "notice", "info", "debug"];
+// Used when a line does not say
+const DEFAULT_LEVEL: u8 = 6;
+
+#[derive(Debug, Clone)]
+pub(crate) struct LogLine {
+    pub source: LogSource,
+    pub level: u8,
+    pub text: String,
+}
+
+impl LogLine {
+    // logread prints "facility.level" after the hostname, raw dmesg starts with "<priority>"
+    pub fn parse(source: LogSource, text: &str) -> LogLine {
+        let mut level = DEFAULT_LEVEL;
+        let mut text = text.to_string();
+        if let Some(rest) = text.strip_prefix('<') {
+            if let Some((priority, message)) = rest.split_once('>') {
+                if let Ok(priority) = priority.parse::<u32>() {
+                    level = (priority & 7) as u8;
+                    text = message.to_string();
+                }
+            }
+        } else if let Some(found) = text.split_whitespace().find_map(|word| {
+            let (_, name) = word.split_once('.')?;
+            let name = match name {
+                "warning" => "warn",
+                "error" => "err",
+                "emergency" | "panic" => "emerg",
+                other => other,
+            };
+            LEVELS.iter().position(|l| *l == name)
+        }) {
+            level = found as u8;
+        }
+        LogLine { source, level, text }
+    }
+
+    // `max_level` is the least severe level shown, `needle` is matched case-insensitively
+    pub fn matches(&self, max_level: u8, needle: &str) -> bool {
+        self.level <= max_level && (needle.is_empty() || self.text.to_lowercase().contains(&needle.to_lowercase()))
+    }
+
+    pub fn display(&self) -> String {
+        format!("[{}] {}", self.source.tag(), self.text)
+    }
+}
+
+pub(crate) fn save(lines: &[&LogLine], path: &Path) -> Result<()> {
+    let mut out = String::new();
+    for line in lines {
+        out.push_str(&line.display());
+        out.push('\n');
+    }
+    fs::write(path, out).with_context(|| format!("Failed to write {}", path.display()))?;
+    Ok(())
+}
+
+// Run a command that keeps producing output, like `logread -f`, until it exits or `stop` turns true.
+// Quiet periods are normal for these, so there is no idle timeout. Complete lines go to `line`.
+pub(crate) async fn stream_command<F>(session: &DeviceSession, command: &str, mut stop: watch::Receiver<bool>, mut line: F) -> Result<Option<u32>, Error> where F: FnMut(&str) {
+    info!("# {}", command);
+    let mut channel = session.channel().await?; // This can return auth errors
+    channel.exec(true, command).await?;
+    let mut pending: Vec<u8> = Vec::new();
+    let mut exit_status = None;
+    loop {
+        tokio::select! {
+            changed = stop.changed() => {
+                if changed.is_err() || *stop.borrow() {
+                    info!("stopping '{}'", command);
+                    let _ = channel.signal(Sig::TERM).await;
+                    let _ = tokio::time::timeout(Duration::from_secs(flasher::TIMEOUT_TINY), channel.close()).await;
+                    break;
+                }
+            }
+            msg = channel.wait() => match msg {
+                Some(ChannelMsg::Data { data }) | Some(ChannelMsg::ExtendedData { data, .. }) => {
+                    pending.extend_from_slice(&data);
+                    while let Some(pos) = pending.iter().position(|b| *b == b'\n') {
+                        let bytes: Vec<u8> = pending.drain(..=pos).collect();
+                        line(String::from_utf8_lossy(&bytes).trim_end());
+                    }
+                }
+                Some(ChannelMsg::ExitStatus { exit_status: status }) => exit_status = Some(status),
+                Some(_) => {}
+                None => break,
+            },
+        }
+    }
+    if !pending.is_empty() {
+        line(String::from_utf8_lossy(&pending).trim_end());
+    }
+    Ok(exit_status)
+}
@@ -0,0 +1,154 @@
+use anyhow::{Error, Result};
+use log::info;
+use std::collections::BTreeSet;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpStream;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+
+use crate::flasher::{self, Credentials};
+use crate::session::DeviceSession;
+
+// Addresses OpenIPC firmware uses out of the box
+const DEFAULT_ADDRESSES: [Ipv4Addr; 2] = [Ipv4Addr::new(192, 168, 1, 10), Ipv4Addr::new(192, 168, 0, 10)];
+const TIMEOUT_PROBE_MS: u64 = 600;
+const TIMEOUT_BANNER_MS: u64 = 1500;
+const MAX_PROBES: usize = 128;
+const MAX_LOGINS: usize = 8;
+
+#[derive(Debug, Clone)]
+pub(crate) struct FoundDevice {
+    pub ip: IpAddr,
+    pub port: u16,
+    pub banner: String,
+    pub soc: Option<String>,
+    pub hostname: Option<String>,
+}
+
+// Every host of the local /24 networks plus the OpenIPC defaults.
+// Larger subnets are cut down to the /24 around our own address.
+pub(crate) fn scan_targets() -> Vec<Ipv4Addr> {
+    let mut own = BTreeSet::new();
+    let mut targets: BTreeSet<Ipv4Addr> = DEFAULT_ADDRESSES.into_iter().collect();
+    if let Ok(interfaces) = if_addrs::get_if_addrs() {
+        for iface in interfaces {
+            if let if_addrs::IfAddr::V4(addr) = iface.addr {
+                if addr.is_loopback() || addr.is_link_local() {
+                    continue;
+                }
+                own.insert(addr.ip);
+                let prefix = addr.prefixlen.max(24);
+                let mask = u32::MAX << (32 - prefix as u32);
+                let network = u32::from(addr.ip) & mask;
+                let broadcast = network | !mask;
+                for host in (network + 1)..broadcast {
+                    targets.insert(Ipv4Addr::from(host));
+                }
+            }
+        }
+    }
+    targets.retain(|ip| !own.contains(ip));
+    targets.into_iter().collect()
+}
+
+// Connects to the port and returns the SSH identification line if there is one
+pub(crate) async fn probe(addr: SocketAddr) -> Option<String> {
+    let mut stream = tokio::time::timeout(Duration::from_millis(TIMEOUT_PROBE_MS), TcpStream::connect(addr))
+        .await
+        .ok()?
+        .ok()?;
+    let mut banner = Vec::new();
+    let mut buf = [0u8; 256];
+    let read = async {
+        while !banner.contains(&b'\n') && banner.len() < 256 {
+            match stream.read(&mut buf).await {
+                Ok(0) | Err(_) => break,
+                Ok(n) => banner.extend_from_slice(&buf[..n]),
+            }
+        }
+    };
+    let _ = tokio::time::timeout(Duration::from_millis(TIMEOUT_BANNER_MS), read).await;
+    let banner = String::from_utf8_lossy(&banner).lines().next().unwrap_or_default().trim().to_string();
+    if banner.starts_with("SSH-") {
+        Some(banner)
+    } else {
+        None
+    }
+}
+
+// OpenIPC firmware ships dropbear, other SSH servers are not logged in to
+fn is_openipc(banner: &str) -> bool {
+    banner.to_ascii_lowercase().contains("dropbear")
+}
+
+// Read SoC and hostname of a device found by a network scan, without touching the log
+async fn identify(session: &DeviceSession) -> Result<(String, String), Error> {
+    let soc = flasher::detect_soc(session, |_| {}).await?;
+    let hostname = flasher::run_command(session, "hostname", |_| {}).await.map(|output| output.stdout).unwrap_or_default();
+    Ok((soc, hostname.trim().to_string()))
+}
+
+// Scan the targets for SSH servers. `found` is called as soon as a device answers.
+// With `login` set, devices whose banner looks like OpenIPC are logged in to with the
+// passwords from `credentials_for` to read their SoC and hostname; no other server
+// is sent a password.
+pub(crate) async fn scan<F, C>(targets: Vec<Ipv4Addr>, port: u16, login: bool, credentials_for: C, mut found: F) -> Result<Vec<FoundDevice>>
+where
+    F: FnMut(&FoundDevice),
+    C: Fn(IpAddr) -> Credentials,
+{
+    info!("Scanning {} addresses on port {}", targets.len(), port);
+    let probes = Arc::new(Semaphore::new(MAX_PROBES));
+    let logins = Arc::new(Semaphore::new(MAX_LOGINS));
+    let mut tasks = JoinSet::new();
+    for ip in targets {
+        let ip = IpAddr::V4(ip);
+        let credentials = login.then(|| credentials_for(ip));
+        let probes = probes.clone();
+        let logins = logins.clone();
+        tasks.spawn(async move {
+            let addr = SocketAddr::new(ip, port);
+            let banner = {
+                let _permit = probes.acquire().await.ok()?;
+                probe(addr).await?
+            };
+            let (soc, hostname) = match credentials.filter(|_| is_openipc(&banner)) {
+                Some(credentials) => {
+                    let _permit = logins.acquire().await.ok()?;
+                    let session = DeviceSession::new(&ip.to_string(), port, credentials);
+                    let identified = identify(&session).await;
+                    session.close().await;
+                    match identified {
+                        Ok((soc, hostname)) => (Some(soc).filter(|s| !s.is_empty()), Some(hostname).filter(|h| !h.is_empty())),
+                        Err(e) => {
+                            info!("{} has SSH but could not be identified: {}", addr, e);
+                            (None, None)
+                        }
+                    }
+                }
+                None => (None, None),
+            };
+            Some(FoundDevice {
+                ip,
+                port,
+                banner,
+                soc,
+                hostname,
+            })
+        });
+    }
+
+    let mut devices = Vec::new();
+    while let Some(res) = tasks.join_next().await {
+        if let Ok(Some(device)) = res {
+            found(&device);
+            devices.push(device);
+        }
+    }
+    devices.sort_by_key(|d| d.ip);
+    info!("Scan finished, {} devices found", devices.len());
+    Ok(devices)
+}
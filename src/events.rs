@@ -0,0 +1,111 @@
+use log::error;
+use serde::Serialize;
+use std::io::Write;
+use std::sync::{Mutex, OnceLock};
+use tokio::sync::broadcast;
+
+use crate::flasher::FlashPhase;
+
+// Events the live feed holds for a slow subscriber, older ones are dropped for it
+const FEED_CAPACITY: usize = 1024;
+
+// Where the events go, if anywhere. Like the logger this is one per process:
+// stdout for headless --json runs, a file when the GUI keeps an event log.
+static SINK: Mutex<Option<Box<dyn Write + Send>>> = Mutex::new(None);
+// Live copies of the lines, for the HTTP API's event stream
+static FEED: OnceLock<broadcast::Sender<String>> = OnceLock::new();
+
+// Something the flasher did, written as one JSON line with the event name under "event"
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub(crate) enum Event<'a> {
+    Connecting,
+    Connected,
+    Soc { soc: &'a str },
+    Phase { phase: FlashPhase },
+    Upload { sent: usize, total: usize },
+    Command { command: &'a str },
+    Output { stream: &'a str, line: &'a str },
+    Exit { command: &'a str, exit_status: Option<u32>, signal: Option<&'a str>, duration_ms: u128 },
+    Warning { message: &'a str },
+    Result {
+        operation: &'a str,
+        ok: bool,
+        error: Option<&'a str>,
+        soc: Option<&'a str>,
+        firmware: Option<&'a str>,
+        version: Option<&'a str>,
+        duration_secs: f64,
+    },
+    // The end of a headless batch run
+    Summary { devices: usize, failed: usize },
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    time: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    host: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    port: Option<u16>,
+    #[serde(flatten)]
+    event: &'a Event<'a>,
+}
+
+// None stops writing events, dropping the previous sink closes it
+pub(crate) fn set_sink(sink: Option<Box<dyn Write + Send>>) {
+    *SINK.lock().unwrap() = sink;
+}
+
+fn feed() -> &'static broadcast::Sender<String> {
+    FEED.get_or_init(|| broadcast::channel(FEED_CAPACITY).0)
+}
+
+// Every event from now on, as JSON lines
+pub(crate) fn subscribe() -> broadcast::Receiver<String> {
+    feed().subscribe()
+}
+
+// An event of one device
+pub(crate) fn emit(host: &str, port: u16, event: Event) {
+    write(Some(host), Some(port), &event);
+}
+
+// An event of the run as a whole
+pub(crate) fn emit_global(event: Event) {
+    write(None, None, &event);
+}
+
+fn write(host: Option<&str>, port: Option<u16>, event: &Event) {
+    let mut sink = SINK.lock().unwrap();
+    let live = feed().receiver_count() > 0;
+    if sink.is_none() && !live {
+        return;
+    }
+    let record = Record {
+        time: chrono::Local::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, false),
+        host,
+        port,
+        event,
+    };
+    let line = match serde_json::to_string(&record) {
+        Ok(line) => line,
+        Err(e) => {
+            error!("Failed to encode event: {:?}", e);
+            return;
+        }
+    };
+    if live {
+        // Only fails when the last subscriber just went away
+        let _ = feed().send(line.clone());
+    }
+    let out = match sink.as_mut() {
+        Some(out) => out,
+        None => return,
+    };
+    if let Err(e) = writeln!(out, "{}", line).and_then(|_| out.flush()) {
+        // A broken sink would fail for every event, so it is dropped after the first error
+        error!("Failed to write event, event output stopped: {:?}", e);
+        *sink = None;
+    }
+}
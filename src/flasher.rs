@@ -1,23 +1,124 @@
-use anyhow::{Error, Result, Context};
+use anyhow::{Context, Error, Result};
 use async_trait::async_trait;
-use client::{Handle, Msg};
+use client::Msg;
 use keys::ssh_key;
 use log::{error, info};
 use russh::*;
 use tokio::fs::File;
 use tokio::io::AsyncReadExt;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::error::Error as StdError;
 use std::io::Write;
 use std::path::Path;
 use std::time::Duration;
-use std::{net::IpAddr, str::FromStr, sync::Arc};
+use std::{net::SocketAddr, sync::Arc};
 use std::str;
 use thiserror::Error;
 
-struct Client;
+use crate::events::{self, Event};
+use crate::matcher::{LineSplitter, MatchState, OutputMatcher};
+use crate::recipe::{self, Recipe, StepAction};
+use crate::remote::{validate_soc, RemoteCommand};
+use crate::session::DeviceSession;
 
-const TIMEOUT_TINY: u64 = 5;
-const TIMEOUT_MAIN: u64 = 60;
+pub(crate) struct Client;
+
+pub(crate) const DEFAULT_PASSWORD: &str = "12345";
+
+// Passwords to try when connecting, the default password always goes last
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Credentials {
+    pub candidates: Vec<String>,
+    // The password that authenticated, set by a successful connection
+    pub working: Option<String>,
+}
+
+impl Credentials {
+    pub fn new(candidates: Vec<String>) -> Self {
+        Self {
+            candidates,
+            working: None,
+        }
+    }
+
+    fn passwords(&self) -> Vec<String> {
+        let mut passwords: Vec<String> = Vec::new();
+        for password in self.candidates.iter().map(|p| p.as_str()).chain([DEFAULT_PASSWORD]) {
+            if !passwords.iter().any(|p| p == password) {
+                passwords.push(password.to_string());
+            }
+        }
+        passwords
+    }
+}
+
+pub(crate) const TIMEOUT_TINY: u64 = 5;
+pub(crate) const TIMEOUT_MAIN: u64 = 60;
+// How long a command may stay silent before it counts as hung
+pub(crate) const IDLE_TIMEOUT: Duration = Duration::from_secs(TIMEOUT_MAIN);
+// How long a device may take to come back after sysupgrade
+const TIMEOUT_REBOOT: u64 = 300;
+const REBOOT_POLL_INTERVAL: u64 = 5;
+// Connections are kept open between operations, keepalives notice a device that went away
+const KEEPALIVE_INTERVAL: u64 = 15;
+const KEEPALIVE_MAX: usize = 3;
+
+// Steps of the flash sequence, in the order they happen. Recipes name them in snake_case.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum FlashPhase {
+    Connect,
+    Detect,
+    StopRuby,
+    Upload,
+    Extract,
+    Sysupgrade,
+    Reboot,
+    Verify,
+}
+
+impl FlashPhase {
+    pub(crate) const ALL: [FlashPhase; 8] = [
+        FlashPhase::Connect,
+        FlashPhase::Detect,
+        FlashPhase::StopRuby,
+        FlashPhase::Upload,
+        FlashPhase::Extract,
+        FlashPhase::Sysupgrade,
+        FlashPhase::Reboot,
+        FlashPhase::Verify,
+    ];
+
+    pub(crate) fn label(&self) -> &'static str {
+        match self {
+            FlashPhase::Connect => "Connect",
+            FlashPhase::Detect => "Detect",
+            FlashPhase::StopRuby => "Stop Ruby",
+            FlashPhase::Upload => "Upload",
+            FlashPhase::Extract => "Extract",
+            FlashPhase::Sysupgrade => "Sysupgrade",
+            FlashPhase::Reboot => "Reboot",
+            FlashPhase::Verify => "Verify",
+        }
+    }
+}
+
+// Structured progress reported alongside the plain status lines
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Progress {
+    Phase(FlashPhase),
+    Upload { sent: usize, total: usize },
+}
+
+// What a device reports about itself
+#[derive(Debug, Clone, Default)]
+pub(crate) struct UnitInfo {
+    // MAC address, or the u-boot ethaddr if there is no eth0
+    pub identity: String,
+    pub soc: String,
+    pub version: String,
+}
 
 // Custom error type for authentication failures
 #[derive(Debug, Error)]
@@ -29,7 +130,7 @@ pub struct AuthError {
 }
 
 impl AuthError {
-    fn new(message: impl Into<String>) -> Self {
+    pub(crate) fn new(message: impl Into<String>) -> Self {
         Self {
             message: message.into(),
             source: None,
@@ -108,26 +209,28 @@ impl client::Handler for Client {
     }
 }
 
-async fn connect(ip: IpAddr, port: u16, password: &str) -> Result<russh::client::Handle<Client>> {
-    let config = russh::client::Config::default();
+pub(crate) async fn open_session(addrs: &[SocketAddr]) -> Result<russh::client::Handle<Client>> {
+    let config = russh::client::Config {
+        keepalive_interval: Some(Duration::from_secs(KEEPALIVE_INTERVAL)),
+        keepalive_max: KEEPALIVE_MAX,
+        ..Default::default()
+    };
     let sh = Client {};
-    info!("Connecting to {}:{}", ip, port);
-    let mut session = tokio::time::timeout(
+    info!("Connecting to {:?}", addrs);
+    let session = tokio::time::timeout(
         Duration::from_secs(TIMEOUT_TINY),
-        russh::client::connect(Arc::new(config), (ip, port), sh)
+        russh::client::connect(Arc::new(config), addrs, sh)
     ).await??;
+    Ok(session)
+}
 
+// Returns Ok(false) when the server rejected the password
+pub(crate) async fn authenticate(session: &mut russh::client::Handle<Client>, password: &str) -> Result<bool> {
     info!("Connected, attempting authentication for user 'root' with password");
     match session.authenticate_password("root", password).await {
         Ok(auth_result) => {
             info!("Authentication call completed with result: {:?}", auth_result);
-            if auth_result {
-                info!("Authentication successful");
-                Ok(session)
-            } else {
-                error!("Authentication failed - authenticate_password returned false");
-                Err(AuthError::invalid_credentials().into())
-            }
+            Ok(auth_result)
         }
         Err(e) => {
             error!("Authentication failed: {}", e);
@@ -136,17 +239,6 @@ async fn connect(ip: IpAddr, port: u16, password: &str) -> Result<russh::client:
     }
 }
 
-// Try to connect with a specific password, returning detailed error info
-async fn try_connect(ip: IpAddr, port: u16, password: &str) -> Result<russh::client::Handle<Client>> {
-    match connect(ip, port, password).await {
-        Ok(session) => Ok(session),
-        Err(e) => {
-            error!("Connection failed: {}", e);
-            Err(e)
-        }
-    }
-}
-
 // Helper function to wait for SCP acknowledgment
 async fn wait_for_acknowledgment(channel: &mut russh::Channel<Msg>) -> Result<()> {
     let timeout_duration = Duration::from_secs(TIMEOUT_MAIN);
@@ -181,7 +273,8 @@ async fn wait_for_acknowledgment(channel: &mut russh::Channel<Msg>) -> Result<()
     }
 }
 
-async fn transfer_file<F>(src: &str, dst: &str, session: &mut Handle<Client>, mut status_update: F) -> Result<()> where F: FnMut(&str) {
+pub(crate) async fn transfer_file<F, P>(src: &str, dst: &str, session: &DeviceSession, mut status_update: F, mut progress: P) -> Result<()>
+where F: FnMut(&str), P: FnMut(usize, usize) {
     // Read the file into memory
     let mut src_file = File::open(src).await?;
     let mut file_contents = Vec::new();
@@ -191,22 +284,38 @@ async fn transfer_file<F>(src: &str, dst: &str, session: &mut Handle<Client>, mu
     let total_size = file_size + cmd.as_bytes().len() + 1; // File + command + null byte
 
     // Open the channel and start SCP
-    let mut channel = session.channel_open_session().await?;
-    channel.exec(true, format!("scp -t {}", dst)).await?;
+    let mut channel = session.channel().await?;
+    channel.exec(true, RemoteCommand::new("scp").arg("-t").arg(dst).to_string()).await?;
 
     // Wait for initial acknowledgment (0x00 byte)
     wait_for_acknowledgment(&mut channel).await?;
 
     let mut total_sent = 0;
+    // The log only gets a line every 10%, the progress bar gets every chunk
+    let mut last_logged_step = 0;
+    // Events get a line per percent
+    let mut last_event_percent = None;
+    let mut report = |total_sent: usize| {
+        progress(total_sent, total_size);
+        let percent = (total_sent as f64 / total_size as f64 * 100.0).min(100.0);
+        if last_event_percent != Some(percent as usize) {
+            last_event_percent = Some(percent as usize);
+            events::emit(session.host(), session.port(), Event::Upload { sent: total_sent, total: total_size });
+        }
+        let step = (percent / 10.0) as usize;
+        if step > last_logged_step || total_sent == total_size {
+            last_logged_step = step;
+            status_update(&format!(
+                "Progress: {:.1}% ({} / {} bytes)",
+                percent, total_sent, total_size
+            ));
+        }
+    };
 
     // Send the SCP command
     channel.data(cmd.as_bytes()).await?; // &[u8] still works here (might be coerced)
     total_sent += cmd.as_bytes().len();
-    let percent = (total_sent as f64 / total_size as f64 * 100.0).min(100.0);
-    status_update(&format!(
-        "Progress: {:.1}% ({} / {} bytes)",
-        percent, total_sent, total_size
-    ));
+    report(total_sent);
 
     // Wait for acknowledgment of the command
     wait_for_acknowledgment(&mut channel).await?;
@@ -220,22 +329,13 @@ async fn transfer_file<F>(src: &str, dst: &str, session: &mut Handle<Client>, mu
 
         tokio::time::timeout(Duration::from_secs(TIMEOUT_MAIN), channel.data(chunk)).await??;
         total_sent += chunk.len();
-
-        let percent = (total_sent as f64 / total_size as f64 * 100.0).min(100.0);
-        status_update(&format!(
-            "Progress: {:.1}% ({} / {} bytes)",
-            percent, total_sent, total_size
-        ));
+        report(total_sent);
     }
 
     // Send the null byte
     channel.data(&b"\0"[..]).await?;
     total_sent += 1;
-    let percent = (total_sent as f64 / total_size as f64 * 100.0).min(100.0);
-    status_update(&format!(
-        "Progress: {:.1}% ({} / {} bytes)",
-        percent, total_sent, total_size
-    ));
+    report(total_sent);
 
     // Wait for final acknowledgment
     wait_for_acknowledgment(&mut channel).await?;
@@ -261,17 +361,96 @@ async fn transfer_file<F>(src: &str, dst: &str, session: &mut Handle<Client>, mu
     Ok(())
 }
 
-async fn run_command<F>(session: &mut Handle<Client>, command: &str, mut status_update: F) -> Result<String> where F: FnMut(&str) {
+// What a remote command produced
+#[derive(Debug, Clone, Default)]
+pub(crate) struct CommandOutput {
+    pub stdout: String,
+    pub stderr: String,
+    // None when the server never reported one
+    pub exit_status: Option<u32>,
+    // Name of the signal that killed the command, if the server reported one
+    pub signal: Option<String>,
+    pub duration: Duration,
+    // What the output matchers of the command found
+    pub matched: MatchState,
+}
+
+impl CommandOutput {
+    // Turn a failed run into an error. Strict mode also rejects a missing exit status,
+    // which is accepted otherwise because some servers close the channel without one.
+    pub fn check(self, command: &str, strict: bool) -> Result<CommandOutput> {
+        let stderr = self.stderr.trim();
+        let detail = if stderr.is_empty() { String::new() } else { format!(": {}", stderr) };
+        if let Some(line) = &self.matched.failure {
+            return Err(anyhow::anyhow!("command '{}' failed: {}", command, line));
+        }
+        if self.matched.completed {
+            return Ok(self);
+        }
+        if let Some(signal) = &self.signal {
+            return Err(anyhow::anyhow!("command '{}' was killed by signal {}{}", command, signal, detail));
+        }
+        match self.exit_status {
+            Some(0) => Ok(self),
+            Some(exit_status) => Err(anyhow::anyhow!("command '{}' failed with exit status: {}{}", command, exit_status, detail)),
+            None if strict => Err(anyhow::anyhow!("command '{}' ended without an exit status", command)),
+            None => Ok(self),
+        }
+    }
+}
+
+fn signal_name(signal: &Sig) -> String {
+    match signal {
+        Sig::Custom(name) => name.clone(),
+        other => format!("{:?}", other),
+    }
+}
+
+pub(crate) async fn run_command<F>(session: &DeviceSession, command: &str, status_update: F) -> Result<CommandOutput> where F: FnMut(&str) {
+    run_command_matching(session, command, &[], status_update).await
+}
+
+// Like run_command, but a command that fails is reported in the output instead of as an error.
+// Without `idle` a quiet `sleep 120` or a slow opkg is left to finish.
+pub(crate) async fn execute<F>(session: &DeviceSession, command: &str, idle: Option<Duration>, status_update: F) -> Result<CommandOutput> where F: FnMut(&str) {
+    exec_command(session, command, None, &[], idle, status_update).await
+}
+
+// Like run_command, with patterns that decide the outcome from the output
+async fn run_command_matching<F>(session: &DeviceSession, command: &str, matchers: &[OutputMatcher], status_update: F) -> Result<CommandOutput> where F: FnMut(&str) {
+    exec_command(session, command, None, matchers, Some(IDLE_TIMEOUT), status_update).await?.check(command, false)
+}
+
+// Run a command and collect everything it produced. Only transport problems are errors,
+// the exit status is left to the caller. Once a matcher decides the outcome the
+// channel is closed without waiting for the command to exit. With `idle` set the command
+// fails when the device sends nothing for that long.
+pub(crate) async fn exec_command<F>(session: &DeviceSession, command: &str, input: Option<&[u8]>, matchers: &[OutputMatcher], idle: Option<Duration>, mut status_update: F) -> Result<CommandOutput> where F: FnMut(&str) {
     info!("# {}", command);
     //tokio::time::sleep(Duration::from_secs(2)).await;
     status_update(&format!("# {}", command));
-    let mut result: Option<u32> = None;
-    let mut res = String::new();
+    events::emit(session.host(), session.port(), Event::Command { command });
+    let started = std::time::Instant::now();
+    let mut output = CommandOutput::default();
     let mut buf: Vec<u8> = Vec::new();
-    let mut channel = session.channel_open_session().await?;
+    let mut stdout_lines = LineSplitter::default();
+    let mut stderr_lines = LineSplitter::default();
+    let mut decided = false;
+    let mut channel = session.channel().await?;
     channel.exec(true, command).await?;
+    if let Some(input) = input {
+        channel.data(input).await?;
+        channel.eof().await?;
+    }
 
-    while let Some(msg) = tokio::time::timeout(Duration::from_secs(TIMEOUT_MAIN), channel.wait()).await? {
+    loop {
+        let msg = match idle {
+            Some(idle) => tokio::time::timeout(idle, channel.wait())
+                .await
+                .with_context(|| format!("'{}' sent nothing for {} seconds", command, idle.as_secs()))?,
+            None => channel.wait().await,
+        };
+        let Some(msg) = msg else { break };
         match msg {
             russh::ChannelMsg::Data { ref data } => {
                 buf.write_all(data)?;
@@ -295,17 +474,16 @@ async fn run_command<F>(session: &mut Handle<Client>, command: &str, mut status_
                     }
                 };
                 if !valid_str.is_empty() {
-                    res.push_str(&valid_str);
+                    output.stdout.push_str(&valid_str);
                     for line in valid_str.split('\n') {
                         let trimmed = line.trim();
                         if !trimmed.is_empty() {
                             status_update(trimmed);
-                            if line.contains("Unconditional reboot") {
-                                let _ = tokio::time::timeout(Duration::from_secs(TIMEOUT_TINY), channel.close()).await;
-                                return Ok(res);
-                            }
                         }
                     }
+                    for line in stdout_lines.push(&valid_str) {
+                        decided |= feed_line(session, &mut output, matchers, "stdout", &line, &mut status_update);
+                    }
                 }
 
                 // Update buffer with remaining bytes
@@ -314,16 +492,21 @@ async fn run_command<F>(session: &mut Handle<Client>, command: &str, mut status_
             russh::ChannelMsg::ExtendedData { ref data, ext } => {
                 if ext == 1 {
                     let str_msg = String::from_utf8_lossy(data);
+                    output.stderr.push_str(&str_msg);
                     for line in str_msg.split("\n") {
                         error!("stderr: {}", line);
                         status_update(&format!("stderr: {}", line));
                     }
+                    for line in stderr_lines.push(&str_msg) {
+                        decided |= feed_line(session, &mut output, matchers, "stderr", &line, &mut status_update);
+                    }
                 }
             }
             // If we get an exit code report, store it, but crucially don't
             // assume this message means end of communications. The data might
             // not be finished yet!
-            russh::ChannelMsg::ExitStatus { exit_status } => result = Some(exit_status),
+            russh::ChannelMsg::ExitStatus { exit_status } => output.exit_status = Some(exit_status),
+            russh::ChannelMsg::ExitSignal { ref signal_name, .. } => output.signal = Some(self::signal_name(signal_name)),
 
             // We SHOULD get this EOF messagge, but 4254 sec 5.3 also permits
             // the channel to close without it being sent. And sometimes this
@@ -331,22 +514,46 @@ async fn run_command<F>(session: &mut Handle<Client>, command: &str, mut status_
             // russh::ChannelMsg::Eof => break,
             _ => {}
         }
+        if decided {
+            info!("output matched, closing channel");
+            let _ = tokio::time::timeout(Duration::from_secs(TIMEOUT_TINY), channel.close()).await;
+            output.duration = started.elapsed();
+            emit_exit(session, command, &output);
+            return Ok(output);
+        }
     };
 
     // Try to handle any remaining data in buffer
     if !buf.is_empty() {
         let msg = String::from_utf8_lossy(&buf);
-        res.push_str(&msg);
+        output.stdout.push_str(&msg);
         for line in msg.split('\n') {
             let trimmed = line.trim();
             if !trimmed.is_empty() {
                 status_update(trimmed);
             }
         }
+        for line in stdout_lines.push(&msg) {
+            decided |= feed_line(session, &mut output, matchers, "stdout", &line, &mut status_update);
+        }
+    }
+    // A last line without a newline can still decide the outcome
+    if let Some(line) = stdout_lines.finish() {
+        decided |= feed_line(session, &mut output, matchers, "stdout", &line, &mut status_update);
+    }
+    if let Some(line) = stderr_lines.finish() {
+        decided |= feed_line(session, &mut output, matchers, "stderr", &line, &mut status_update);
+    }
+    if decided {
+        output.duration = started.elapsed();
+        emit_exit(session, command, &output);
+        return Ok(output);
     }
 
     // Finish up
-    tokio::time::timeout(Duration::from_secs(TIMEOUT_TINY), channel.eof()).await??;
+    if input.is_none() {
+        tokio::time::timeout(Duration::from_secs(TIMEOUT_TINY), channel.eof()).await??;
+    }
 
     info!("consuming leftovers if any...");
     // consume leftovers
@@ -368,12 +575,30 @@ async fn run_command<F>(session: &mut Handle<Client>, command: &str, mut status_
 
     // tokio::time::sleep(Duration::from_secs(1)).await;
 
-    match result {
-        Some(exit_status) if exit_status != 0 => {
-            Err(anyhow::anyhow!("command '{}' failed with exit status: {}", command, exit_status).into())
-        }
-        _ => Ok(res), // Success or no exit status (treat as success)
+    output.duration = started.elapsed();
+    emit_exit(session, command, &output);
+    Ok(output)
+}
+
+// Report a whole line of output and run it through the matchers
+fn feed_line<F>(session: &DeviceSession, output: &mut CommandOutput, matchers: &[OutputMatcher], stream: &str, line: &str, status_update: F) -> bool
+where F: FnMut(&str) {
+    events::emit(session.host(), session.port(), Event::Output { stream, line });
+    let known_warnings = output.matched.warnings.len();
+    let decided = output.matched.feed(matchers, line, status_update);
+    for message in &output.matched.warnings[known_warnings..] {
+        events::emit(session.host(), session.port(), Event::Warning { message });
     }
+    decided
+}
+
+fn emit_exit(session: &DeviceSession, command: &str, output: &CommandOutput) {
+    events::emit(session.host(), session.port(), Event::Exit {
+        command,
+        exit_status: output.exit_status,
+        signal: output.signal.as_deref(),
+        duration_ms: output.duration.as_millis(),
+    });
 }
 
 // fn replace_extension(filename: &str, new_ext: &str) -> String {
@@ -393,61 +618,196 @@ fn extract_filename(src: &str) -> Result<String> {
     }
 }
 
-// Smart connect that tries stored password first, then default if no stored password
-async fn smart_connect(ip: IpAddr, port: u16, custom_password: Option<&str>) -> Result<russh::client::Handle<Client>> {
-    match custom_password {
-        Some(password) => {
-            // We have a stored password, try it first
-            try_connect(ip, port, password).await
-        }
-        None => {
-            // No stored password, try default password
-            try_connect(ip, port, "12345").await
+// Smart connect that tries every candidate password in turn, then the default one
+pub(crate) async fn smart_connect(addrs: &[SocketAddr], credentials: &mut Credentials) -> Result<russh::client::Handle<Client>> {
+    let mut session: Option<russh::client::Handle<Client>> = None;
+    let mut last_error: Error = AuthError::invalid_credentials().into();
+    for password in credentials.passwords() {
+        // Reuse the connection between attempts unless the server dropped it
+        let mut handle = match session.take() {
+            Some(handle) if !handle.is_closed() => handle,
+            _ => open_session(addrs).await?,
+        };
+        match authenticate(&mut handle, &password).await {
+            Ok(true) => {
+                info!("Authentication successful");
+                credentials.working = Some(password);
+                return Ok(handle);
+            }
+            Ok(false) => {
+                error!("Authentication failed - authenticate_password returned false");
+                last_error = AuthError::invalid_credentials().into();
+                session = Some(handle);
+            }
+            Err(e) if is_auth_error(&e) => last_error = e,
+            Err(e) => return Err(e),
         }
     }
+    Err(last_error)
+}
+
+// The SoC from the U-Boot environment. A missing fw_printenv, a failed read and an
+// empty value are told apart, each means something different is wrong with the device.
+async fn read_soc<F>(session: &DeviceSession, status_update: F) -> Result<String> where F: FnMut(&str) {
+    const COMMAND: &str = "fw_printenv -n soc";
+    let output = exec_command(session, COMMAND, None, &[], Some(IDLE_TIMEOUT), status_update).await?;
+    if output.exit_status == Some(127) {
+        return Err(anyhow::anyhow!("fw_printenv is not available on the device"));
+    }
+    let soc = output.check(COMMAND, true)?.stdout.trim().to_string();
+    if soc.is_empty() {
+        return Err(anyhow::anyhow!("The device has no 'soc' variable in its U-Boot environment"));
+    }
+    validate_soc(&soc)?;
+    events::emit(session.host(), session.port(), Event::Soc { soc: &soc });
+    Ok(soc)
+}
+
+async fn read_unit_info<F>(session: &DeviceSession, mut status_update: F) -> Result<UnitInfo> where F: FnMut(&str) {
+    let soc = read_soc(session, &mut status_update).await?;
+    let identity = run_command(session, "cat /sys/class/net/eth0/address 2>/dev/null || fw_printenv -n ethaddr", &mut status_update)
+        .await
+        .map(|output| output.stdout)
+        .unwrap_or_default();
+    // The firmware version is BUILD_ID, or VERSION on images without one
+    let version_matchers = [
+        OutputMatcher::capture("build_id", r#"^BUILD_ID="?([^"]*)"?$"#)?,
+        OutputMatcher::capture("version", r#"^VERSION="?([^"]*)"?$"#)?,
+    ];
+    let mut captures = run_command_matching(session, "cat /etc/os-release", &version_matchers, &mut status_update)
+        .await
+        .map(|output| output.matched.captures)
+        .unwrap_or_default();
+    let version = captures.remove("build_id").or_else(|| captures.remove("version")).unwrap_or_default();
+    Ok(UnitInfo {
+        identity: identity.trim().to_lowercase(),
+        soc,
+        version,
+    })
 }
 
-pub(crate) async fn detect_soc<F>(ip_addr: &str, port: u16, mut status_update: F, password: Option<&str>) -> Result<String, Error>
+pub(crate) async fn detect_soc<F>(session: &DeviceSession, mut status_update: F) -> Result<String, Error>
 where F: FnMut(&str) {
-    let ip = IpAddr::from_str(&ip_addr).context("Invalid IP address")?;
-    let mut session = smart_connect(ip, port, password).await?; // This can return auth errors
-    let soc = run_command(&mut session, "fw_printenv -n soc", &mut status_update).await?;
-    session.disconnect(Disconnect::ByApplication, "", "en").await?;
-    Ok(soc.trim().to_string())
+    read_soc(session, &mut status_update).await // This can return auth errors
 }
 
-pub(crate) async fn flash<F>(ip_addr: &str, port: u16, src: &str, mut status_update: F, password: Option<&str>) -> Result<(), Error> where F: FnMut(&str) {
-    let ip = IpAddr::from_str(&ip_addr).context("Invalid IP address")?;
+// Install firmware with the steps of `recipe`. The device's soc, identity and version,
+// the firmware file name and whatever a step captures are available to later steps.
+pub(crate) async fn flash<F, P>(session: &DeviceSession, recipe: &Recipe, src: &str, mut status_update: F, progress: P) -> Result<UnitInfo, Error>
+where F: FnMut(&str), P: FnMut(Progress) {
+    let mut progress = report_progress(session, progress);
     let fname = extract_filename(&src)?;
-    let dst = format!("/tmp/{}", fname);
-    status_update(&format!("Connecting to {}:{}...", ip_addr, port));
-    let mut session = smart_connect(ip, port, password).await?; // This can return auth errors
-    let soc = run_command(&mut session, "fw_printenv -n soc", &mut status_update).await?;
-    run_command(&mut session, "ruby_stop.sh || true", &mut status_update).await?;
-    status_update(&format!("Uploading firmware {}...", fname));
-    transfer_file(&src, &dst, &mut session, &mut status_update).await?;
-    run_command(&mut session, format!("sh -c 'gunzip -c {} | tar -xvC /tmp'", dst).as_str(), &mut status_update).await?;
-    run_command(&mut session, format!("sysupgrade --kernel=/tmp/uImage.{} --rootfs=/tmp/rootfs.squashfs.{} -z", soc.trim(), soc.trim()).as_str(), &mut status_update).await?;
-    session.disconnect(Disconnect::ByApplication, "", "en").await?;
-    Ok(())
+    progress(Progress::Phase(FlashPhase::Connect));
+    status_update(&format!("Connecting to {}:{}...", session.host(), session.port()));
+    session.handle().await?; // This can return auth errors
+    progress(Progress::Phase(FlashPhase::Detect));
+    let unit = read_unit_info(session, &mut status_update).await?;
+    let mut vars = BTreeMap::from([
+        ("soc".to_string(), unit.soc.clone()),
+        ("identity".to_string(), unit.identity.clone()),
+        ("version".to_string(), unit.version.clone()),
+        ("file".to_string(), fname.clone()),
+    ]);
+    status_update(&format!("Using the {} recipe", recipe.name));
+    for step in &recipe.steps {
+        if !step.applies(&vars)? {
+            info!("skipping step '{}'", step.name);
+            continue;
+        }
+        if let Some(phase) = step.phase {
+            progress(Progress::Phase(phase));
+        }
+        match step.action() {
+            StepAction::Run(template) => {
+                let command = recipe::render(template, &vars)?;
+                let output = run_command_matching(session, &command, &step.matchers()?, &mut status_update)
+                    .await
+                    .with_context(|| format!("step '{}' failed", step.name))?;
+                vars.extend(output.matched.captures);
+            }
+            StepAction::Upload(template) => {
+                let dst = recipe::render_plain(template, &vars)?;
+                status_update(&format!("Uploading firmware {}...", fname));
+                transfer_file(src, &dst, session, &mut status_update, |sent, total| {
+                    progress(Progress::Upload { sent, total })
+                }).await?;
+            }
+        }
+    }
+    if recipe.reboot {
+        progress(Progress::Phase(FlashPhase::Reboot));
+        // The device is usually already rebooting, so the disconnect may fail
+        session.close().await;
+    }
+    Ok(unit)
 }
 
-pub(crate) async fn reset_device<F>(ip_addr: &str, port: u16, mut status_update: F, password: Option<&str>) -> Result<(), Error> where F: FnMut(&str) {
-    let ip = IpAddr::from_str(&ip_addr).context("Invalid IP address")?;
-    status_update(&format!("Connecting to {}:{}...", ip_addr, port));
-    let mut session = smart_connect(ip, port, password).await?; // This can return auth errors
+// Passes progress on to `progress`, phase changes also go out as events.
+// Upload events come from transfer_file, which scripts use as well.
+fn report_progress<'a, P>(session: &'a DeviceSession, mut progress: P) -> impl FnMut(Progress) + 'a
+where P: FnMut(Progress) + 'a {
+    move |update| {
+        if let Progress::Phase(phase) = update {
+            events::emit(session.host(), session.port(), Event::Phase { phase });
+        }
+        progress(update)
+    }
+}
+
+// Poll a rebooting device until it accepts a login again
+pub(crate) async fn wait_until_back<F>(session: &DeviceSession, mut status_update: F) -> Result<()> where F: FnMut(&str) {
+    status_update("Waiting for the device to reboot...");
+    // Whatever connection is left belongs to the old system
+    session.close().await;
+    // Give the device time to actually go down before polling it
+    tokio::time::sleep(Duration::from_secs(REBOOT_POLL_INTERVAL * 4)).await;
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(TIMEOUT_REBOOT);
+    loop {
+        // sysupgrade -z wipes the settings, the default password is tried as well
+        match session.handle().await {
+            Ok(_) => return Ok(()),
+            Err(e) if is_auth_error(&e) => return Err(e),
+            Err(e) => {
+                if tokio::time::Instant::now() >= deadline {
+                    return Err(e.context("Device did not come back after reboot"));
+                }
+                info!("device not up yet: {}", e);
+                tokio::time::sleep(Duration::from_secs(REBOOT_POLL_INTERVAL)).await;
+            }
+        }
+    }
+}
+
+// Wait for a freshly flashed device to come back and check that it reports the expected SoC
+pub(crate) async fn wait_for_reboot<F, P>(session: &DeviceSession, expected_soc: &str, mut status_update: F, progress: P) -> Result<UnitInfo, Error>
+where F: FnMut(&str), P: FnMut(Progress) {
+    let mut progress = report_progress(session, progress);
+    progress(Progress::Phase(FlashPhase::Reboot));
+    wait_until_back(session, &mut status_update).await?;
+    progress(Progress::Phase(FlashPhase::Verify));
+    status_update("Device is back online, verifying...");
+    let unit = read_unit_info(session, &mut status_update).await?;
+    if unit.soc != expected_soc {
+        return Err(anyhow::anyhow!("Device reports SoC '{}' after reboot, expected '{}'", unit.soc, expected_soc));
+    }
+    Ok(unit)
+}
+
+pub(crate) async fn reset_device<F>(session: &DeviceSession, mut status_update: F) -> Result<(), Error> where F: FnMut(&str) {
+    status_update(&format!("Connecting to {}:{}...", session.host(), session.port()));
+    session.handle().await?; // This can return auth errors
     status_update("Executing firstboot command...");
-    run_command(&mut session, "firstboot", &mut status_update).await?;
-    session.disconnect(Disconnect::ByApplication, "", "en").await?;
+    // firstboot reboots the device, which takes the connection down
+    let reboot_matchers = [OutputMatcher::complete("Unconditional reboot")?];
+    run_command_matching(session, "firstboot", &reboot_matchers, &mut status_update).await?;
     Ok(())
 }
 
-pub(crate) async fn execute_command<F>(ip_addr: &str, port: u16, command: &str, mut status_update: F, password: Option<&str>) -> Result<(), Error> where F: FnMut(&str) {
-    let ip = IpAddr::from_str(&ip_addr).context("Invalid IP address")?;
-    status_update(&format!("Connecting to {}:{}...", ip_addr, port));
-    let mut session = smart_connect(ip, port, password).await?; // This can return auth errors
-    status_update(&format!("Executing command: {}", command));
-    run_command(&mut session, command, &mut status_update).await?;
-    session.disconnect(Disconnect::ByApplication, "", "en").await?;
-    Ok(())
+// Identity, SoC and firmware version of whatever unit answers at the address
+pub(crate) async fn identify_unit<F>(session: &DeviceSession, mut status_update: F) -> Result<UnitInfo, Error> where F: FnMut(&str) {
+    let unit = read_unit_info(session, &mut status_update).await?; // This can return auth errors
+    if unit.identity.is_empty() {
+        return Err(anyhow::anyhow!("The device reported no MAC address"));
+    }
+    Ok(unit)
 }
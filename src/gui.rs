@@ -0,0 +1,3007 @@
+#[cfg(not(target_os = "windows"))]
+use std::process::Command;
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use fltk::{
+    app,
+    browser::{Browser, HoldBrowser},
+    button::{Button, CheckButton, ToggleButton},
+    enums::{self, Color, Event, Font, FrameType, Key, Shortcut},
+    frame::Frame,
+    group::Flex,
+    image::IcoImage,
+    input::{Input, InputType, MultilineInput},
+    menu::{Choice, MenuButton, MenuFlag},
+    misc::{InputChoice, Progress},
+    prelude::*,
+    text::{StyleTableEntry, TextBuffer, TextDisplay},
+    widget::Widget,
+    window::Window,
+};
+use anyhow::Context;
+use fltk_theme::{color_themes, ColorTheme};
+use log::{error, info};
+
+use crate::{batch, config, console, devlog, discovery, events, flasher, history, password, recipe, script, session_log, shell, support, terminal, vault, watch};
+use crate::Asset;
+use crate::flasher::FlashPhase;
+use crate::session::DeviceSession;
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+// `styles` holds one style character per byte of `text` ('A' is the first entry of `colors`)
+fn to_html(text: &str, styles: &str, colors: &[(u8, u8, u8)], background: (u8, u8, u8)) -> String {
+    let mut out = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>Ruby Flasher log</title>\n</head>\n\
+         <body style=\"background: #{:02x}{:02x}{:02x}\">\n<pre style=\"font-family: monospace\">",
+        background.0, background.1, background.2
+    );
+    let styles = styles.as_bytes();
+    let mut run = String::new();
+    let mut run_style = None;
+    let flush = |out: &mut String, run: &mut String, style: Option<u8>| {
+        if run.is_empty() {
+            return;
+        }
+        let (r, g, b) = style
+            .and_then(|s| colors.get(s.wrapping_sub(b'A') as usize))
+            .copied()
+            .unwrap_or((0, 0, 0));
+        out.push_str(&format!("<span style=\"color: #{:02x}{:02x}{:02x}\">{}</span>", r, g, b, escape_html(run)));
+        run.clear();
+    };
+    for (pos, ch) in text.char_indices() {
+        let style = styles.get(pos).copied();
+        if style != run_style {
+            flush(&mut out, &mut run, run_style);
+            run_style = style;
+        }
+        run.push(ch);
+    }
+    flush(&mut out, &mut run, run_style);
+    out.push_str("</pre>\n</body>\n</html>\n");
+    out
+}
+
+#[derive(Clone)]
+struct DisplayState {
+    disp: TextDisplay,
+    text_buf: TextBuffer,
+    style_buf: TextBuffer,
+    // Colours of the active style table, used when saving as HTML
+    colors: Vec<(u8, u8, u8)>,
+    dark: bool,
+    session_file: Option<Arc<session_log::SessionFile>>,
+}
+
+fn is_dark_mode() -> bool {
+    // macOS-specific check
+    #[cfg(target_os = "macos")]
+    {
+        let output = Command::new("defaults")
+            .args(["read", "NSGlobalDomain", "AppleInterfaceStyle"])
+            .output();
+        match output {
+            Ok(output) => {
+                let result = String::from_utf8_lossy(&output.stdout);
+                result.trim() == "Dark"
+            }
+            Err(_) => false, // Default to light mode if check fails
+        }
+    }
+
+    // Windows-specific check
+    #[cfg(target_os = "windows")]
+    {
+        use winreg::enums::*;
+        use winreg::RegKey;
+
+        let hkey = RegKey::predef(HKEY_CURRENT_USER);
+        match hkey.open_subkey("Software\\Microsoft\\Windows\\CurrentVersion\\Themes\\Personalize")
+        {
+            Ok(key) => {
+                match key.get_value::<u32, _>("AppsUseLightTheme") {
+                    Ok(value) => value == 0, // 0 means dark mode, 1 means light mode
+                    Err(_) => false,         // Default to light mode if value not found
+                }
+            }
+            Err(_) => false, // Default to light mode if registry key not accessible
+        }
+    }
+
+    // Linux-specific check (GNOME example)
+    #[cfg(target_os = "linux")]
+    {
+        let desktop = std::env::var("XDG_CURRENT_DESKTOP")
+            .unwrap_or_default()
+            .to_lowercase();
+
+        // KDE check
+        if desktop.contains("kde") || desktop.contains("plasma") {
+            if let Ok(output) = Command::new("kreadconfig5")
+                .args([
+                    "--file",
+                    "kdeglobals",
+                    "--group",
+                    "KDE",
+                    "--key",
+                    "widgetStyle",
+                ])
+                .output()
+            {
+                let result = String::from_utf8_lossy(&output.stdout);
+                return result.to_lowercase().contains("dark");
+            }
+        }
+
+        // GNOME check
+        if desktop.contains("gnome") || desktop.contains("gtk") {
+            if let Ok(output) = Command::new("gsettings")
+                .args(["get", "org.gnome.desktop.interface", "gtk-theme"])
+                .output()
+            {
+                let result = String::from_utf8_lossy(&output.stdout);
+                return result.to_lowercase().contains("dark");
+            }
+        }
+
+        // Fallback: check QT_STYLE_OVERRIDE or GTK_THEME
+        std::env::var("QT_STYLE_OVERRIDE")
+            .map(|theme| theme.to_lowercase().contains("dark"))
+            .unwrap_or(false)
+            || std::env::var("GTK_THEME")
+                .map(|theme| theme.to_lowercase().contains("dark"))
+                .unwrap_or(false)
+    }
+
+    // Fallback for unsupported platforms
+    #[cfg(not(any(target_os = "macos", target_os = "windows", target_os = "linux")))]
+    {
+        false // Default to light mode
+    }
+}
+
+impl DisplayState {
+    fn new() -> Self {
+        let mut disp = TextDisplay::default();
+        disp.wrap_mode(fltk::text::WrapMode::AtBounds, 0);
+        let text_buf = TextBuffer::default();
+        let style_buf = TextBuffer::default();
+        disp.set_buffer(text_buf.clone());
+
+        // Define style tables for light and dark modes
+        let light_styles = vec![
+            StyleTableEntry {
+                color: Color::from_rgb(0, 0, 0),
+                font: Font::Courier,
+                size: 12,
+            }, // 'A' = Black
+            StyleTableEntry {
+                color: Color::from_rgb(200, 0, 0),
+                font: Font::Courier,
+                size: 12,
+            }, // 'B' = Dark Red
+            StyleTableEntry {
+                color: Color::from_rgb(0, 200, 0),
+                font: Font::Courier,
+                size: 12,
+            }, // 'C' = Dark Green
+            StyleTableEntry {
+                color: Color::from_rgb(0, 0, 200),
+                font: Font::Courier,
+                size: 12,
+            }, // 'D' = Dark Blue
+            StyleTableEntry {
+                color: Color::from_rgb(200, 0, 200),
+                font: Font::Courier,
+                size: 12,
+            }, // 'E' = Dark Magenta
+            StyleTableEntry {
+                color: Color::from_rgb(0, 200, 200),
+                font: Font::Courier,
+                size: 12,
+            }, // 'F' = Dark Cyan
+            StyleTableEntry {
+                color: Color::from_rgb(90, 90, 90),
+                font: Font::Courier,
+                size: 12,
+            }, // 'G' = Dark Gray
+        ];
+
+        let dark_styles = vec![
+            StyleTableEntry {
+                color: Color::from_rgb(255, 255, 255),
+                font: Font::Courier,
+                size: 12,
+            }, // 'A' = White
+            StyleTableEntry {
+                color: Color::from_rgb(255, 100, 100),
+                font: Font::Courier,
+                size: 12,
+            }, // 'B' = Light Red
+            StyleTableEntry {
+                color: Color::from_rgb(100, 255, 100),
+                font: Font::Courier,
+                size: 12,
+            }, // 'C' = Light Green
+            StyleTableEntry {
+                color: Color::from_rgb(100, 100, 255),
+                font: Font::Courier,
+                size: 12,
+            }, // 'D' = Light Blue
+            StyleTableEntry {
+                color: Color::from_rgb(255, 100, 255),
+                font: Font::Courier,
+                size: 12,
+            }, // 'E' = Light Magenta
+            StyleTableEntry {
+                color: Color::from_rgb(100, 255, 255),
+                font: Font::Courier,
+                size: 12,
+            }, // 'F' = Light Cyan
+            StyleTableEntry {
+                color: Color::from_rgb(127, 127, 127),
+                font: Font::Courier,
+                size: 12,
+            }, // 'G' = Light Gray
+        ];
+
+        // Choose styles based on dark mode
+        let dark = is_dark_mode();
+        let styles = if dark {
+            dark_styles
+        } else {
+            light_styles
+        };
+        let colors = styles.iter().map(|style| style.color.to_rgb()).collect();
+
+        // Apply styles
+        disp.set_highlight_data(style_buf.clone(), styles);
+
+        let session_file = match session_log::SessionFile::create() {
+            Ok(file) => {
+                info!("Session log: {}", file.path().display());
+                Some(Arc::new(file))
+            }
+            Err(e) => {
+                error!("Failed to create the session log: {:?}", e);
+                None
+            }
+        };
+
+        DisplayState {
+            disp,
+            text_buf,
+            style_buf,
+            colors,
+            dark,
+            session_file,
+        }
+    }
+
+    fn save_text(&self, path: &std::path::Path) -> anyhow::Result<()> {
+        std::fs::write(path, self.text_buf.text())?;
+        Ok(())
+    }
+
+    fn save_html(&self, path: &std::path::Path) -> anyhow::Result<()> {
+        let background = if self.dark { (30, 30, 30) } else { (255, 255, 255) };
+        let html = to_html(&self.text_buf.text(), &self.style_buf.text(), &self.colors, background);
+        std::fs::write(path, html)?;
+        Ok(())
+    }
+
+    fn append_text(&mut self, text: &str) {
+        let mut plain_text = String::new();
+        let mut style_text = String::new(); // Style characters
+
+        let mut current_style = 'A'; // Default: Black
+
+        let mut chars = text.chars().peekable();
+        while let Some(ch) = chars.next() {
+            // ANSI Escape Sequences
+            if ch == '\x1b' && chars.peek() == Some(&'[') {
+                chars.next(); // Skip '['
+                let mut code = String::new();
+
+                // Collect ANSI escape sequence (e.g., "0;31m") up to its final byte
+                let mut final_byte = None;
+                while let Some(&next) = chars.peek() {
+                    chars.next();
+                    if ('\x40'..='\x7e').contains(&next) {
+                        final_byte = Some(next);
+                        break;
+                    }
+                    code.push(next);
+                }
+                // Cursor movement and the like from the shell are not rendered
+                if final_byte != Some('m') {
+                    continue;
+                }
+
+                // Parse ANSI codes
+                let mut new_style = 'A'; // Default reset
+                for part in code.trim_end_matches('m').split(';') {
+                    match part {
+                        "0" => new_style = 'A',  // Reset style
+                        "31" => new_style = 'B', // Red
+                        "32" => new_style = 'C', // Green
+                        "34" => new_style = 'D', // Blue
+                        "35" => new_style = 'E', // Magenta
+                        "36" => new_style = 'F', // Cyan
+                        "90" => new_style = 'G', // Gray
+                        _ => {}                  // Ignore unsupported codes
+                    }
+                }
+                current_style = new_style; // Apply last parsed style
+            } else {
+                // ASCII Highlighting
+                let ascii_style = if ch.is_ascii_control() {
+                    'G' // Gray for non-printable ASCII
+                } else if ch.is_ascii_graphic() {
+                    current_style // Keep ANSI color
+                } else {
+                    'A' // Default Black
+                };
+
+                plain_text.push(ch);
+                for _ in 0..ch.len_utf8() {
+                    style_text.push(ascii_style);
+                }
+            }
+        }
+
+        self.text_buf.append(&plain_text);
+        self.style_buf.append(&style_text);
+        if let Some(file) = &self.session_file {
+            file.write(&plain_text);
+        }
+
+        // Scroll to bottom
+        let text_len = self.text_buf.length();
+        self.disp.set_insert_position(text_len);
+        let line_count = self.disp.count_lines(0, text_len, true) as i32;
+        self.disp.scroll(line_count - 1, 0);
+
+        app::awake();
+        app::redraw();
+    }
+}
+
+const TERMINAL_FONT_SIZE: i32 = 12;
+const TERMINAL_PADDING: i32 = 4;
+
+// xterm's 256 colour palette: 16 system colours, a 6x6x6 cube and a grey ramp
+fn palette(index: u8) -> (u8, u8, u8) {
+    const SYSTEM: [(u8, u8, u8); 16] = [
+        (0, 0, 0),
+        (205, 0, 0),
+        (0, 205, 0),
+        (205, 205, 0),
+        (0, 0, 238),
+        (205, 0, 205),
+        (0, 205, 205),
+        (229, 229, 229),
+        (127, 127, 127),
+        (255, 0, 0),
+        (0, 255, 0),
+        (255, 255, 0),
+        (92, 92, 255),
+        (255, 0, 255),
+        (0, 255, 255),
+        (255, 255, 255),
+    ];
+    match index {
+        0..=15 => SYSTEM[index as usize],
+        16..=231 => {
+            let i = index - 16;
+            let level = |v: u8| if v == 0 { 0 } else { 55 + v * 40 };
+            (level(i / 36), level((i / 6) % 6), level(i % 6))
+        }
+        _ => {
+            let grey = 8 + (index - 232) * 10;
+            (grey, grey, grey)
+        }
+    }
+}
+
+// Bytes a key press sends to the device, following xterm
+fn terminal_key_bytes(key: Key, text: &str, ctrl: bool, app_cursor_keys: bool) -> Option<Vec<u8>> {
+    let cursor = |c: char| {
+        let prefix = if app_cursor_keys { "\x1bO" } else { "\x1b[" };
+        Some(format!("{}{}", prefix, c).into_bytes())
+    };
+    let seq = |s: &str| Some(s.as_bytes().to_vec());
+    match key {
+        Key::Enter | Key::KPEnter => seq("\r"),
+        Key::BackSpace => Some(vec![0x7f]),
+        Key::Tab => seq("\t"),
+        Key::Escape => seq("\x1b"),
+        Key::Up => cursor('A'),
+        Key::Down => cursor('B'),
+        Key::Right => cursor('C'),
+        Key::Left => cursor('D'),
+        Key::Home => cursor('H'),
+        Key::End => cursor('F'),
+        Key::Insert => seq("\x1b[2~"),
+        Key::Delete => seq("\x1b[3~"),
+        Key::PageUp => seq("\x1b[5~"),
+        Key::PageDown => seq("\x1b[6~"),
+        k if Key::is_fn_key(k) => {
+            const CODES: [&str; 12] = ["OP", "OQ", "OR", "OS", "[15~", "[17~", "[18~", "[19~", "[20~", "[21~", "[23~", "[24~"];
+            let n = (k.bits() - Key::F1.bits()) as usize;
+            CODES.get(n).map(|code| format!("\x1b{}", code).into_bytes())
+        }
+        k => match k.to_char() {
+            // Ctrl+letter gives the matching control character
+            Some(c) if ctrl && c.is_ascii_alphabetic() => Some(vec![c.to_ascii_lowercase() as u8 - b'a' + 1]),
+            _ if !text.is_empty() => seq(text),
+            _ => None,
+        },
+    }
+}
+
+// Manual mode console, drawn from the emulator's screen buffer
+#[derive(Clone)]
+struct TerminalView {
+    widget: Widget,
+    screen: Arc<Mutex<terminal::Screen>>,
+}
+
+impl TerminalView {
+    fn new(dark: bool) -> Self {
+        let mut widget = Widget::default();
+        widget.set_frame(FrameType::FlatBox);
+        let screen = Arc::new(Mutex::new(terminal::Screen::new(80, 24)));
+        let (default_fg, default_bg) = if dark {
+            ((229, 229, 229), (20, 20, 20))
+        } else {
+            ((0, 0, 0), (255, 255, 255))
+        };
+        let to_color = |(r, g, b): (u8, u8, u8)| Color::from_rgb(r, g, b);
+
+        let screen_draw = screen.clone();
+        widget.draw(move |w| {
+            let screen = screen_draw.lock().unwrap();
+            let (char_w, char_h) = Self::char_size();
+            fltk::draw::push_clip(w.x(), w.y(), w.w(), w.h());
+            fltk::draw::draw_rect_fill(w.x(), w.y(), w.w(), w.h(), to_color(default_bg));
+            let (_, rows) = screen.size();
+            let cell_x = |col: usize| w.x() + TERMINAL_PADDING + (col as f64 * char_w) as i32;
+            for row in 0..rows {
+                let y = w.y() + TERMINAL_PADDING + row as i32 * char_h;
+                for (col, cell) in screen.visible_row(row).iter().enumerate() {
+                    let mut fg = match cell.fg {
+                        terminal::TermColor::Default => default_fg,
+                        // Bold makes the basic colours bright, like most terminals
+                        terminal::TermColor::Indexed(i) if cell.bold && i < 8 => palette(i + 8),
+                        terminal::TermColor::Indexed(i) => palette(i),
+                        terminal::TermColor::Rgb(r, g, b) => (r, g, b),
+                    };
+                    let mut bg = match cell.bg {
+                        terminal::TermColor::Default => default_bg,
+                        terminal::TermColor::Indexed(i) => palette(i),
+                        terminal::TermColor::Rgb(r, g, b) => (r, g, b),
+                    };
+                    if cell.inverse {
+                        std::mem::swap(&mut fg, &mut bg);
+                    }
+                    let x = cell_x(col);
+                    let width = cell_x(col + 1) - x;
+                    if bg != default_bg {
+                        fltk::draw::draw_rect_fill(x, y, width, char_h, to_color(bg));
+                    }
+                    if cell.ch != ' ' || cell.underline {
+                        fltk::draw::set_draw_color(to_color(fg));
+                        let font = if cell.bold { Font::CourierBold } else { Font::Courier };
+                        fltk::draw::set_font(font, TERMINAL_FONT_SIZE);
+                        fltk::draw::draw_text(&cell.ch.to_string(), x, y + char_h - fltk::draw::descent());
+                        if cell.underline {
+                            fltk::draw::draw_line(x, y + char_h - 1, x + width, y + char_h - 1);
+                        }
+                    }
+                }
+            }
+            if screen.cursor_visible && screen.view_offset() == 0 {
+                let (col, row) = screen.cursor();
+                let (x, y) = (cell_x(col), w.y() + TERMINAL_PADDING + row as i32 * char_h);
+                fltk::draw::set_draw_color(to_color(default_fg));
+                // A hollow cursor shows that keys go elsewhere
+                if app::focus().map(|f| f.as_widget_ptr() == w.as_widget_ptr()).unwrap_or(false) {
+                    fltk::draw::draw_rect_fill(x, y, cell_x(col + 1) - x, char_h, to_color(default_fg));
+                    let cell = screen.visible_row(row)[col];
+                    fltk::draw::set_draw_color(to_color(default_bg));
+                    fltk::draw::set_font(Font::Courier, TERMINAL_FONT_SIZE);
+                    fltk::draw::draw_text(&cell.ch.to_string(), x, y + char_h - fltk::draw::descent());
+                } else {
+                    fltk::draw::draw_rect(x, y, cell_x(col + 1) - x, char_h);
+                }
+            }
+            fltk::draw::pop_clip();
+        });
+
+        TerminalView { widget, screen }
+    }
+
+    fn char_size() -> (f64, i32) {
+        fltk::draw::set_font(Font::Courier, TERMINAL_FONT_SIZE);
+        (fltk::draw::width("M").max(1.0), fltk::draw::height().max(1))
+    }
+
+    fn size_in_chars(w: i32, h: i32) -> (usize, usize) {
+        let (char_w, char_h) = Self::char_size();
+        let cols = ((w - 2 * TERMINAL_PADDING) as f64 / char_w) as usize;
+        let rows = ((h - 2 * TERMINAL_PADDING) / char_h) as usize;
+        (cols.max(20), rows.max(5))
+    }
+
+    // Start over with an empty screen the size of the widget
+    fn reset(&mut self) -> (usize, usize) {
+        let (cols, rows) = Self::size_in_chars(self.widget.w(), self.widget.h());
+        *self.screen.lock().unwrap() = terminal::Screen::new(cols, rows);
+        self.widget.redraw();
+        (cols, rows)
+    }
+
+    // Returns what the emulator has to answer to the device
+    fn feed(&mut self, text: &str) -> Vec<u8> {
+        let responses = {
+            let mut screen = self.screen.lock().unwrap();
+            screen.feed(text);
+            screen.take_responses()
+        };
+        self.widget.redraw();
+        app::awake();
+        responses
+    }
+
+    // `input` gets what the user types, `resized` the new size in characters
+    fn set_handlers<I, R>(&mut self, mut input: I, mut resized: R)
+    where
+        I: FnMut(&[u8]) + 'static,
+        R: FnMut(usize, usize) + 'static,
+    {
+        let screen_handle = self.screen.clone();
+        self.widget.handle(move |w, ev| match ev {
+            Event::Push => {
+                if app::event_button() == 2 {
+                    app::paste_text2(w);
+                }
+                let _ = w.take_focus();
+                true
+            }
+            Event::Focus | Event::Unfocus => {
+                w.redraw();
+                true
+            }
+            Event::KeyDown => {
+                let key = app::event_key();
+                // Shift+PageUp/PageDown scroll back through the output
+                if app::is_event_shift() && (key == Key::PageUp || key == Key::PageDown) {
+                    let page = screen_handle.lock().unwrap().size().1 as isize;
+                    screen_handle.lock().unwrap().scroll_view(if key == Key::PageUp { page } else { -page });
+                    w.redraw();
+                    return true;
+                }
+                if app::is_event_ctrl() && app::is_event_shift() && key == Key::from_char('v') {
+                    app::paste_text(w);
+                    return true;
+                }
+                let app_cursor_keys = screen_handle.lock().unwrap().app_cursor_keys;
+                match terminal_key_bytes(key, &app::event_text(), app::is_event_ctrl(), app_cursor_keys) {
+                    Some(bytes) => {
+                        input(&bytes);
+                        true
+                    }
+                    None => false,
+                }
+            }
+            Event::Paste => {
+                input(app::event_text().as_bytes());
+                true
+            }
+            Event::MouseWheel => {
+                screen_handle.lock().unwrap().scroll_view(-3 * app::event_dy_value() as isize);
+                w.redraw();
+                true
+            }
+            _ => false,
+        });
+
+        let screen_resize = self.screen.clone();
+        self.widget.resize_callback(move |_, _, _, w, h| {
+            let (cols, rows) = Self::size_in_chars(w, h);
+            if screen_resize.lock().unwrap().size() != (cols, rows) {
+                screen_resize.lock().unwrap().resize(cols, rows);
+                resized(cols, rows);
+            }
+        });
+    }
+}
+
+#[derive(Clone)]
+struct ProgressView {
+    steps: Vec<Frame>,
+    bar: Progress,
+    info: Frame,
+    current: Option<FlashPhase>,
+    upload_started: Option<Instant>,
+}
+
+impl ProgressView {
+    fn new(container: &mut Flex) -> Self {
+        // Phase stepper
+        let stepper = Flex::default().row();
+        let mut steps = Vec::new();
+        for (i, phase) in FlashPhase::ALL.iter().enumerate() {
+            let mut step = Frame::default().with_label(&format!("{}. {}", i + 1, phase.label()));
+            step.set_frame(FrameType::FlatBox);
+            step.set_label_size(12);
+            steps.push(step);
+        }
+        stepper.end();
+        container.fixed(&stepper, 22);
+
+        // Upload progress bar with speed and ETA
+        let mut row = Flex::default().row();
+        let mut bar = Progress::default();
+        bar.set_minimum(0.0);
+        bar.set_maximum(100.0);
+        bar.set_selection_color(Color::from_rgb(0, 120, 215));
+        let mut info = Frame::default().with_align(enums::Align::Inside | enums::Align::Left);
+        info.set_label_size(12);
+        row.fixed(&info, 220);
+        row.end();
+        container.fixed(&row, 20);
+
+        let mut view = ProgressView {
+            steps,
+            bar,
+            info,
+            current: None,
+            upload_started: None,
+        };
+        view.reset();
+        view
+    }
+
+    fn reset(&mut self) {
+        self.current = None;
+        self.upload_started = None;
+        for step in self.steps.iter_mut() {
+            step.set_color(Color::Background);
+            step.set_label_color(Color::from_rgb(127, 127, 127));
+        }
+        self.bar.set_value(0.0);
+        self.bar.set_label("");
+        self.info.set_label("");
+        app::awake();
+        app::redraw();
+    }
+
+    fn update(&mut self, progress: flasher::Progress) {
+        match progress {
+            flasher::Progress::Phase(phase) => {
+                let index = FlashPhase::ALL.iter().position(|p| *p == phase).unwrap_or(0);
+                for (i, step) in self.steps.iter_mut().enumerate() {
+                    if i < index {
+                        step.set_color(Color::from_rgb(0, 150, 0));
+                        step.set_label_color(Color::White);
+                    } else if i == index {
+                        step.set_color(Color::from_rgb(0, 120, 215));
+                        step.set_label_color(Color::White);
+                    }
+                }
+                self.current = Some(phase);
+            }
+            flasher::Progress::Upload { sent, total } => {
+                let started = *self.upload_started.get_or_insert_with(Instant::now);
+                let percent = if total > 0 { sent as f64 / total as f64 * 100.0 } else { 0.0 };
+                self.bar.set_value(percent);
+                self.bar.set_label(&format!("{:.0}%", percent));
+
+                let elapsed = started.elapsed().as_secs_f64();
+                if elapsed > 0.0 && sent > 0 {
+                    let speed = sent as f64 / elapsed;
+                    let eta = (total.saturating_sub(sent)) as f64 / speed;
+                    self.info.set_label(&format!(
+                        "{} / {}  {}/s  ETA {}",
+                        format_bytes(sent as f64),
+                        format_bytes(total as f64),
+                        format_bytes(speed),
+                        format_duration(eta)
+                    ));
+                }
+            }
+        }
+        app::awake();
+        app::redraw();
+    }
+
+    // Mark every phase as done, used once verification succeeded
+    fn finish(&mut self) {
+        for step in self.steps.iter_mut() {
+            step.set_color(Color::from_rgb(0, 150, 0));
+            step.set_label_color(Color::White);
+        }
+        self.current = None;
+        app::awake();
+        app::redraw();
+    }
+
+    // Highlight the phase that was running when the operation failed
+    fn fail(&mut self) {
+        if let Some(phase) = self.current {
+            let index = FlashPhase::ALL.iter().position(|p| *p == phase).unwrap_or(0);
+            self.steps[index].set_color(Color::from_rgb(200, 0, 0));
+            self.steps[index].set_label_color(Color::White);
+        }
+        app::awake();
+        app::redraw();
+    }
+}
+
+fn format_bytes(bytes: f64) -> String {
+    if bytes >= 1024.0 * 1024.0 {
+        format!("{:.1} MB", bytes / 1024.0 / 1024.0)
+    } else if bytes >= 1024.0 {
+        format!("{:.1} KB", bytes / 1024.0)
+    } else {
+        format!("{:.0} B", bytes)
+    }
+}
+
+fn format_duration(secs: f64) -> String {
+    let secs = secs.round() as u64;
+    format!("{}:{:02}", secs / 60, secs % 60)
+}
+
+fn update_status(text_display: &mut DisplayState, status: &str) {
+    info!("{}", status);
+    text_display.append_text(format!("{}\n", status).as_str());
+}
+
+fn choose_file(filter: &str) -> Option<String> {
+    let mut dialog =
+        fltk::dialog::NativeFileChooser::new(fltk::dialog::NativeFileChooserType::BrowseFile);
+    dialog.set_option(fltk::dialog::NativeFileChooserOptions::UseFilterExt);
+    dialog.set_filter(filter);
+    match dialog.try_show() {
+        Err(e) => {
+            error!("error: {:?}", e);
+            None
+        }
+        Ok(res) => match res {
+            fltk::dialog::NativeFileChooserAction::Success => {
+                let res = dialog.filename();
+                match res.as_os_str().to_str() {
+                    Some(res) => Some(res.to_owned()),
+                    None => None,
+                }
+            }
+            fltk::dialog::NativeFileChooserAction::Cancelled => None,
+        },
+    }
+}
+
+fn choose_save_file(filter: &str, default_name: &str) -> Option<std::path::PathBuf> {
+    let mut dialog =
+        fltk::dialog::NativeFileChooser::new(fltk::dialog::NativeFileChooserType::BrowseSaveFile);
+    dialog.set_option(fltk::dialog::NativeFileChooserOptions::SaveAsConfirm);
+    dialog.set_filter(filter);
+    dialog.set_preset_file(default_name);
+    match dialog.try_show() {
+        Ok(fltk::dialog::NativeFileChooserAction::Success) => Some(dialog.filename()),
+        Ok(fltk::dialog::NativeFileChooserAction::Cancelled) => None,
+        Err(e) => {
+            error!("error: {:?}", e);
+            None
+        }
+    }
+}
+
+fn prompt_for_password() -> Option<String> {
+    match fltk::dialog::input_default("Authentication failed.\nPlease enter the device password:", "") {
+        Some(password) => Some(password.to_string()),
+        _ => None,
+    }
+}
+
+// Menu labels treat '/', '&' and '_' specially
+fn escape_menu_label(label: &str) -> String {
+    label
+        .replace('\\', "\\\\")
+        .replace('/', "\\/")
+        .replace('&', "&&")
+        .replace('_', "\\_")
+}
+
+fn fill_recent_devices(ip_input: &mut InputChoice, config: &config::Config) {
+    ip_input.clear();
+    for device in config.devices.iter() {
+        ip_input.add(&escape_menu_label(&device.label()));
+    }
+}
+
+fn save_config(config: &config::Config) {
+    if let Err(e) = config.save() {
+        error!("Failed to save config: {:?}", e);
+    }
+}
+
+// Write the JSON events to `path`, appended to those of earlier runs
+fn open_event_log(path: &std::path::Path) -> anyhow::Result<()> {
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    }
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    events::set_sink(Some(Box::new(file)));
+    Ok(())
+}
+
+// Put the current device on top of the recent list and persist it
+fn remember_device(state: &mut State) {
+    let port: u16 = match state.port.parse() {
+        Ok(v) => v,
+        Err(_) => return,
+    };
+    let device = config::Device {
+        nickname: String::new(),
+        ip: state.ip.clone(),
+        port,
+        last_soc: Some(state.soc.clone()).filter(|soc| !soc.is_empty()),
+        auth: match state.password {
+            Some(_) => config::AuthMethod::Password,
+            None => config::AuthMethod::DefaultPassword,
+        },
+    };
+    state.config.remember(device);
+    save_config(&state.config);
+}
+
+fn credentials_for_device(state: &State, ip: &str, port: u16) -> flasher::Credentials {
+    vault::credentials(state.password.as_deref(), state.vault.as_ref(), ip, port)
+}
+
+// The recipe chosen for flashing, the built-in one if the list is empty
+fn selected_recipe(state: &State) -> recipe::Recipe {
+    state.recipes.get(state.recipe).cloned().unwrap_or_else(recipe::Recipe::builtin)
+}
+
+// The connection to a device, kept between operations. Switching to another device
+// closes the previous one.
+fn device_session(state: &mut State, ip: &str, port: u16) -> Arc<DeviceSession> {
+    let candidates = credentials_for_device(state, ip, port).candidates;
+    if let Some(session) = &state.session {
+        if session.host() == ip && session.port() == port {
+            session.set_candidates(candidates);
+            return session.clone();
+        }
+    }
+    let session = DeviceSession::new(ip, port, flasher::Credentials::new(candidates));
+    if let Some(previous) = state.session.replace(session.clone()) {
+        tokio::spawn(async move { previous.close().await });
+    }
+    session
+}
+
+// Keep the password that worked for the next operation and in the vault
+fn store_working_password(state: &mut State, credentials: &flasher::Credentials) {
+    let password = match &credentials.working {
+        Some(password) => password.clone(),
+        None => return,
+    };
+    state.password = Some(password.clone()).filter(|p| p != flasher::DEFAULT_PASSWORD);
+    store_device_password(state, &password);
+}
+
+// Keep the password of the current device in the vault, if one is unlocked and the
+// user opted in to remembering passwords
+fn store_device_password(state: &mut State, password: &str) {
+    if !state.config.remember_passwords {
+        return;
+    }
+    let port: u16 = match state.port.parse() {
+        Ok(v) => v,
+        Err(_) => return,
+    };
+    let key = vault::device_key(&state.ip, port);
+    if let Some(vault) = state.vault.as_mut() {
+        vault.keep_password(&key, password);
+    }
+}
+
+fn prompt_new_password() -> Option<String> {
+    let password = fltk::dialog::password_default("New root password for the device:", "")?;
+    let confirm = fltk::dialog::password_default("Repeat the new root password:", "")?;
+    if password.is_empty() || password != confirm {
+        fltk::dialog::alert_default("The passwords are empty or do not match.");
+        return None;
+    }
+    Some(password)
+}
+
+// Remember a password the app just set on the device
+fn record_new_password(state: &mut State, credentials: &flasher::Credentials) {
+    store_working_password(state, credentials);
+    remember_device(state);
+}
+
+fn unlock_vault() -> Option<vault::Vault> {
+    loop {
+        let passphrase = fltk::dialog::password_default("Master passphrase for the credential vault:", "")?;
+        match vault::Vault::open(&passphrase) {
+            Ok(vault) => return Some(vault),
+            Err(e) => {
+                error!("error: {:?}", e);
+                fltk::dialog::alert_default(&format!("Could not unlock the vault: {}", e));
+            }
+        }
+    }
+}
+
+// Unlock the vault, or create one if there is none yet. The dialogs run without
+// the state lock so running operations are not blocked meanwhile.
+fn open_vault(state: &Mutex<State>) -> bool {
+    if state.lock().unwrap().vault.is_some() {
+        return true;
+    }
+    let vault = if vault::Vault::exists() {
+        unlock_vault()
+    } else {
+        create_vault()
+    };
+    let mut state = state.lock().unwrap();
+    if state.vault.is_none() {
+        state.vault = vault;
+    }
+    state.vault.is_some()
+}
+
+fn create_vault() -> Option<vault::Vault> {
+    let passphrase = fltk::dialog::password_default("Choose a master passphrase for the new credential vault:", "")?;
+    let confirm = fltk::dialog::password_default("Repeat the master passphrase:", "")?;
+    if passphrase.is_empty() || passphrase != confirm {
+        fltk::dialog::alert_default("The passphrases are empty or do not match.");
+        return None;
+    }
+    match vault::Vault::open(&passphrase) {
+        Ok(vault) => Some(vault),
+        Err(e) => {
+            error!("error: {:?}", e);
+            fltk::dialog::alert_default(&format!("Could not create the vault: {}", e));
+            None
+        }
+    }
+}
+
+// Modal editor for the fleet passwords, one per line
+fn edit_fleet_passwords(current: &[String]) -> Option<Vec<String>> {
+    let (x, y) = center();
+    let (w, h) = (360, 260);
+    let mut wind = Window::new(x - w / 2, y - h / 2, w, h, "Fleet passwords");
+    let mut col = Flex::default().size_of_parent().column();
+    col.set_margin(10);
+    let label = Frame::default().with_label("Tried on every device after its stored password, one per line:");
+    col.fixed(&label, 20);
+    let mut input = MultilineInput::default();
+    input.set_value(&current.join("\n"));
+    let mut row = Flex::default().row();
+    Frame::default();
+    let mut btn_cancel = Button::default().with_label("Cancel");
+    let mut btn_save = Button::default().with_label("Save");
+    row.fixed(&btn_cancel, 90);
+    row.fixed(&btn_save, 90);
+    row.end();
+    col.fixed(&row, 29);
+    col.end();
+    wind.end();
+    wind.make_modal(true);
+    wind.show();
+
+    let result = Arc::new(Mutex::new(None));
+    let mut wind_cancel = wind.clone();
+    btn_cancel.set_callback(move |_| wind_cancel.hide());
+    let mut wind_save = wind.clone();
+    let result_save = result.clone();
+    btn_save.set_callback(move |_| {
+        let passwords = input
+            .value()
+            .lines()
+            .map(|line| line.trim().to_string())
+            .filter(|line| !line.is_empty())
+            .collect::<Vec<_>>();
+        *result_save.lock().unwrap() = Some(passwords);
+        wind_save.hide();
+    });
+    while wind.shown() {
+        app::wait();
+    }
+    let passwords = result.lock().unwrap().take();
+    passwords
+}
+
+pub fn center() -> (i32, i32) {
+    (
+        (app::screen_size().0 / 2.0) as i32,
+        (app::screen_size().1 / 2.0) as i32,
+    )
+}
+
+#[derive(Copy, Clone)]
+enum Message {
+    PortChanged,
+    IpChanged,
+    DetectSoc,
+    Flash,
+    ResetDevice,
+    EnterManualMode,
+    ExitManualMode,
+    ExecuteManualCommand,
+    PromptPasswordAndRetry(RetryAction),
+    RefreshRecentDevices,
+    RenameDevice,
+    ToggleRememberPasswords,
+    UnlockVault,
+    ManageVault,
+    SetDevicePassword,
+    ScanNetwork,
+    BatchFlash,
+    WatchMode,
+    History,
+    SaveLog,
+    SupportBundle,
+    CompleteCommand,
+    ShowCompletions,
+    StopManualCommand,
+    DeviceLogs,
+    RecipeChanged,
+    RunScript(usize),
+    EventLog,
+}
+
+#[derive(Copy, Clone)]
+enum RetryAction {
+    DetectSoc,
+    Flash,
+    ResetDevice,
+}
+
+#[derive(Default)]
+struct State {
+    soc: String,
+    ip: String,
+    port: String,
+    password: Option<String>,
+    config: config::Config,
+    vault: Option<vault::Vault>,
+    // Interactive shell of manual mode
+    shell: Option<console::Console>,
+    history: console::History,
+    // Line that was completed and the candidates found for it
+    completions: Option<(String, Vec<String>)>,
+    // When Stop was last pressed, a second press soon after terminates the command
+    last_stop: Option<Instant>,
+    // Connection to the last device used, shared by every operation on it
+    session: Option<Arc<DeviceSession>>,
+    // Flash recipes to choose from and the index of the chosen one
+    recipes: Vec<recipe::Recipe>,
+    recipe: usize,
+    // Workflow scripts listed in the Actions menu
+    scripts: Vec<script::Script>,
+}
+
+struct RubyFlasher {
+    app: app::App,
+    receiver: app::Receiver<Message>,
+    sender: app::Sender<Message>,
+    display: Arc<Mutex<DisplayState>>,
+    terminal: TerminalView,
+    progress: Arc<Mutex<ProgressView>>,
+    ip_input: InputChoice,
+    port_input: Input,
+    btn_detect: Button,
+    btn_flash: Button,
+    recipe_choice: Choice,
+    menu_btn: MenuButton,
+    manual_input: Input,
+    manual_flex: Flex,
+    container: Flex,
+    state: Arc<Mutex<State>>,
+}
+
+impl RubyFlasher {
+    pub fn new() -> Self {
+        let app = app::App::default().with_scheme(app::Scheme::Gtk);
+
+        // Apply theme based on system dark mode
+        let theme = if is_dark_mode() {
+            ColorTheme::new(color_themes::DARK_THEME) // Dark theme
+        } else {
+            ColorTheme::new(color_themes::GRAY_THEME) // Light theme
+        };
+        theme.apply();
+
+        // Message channel
+        let (s, receiver) = app::channel();
+
+        let (x, y) = center();
+        let (w, h) = (720, 576);
+        let mut wind = Window::new(x - w / 2, y - h / 2, w, h, "RubyFPV simple flasher");
+        wind.set_xclass(wind.label().as_str());
+        wind.make_resizable(true);
+        let bytes = Asset::get("ruby.ico").unwrap();
+        let image = IcoImage::from_data(&bytes.data).unwrap();
+        wind.set_icon(Some(image));
+
+        let mut container = Flex::default().size_of_parent().column();
+        container.set_margin(12);
+
+        let mut flex = Flex::default().size_of_parent().row();
+        container.fixed(&flex, 29);
+        let frame = Frame::default()
+            .with_label("IP address:")
+            .with_align(enums::Align::Inside);
+        flex.fixed(&frame, 70);
+        let mut ip_input = InputChoice::default();
+        ip_input.emit(s, Message::IpChanged);
+        let frame = Frame::default()
+            .with_label("port:")
+            .with_align(enums::Align::Inside);
+        flex.fixed(&frame, 30);
+        let mut port_input = Input::default().with_type(InputType::Int);
+        port_input.set_value("22");
+        port_input.emit(s, Message::PortChanged);
+
+        flex.fixed(&port_input, 70);
+
+        let mut btn_detect = Button::default().with_label("Identify SOC");
+
+        // Which flash workflow the Flash button runs
+        let mut recipe_choice = Choice::default();
+        flex.fixed(&recipe_choice, 110);
+        recipe_choice.emit(s, Message::RecipeChanged);
+
+        let mut btn_flash = Button::default().with_label("Flash firmware...");
+        btn_flash.deactivate();
+
+        let mut menu_btn = MenuButton::default().with_label("Actions");
+        menu_btn.deactivate();
+
+        // Tools that do not need an identified device
+        let mut tools_btn = MenuButton::default().with_label("Tools");
+        flex.fixed(&tools_btn, 80);
+
+        flex.end();
+
+        // Flash phases and upload progress
+        let progress = Arc::new(Mutex::new(ProgressView::new(&mut container)));
+
+        // Main display area
+        let display = Arc::new(Mutex::new(DisplayState::new()));
+        {
+            let display_guard = display.lock().unwrap();
+            container.add(&display_guard.disp);
+        }
+
+        // Console of manual mode, takes the place of the log while it is open
+        let mut terminal = TerminalView::new(is_dark_mode());
+        container.add(&terminal.widget);
+        terminal.widget.hide();
+
+        // Manual command input area (initially hidden)
+        let mut manual_flex = Flex::default().row();
+        let manual_label = Frame::default().with_label("Command:");
+        manual_flex.fixed(&manual_label, 70);
+        let mut manual_input = Input::default();
+        manual_flex.add(&manual_input);
+        let mut snippets_btn = MenuButton::default().with_label("Snippets");
+        manual_flex.fixed(&snippets_btn, 90);
+        let mut manual_stop_btn = Button::default().with_label("Stop");
+        manual_stop_btn.set_tooltip("Interrupt the running command (Ctrl-C), press again to terminate it");
+        manual_flex.fixed(&manual_stop_btn, 60);
+        let mut manual_exit_btn = Button::default().with_label("Exit Manual Mode");
+        manual_flex.fixed(&manual_exit_btn, 150);
+        manual_flex.end();
+
+        // Add manual flex to container and initially hide it
+        container.fixed(&manual_flex, 29);
+        manual_flex.hide(); // Initially hidden
+
+        container.end();
+        wind.end();
+        wind.show();
+
+        btn_detect.emit(s, Message::DetectSoc);
+        tools_btn.add_emit("Scan network...", Shortcut::None, MenuFlag::Normal, s, Message::ScanNetwork);
+        tools_btn.add_emit("Batch flash...", Shortcut::None, MenuFlag::Normal, s, Message::BatchFlash);
+        tools_btn.add_emit("Watch and auto-flash...", Shortcut::None, MenuFlag::Normal, s, Message::WatchMode);
+        tools_btn.add_emit("Flash history...", Shortcut::None, MenuFlag::Normal, s, Message::History);
+        tools_btn.add_emit("Save log...", Shortcut::None, MenuFlag::Normal, s, Message::SaveLog);
+        tools_btn.add_emit("Support bundle...", Shortcut::None, MenuFlag::Normal, s, Message::SupportBundle);
+        tools_btn.add_emit("JSON event log...", Shortcut::None, MenuFlag::Normal, s, Message::EventLog);
+        btn_flash.emit(s, Message::Flash);
+
+        // Set up the menu items
+        menu_btn.add_choice("Reset device");
+        menu_btn.add_choice("Manual command execution");
+        menu_btn.add_choice("Rename device...");
+        menu_btn.add_choice("Credential vault...");
+        menu_btn.add_choice("Set device password...");
+        menu_btn.add_choice("Device logs...");
+        let scripts = script::list();
+        for (index, script) in scripts.iter().enumerate() {
+            let label = format!("Scripts/{}", escape_menu_label(&script.name));
+            menu_btn.add_emit(&label, Shortcut::None, MenuFlag::Normal, s, Message::RunScript(index));
+        }
+        if scripts.is_empty() {
+            let folder = script::script_dir().map(|dir| dir.display().to_string()).unwrap_or_default();
+            let label = format!("Scripts/Put *.rhai scripts in {}", escape_menu_label(&folder));
+            menu_btn.add(&label, Shortcut::None, MenuFlag::Inactive, |_| {});
+        }
+        menu_btn.add("Set password after flash", Shortcut::None, MenuFlag::Toggle, |_| {});
+        menu_btn.add_emit(
+            "Remember passwords",
+            Shortcut::None,
+            MenuFlag::Toggle,
+            s,
+            Message::ToggleRememberPasswords,
+        );
+
+        // Set up menu callback
+        let s_menu = s.clone();
+        menu_btn.set_callback(move |m| {
+            if let Some(choice) = m.choice() {
+                match choice.as_str() {
+                    "Reset device" => s_menu.send(Message::ResetDevice),
+                    "Manual command execution" => s_menu.send(Message::EnterManualMode),
+                    "Rename device..." => s_menu.send(Message::RenameDevice),
+                    "Credential vault..." => s_menu.send(Message::ManageVault),
+                    "Set device password..." => s_menu.send(Message::SetDevicePassword),
+                    "Device logs..." => s_menu.send(Message::DeviceLogs),
+                    _ => {}
+                }
+            }
+        });
+
+        // Set up manual mode callbacks
+        manual_input.set_trigger(fltk::enums::CallbackTrigger::EnterKey);
+        manual_input.emit(s, Message::ExecuteManualCommand);
+        manual_exit_btn.emit(s, Message::ExitManualMode);
+        manual_stop_btn.emit(s, Message::StopManualCommand);
+        for (label, command) in console::SNIPPETS {
+            let mut input = manual_input.clone();
+            snippets_btn.add(label, Shortcut::None, MenuFlag::Normal, move |_| {
+                input.set_value(command);
+                let _ = input.take_focus();
+            });
+        }
+
+        // Restore recent devices and preselect the last used one
+        let config = config::Config::load();
+        fill_recent_devices(&mut ip_input, &config);
+        if config.remember_passwords {
+            if let Some(mut item) = menu_btn.find_item("Remember passwords") {
+                item.set();
+            }
+        }
+        let mut state = State {
+            port: "22".to_string(),
+            ..Default::default()
+        };
+        if let Some(device) = config.devices.first() {
+            ip_input.set_value(&device.ip);
+            port_input.set_value(&device.port.to_string());
+            state.ip = device.ip.clone();
+            state.port = device.port.to_string();
+        }
+        if let Some(path) = &config.event_log {
+            if let Err(e) = open_event_log(path) {
+                error!("error: {:?}", e);
+            }
+        }
+        state.config = config;
+        state.history = console::History::load();
+        state.scripts = scripts;
+        state.recipes = recipe::load_all();
+        let builtin = recipe::Recipe::builtin();
+        state.recipe = state.recipes.iter().position(|r| r.name == builtin.name).unwrap_or(0);
+        for recipe in state.recipes.iter() {
+            recipe_choice.add_choice(&recipe.name.replace('/', "\\/").replace('|', "\\|"));
+        }
+        recipe_choice.set_value(state.recipe as i32);
+        if let Some(recipe) = state.recipes.get(state.recipe) {
+            recipe_choice.set_tooltip(&recipe.description);
+        }
+        let state = Arc::new(Mutex::new(state));
+
+        // Up/down browse the command history, tab completes on the device
+        let state_keys = state.clone();
+        manual_input.handle(move |input, ev| {
+            if ev != Event::KeyDown {
+                return false;
+            }
+            let key = app::event_key();
+            let entry = match key {
+                Key::Up => state_keys.lock().unwrap().history.previous(&input.value()),
+                Key::Down => state_keys.lock().unwrap().history.next(),
+                Key::Tab => {
+                    s.send(Message::CompleteCommand);
+                    return true;
+                }
+                // Ctrl-C without a selection to copy interrupts the command on the device
+                k if k == Key::from_char('c') && app::is_event_ctrl() && input.position() == input.mark() => {
+                    s.send(Message::StopManualCommand);
+                    return true;
+                }
+                _ => return false,
+            };
+            if let Some(entry) = entry {
+                input.set_value(&entry);
+                input.set_position(entry.len() as i32).ok();
+            }
+            true
+        });
+
+        // Keys typed into the console go straight to the shell
+        let state_input = state.clone();
+        let state_resize = state.clone();
+        terminal.set_handlers(
+            move |bytes| {
+                if let Some(shell) = &state_input.lock().unwrap().shell {
+                    shell.send(bytes);
+                }
+            },
+            move |cols, rows| {
+                if let Some(shell) = &state_resize.lock().unwrap().shell {
+                    shell.resize(cols as u32, rows as u32);
+                }
+            },
+        );
+
+        Self {
+            app,
+            receiver,
+            sender: s,
+            ip_input,
+            port_input,
+            display,
+            terminal,
+            progress,
+            state,
+            btn_detect,
+            btn_flash,
+            recipe_choice,
+            menu_btn,
+            manual_input,
+            manual_flex,
+            container,
+        }
+    }
+
+    // Follows the system and kernel logs of the device until the window is closed
+    fn show_logs_window(&mut self) {
+        let (ip, port) = {
+            let state = self.state.lock().unwrap();
+            (state.ip.clone(), state.port.parse::<u16>().unwrap_or(22))
+        };
+        if ip.is_empty() {
+            update_status(&mut self.display.lock().unwrap(), "Error: Please enter an IP address first.");
+            return;
+        }
+
+        let (x, y) = center();
+        let (w, h) = (860, 560);
+        let mut wind = Window::new(x - w / 2, y - h / 2, w, h, None);
+        wind.set_label(&format!("Device logs - {}", ip));
+        let mut col = Flex::default().size_of_parent().column();
+        col.set_margin(10);
+        let mut row = Flex::default().row();
+        let label = Frame::default().with_label("Level:");
+        row.fixed(&label, 40);
+        let mut level_choice = Choice::default();
+        for level in devlog::LEVELS {
+            level_choice.add_choice(level);
+        }
+        level_choice.set_value(devlog::LEVELS.len() as i32 - 1);
+        row.fixed(&level_choice, 90);
+        let label = Frame::default().with_label("Filter:");
+        row.fixed(&label, 40);
+        let mut filter_input = Input::default();
+        filter_input.set_trigger(enums::CallbackTrigger::Changed);
+        let mut btn_pause = ToggleButton::default().with_label("Pause");
+        row.fixed(&btn_pause, 70);
+        let mut btn_clear = Button::default().with_label("Clear");
+        row.fixed(&btn_clear, 70);
+        let mut btn_save = Button::default().with_label("Save...");
+        row.fixed(&btn_save, 70);
+        row.end();
+        col.fixed(&row, 29);
+        let mut browser = Browser::default();
+        browser.set_text_size(12);
+        let status = Frame::default()
+            .with_label(&format!("Following logread -f and dmesg -w on {}:{}...", ip, port))
+            .with_align(enums::Align::Inside | enums::Align::Left);
+        col.fixed(&status, 20);
+        col.end();
+        wind.end();
+        wind.make_resizable(true);
+        wind.show();
+
+        // Everything received, the browser only shows what passes the filter
+        struct LogView {
+            lines: VecDeque<devlog::LogLine>,
+            max_level: u8,
+            needle: String,
+            paused: bool,
+        }
+        let view = Arc::new(Mutex::new(LogView {
+            lines: VecDeque::new(),
+            max_level: devlog::LEVELS.len() as u8 - 1,
+            needle: String::new(),
+            paused: false,
+        }));
+        fn browser_line(line: &devlog::LogLine) -> String {
+            // Fixed font, "@." keeps '@' in the log text from being read as formatting
+            let color = match line.level {
+                0..=3 => "@C1",
+                4 => "@C95",
+                _ => "",
+            };
+            format!("@f{}@.{}", color, line.display())
+        }
+        fn rebuild(browser: &mut Browser, view: &LogView) {
+            browser.clear();
+            for line in view.lines.iter().filter(|l| l.matches(view.max_level, &view.needle)) {
+                browser.add(&browser_line(line));
+            }
+            browser.bottom_line(browser.size());
+        }
+
+        let view_level = view.clone();
+        let mut browser_level = browser.clone();
+        level_choice.set_callback(move |c| {
+            let mut view = view_level.lock().unwrap();
+            view.max_level = c.value().max(0) as u8;
+            rebuild(&mut browser_level, &view);
+        });
+        let view_filter = view.clone();
+        let mut browser_filter = browser.clone();
+        filter_input.set_callback(move |i| {
+            let mut view = view_filter.lock().unwrap();
+            view.needle = i.value();
+            rebuild(&mut browser_filter, &view);
+        });
+        let view_pause = view.clone();
+        let mut browser_pause = browser.clone();
+        btn_pause.set_callback(move |b| {
+            let mut view = view_pause.lock().unwrap();
+            view.paused = b.value();
+            b.set_label(if view.paused { "Resume" } else { "Pause" });
+            if !view.paused {
+                rebuild(&mut browser_pause, &view);
+            }
+        });
+        let view_clear = view.clone();
+        let mut browser_clear = browser.clone();
+        btn_clear.set_callback(move |_| {
+            view_clear.lock().unwrap().lines.clear();
+            browser_clear.clear();
+        });
+        let view_save = view.clone();
+        let ip_save = ip.clone();
+        btn_save.set_callback(move |_| {
+            if let Some(path) = choose_save_file("*.log", &format!("device-{}.log", ip_save.replace(':', "_"))) {
+                let view = view_save.lock().unwrap();
+                let lines: Vec<&devlog::LogLine> = view.lines.iter().filter(|l| l.matches(view.max_level, &view.needle)).collect();
+                if let Err(e) = devlog::save(&lines, &path) {
+                    error!("error: {:?}", e);
+                    fltk::dialog::alert_default(&format!("Saving the log failed: {}", e));
+                }
+            }
+        });
+
+        // Closing the window ends both streams
+        let (stop_tx, stop_rx) = tokio::sync::watch::channel(false);
+        wind.set_callback(move |w| {
+            let _ = stop_tx.send(true);
+            w.hide();
+        });
+
+        for source in [devlog::LogSource::System, devlog::LogSource::Kernel] {
+            let ip = ip.clone();
+            let stop = stop_rx.clone();
+            let view = view.clone();
+            let mut browser = browser.clone();
+            let mut status = status.clone();
+            let state_clone = self.state.clone();
+            tokio::spawn(async move {
+                let session = device_session(&mut state_clone.lock().unwrap(), &ip, port);
+                let result = devlog::stream_command(&session, source.command(), stop, |text| {
+                    let line = devlog::LogLine::parse(source, text);
+                    let mut view = view.lock().unwrap();
+                    if !view.paused && line.matches(view.max_level, &view.needle) {
+                        browser.add(&browser_line(&line));
+                        if browser.size() > devlog::MAX_LINES as i32 {
+                            browser.remove(1);
+                        }
+                        browser.bottom_line(browser.size());
+                        app::awake();
+                    }
+                    view.lines.push_back(line);
+                    if view.lines.len() > devlog::MAX_LINES {
+                        view.lines.pop_front();
+                    }
+                })
+                .await;
+                let msg = match result {
+                    Ok(Some(code)) if code != 0 => format!("{} exited with status {}", source.command(), code),
+                    Ok(_) => format!("{} stopped", source.command()),
+                    Err(e) => {
+                        error!("error: {:?}", e);
+                        format!("{} failed: {}", source.command(), e)
+                    }
+                };
+                status.set_label(&msg);
+                app::awake();
+            });
+        }
+    }
+
+    // Past detect/flash/reset operations, newest first, with the full log of the selected one
+    fn show_history_window(&mut self) {
+        let mut records = history::load();
+        records.reverse();
+        let records = Arc::new(records);
+
+        let (x, y) = center();
+        let (w, h) = (900, 560);
+        let mut wind = Window::new(x - w / 2, y - h / 2, w, h, "Flash history");
+        let mut col = Flex::default().size_of_parent().column();
+        col.set_margin(10);
+        let mut header = Frame::default()
+            .with_label("Time / operation / unit / SoC / firmware / version / result / duration")
+            .with_align(enums::Align::Inside | enums::Align::Left);
+        header.set_label_size(12);
+        col.fixed(&header, 18);
+        let mut browser = HoldBrowser::default();
+        browser.set_column_widths(&[150, 60, 130, 70, 220, 150, 60]);
+        browser.set_column_char('\t');
+        let mut log_display = TextDisplay::default();
+        log_display.set_buffer(TextBuffer::default());
+        log_display.set_text_font(Font::Courier);
+        col.fixed(&log_display, 200);
+        let mut row = Flex::default().row();
+        let mut btn_csv = Button::default().with_label("Export CSV...");
+        let mut btn_jsonl = Button::default().with_label("Export JSON lines...");
+        Frame::default();
+        let mut btn_close = Button::default().with_label("Close");
+        row.fixed(&btn_csv, 120);
+        row.fixed(&btn_jsonl, 150);
+        row.fixed(&btn_close, 90);
+        row.end();
+        col.fixed(&row, 29);
+        col.end();
+        wind.end();
+        wind.make_resizable(true);
+        wind.show();
+
+        for r in records.iter() {
+            let version = match (&r.previous_version, &r.new_version) {
+                (Some(before), Some(after)) => format!("{} -> {}", before, after),
+                (Some(before), None) => before.clone(),
+                (None, Some(after)) => after.clone(),
+                (None, None) => String::new(),
+            };
+            let firmware = r
+                .firmware
+                .as_deref()
+                .map(|f| std::path::Path::new(f).file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default())
+                .unwrap_or_default();
+            browser.add(&format!(
+                "{}\t{}\t{}\t{}\t{}\t{}\t{}{}\t{}",
+                r.timestamp.replace('T', " "),
+                r.operation,
+                r.identity.as_deref().unwrap_or(&r.host),
+                r.soc.as_deref().unwrap_or(""),
+                firmware,
+                version,
+                if r.success { "@C60" } else { "@C1" },
+                if r.success { "ok" } else { "failed" },
+                format_duration(r.duration_secs)
+            ));
+        }
+        if records.is_empty() {
+            log_display.buffer().unwrap().set_text("No operations recorded yet.");
+        }
+
+        let records_select = records.clone();
+        let log_select = log_display.clone();
+        browser.set_callback(move |b| {
+            let index = b.value();
+            if index < 1 {
+                return;
+            }
+            if let Some(r) = records_select.get(index as usize - 1) {
+                let mut text = format!("{} {} on {}:{}\n", r.timestamp, r.operation, r.host, r.port);
+                if let Some(firmware) = &r.firmware {
+                    text.push_str(&format!("Firmware: {}\n", firmware));
+                }
+                if let Some(hash) = &r.firmware_sha256 {
+                    text.push_str(&format!("SHA-256: {}\n", hash));
+                }
+                if let Some(error) = &r.error {
+                    text.push_str(&format!("Error: {}\n", error));
+                }
+                text.push('\n');
+                text.push_str(&r.log);
+                log_select.buffer().unwrap().set_text(&text);
+            }
+        });
+
+        let records_csv = records.clone();
+        btn_csv.set_callback(move |_| {
+            if let Some(path) = choose_save_file("*.csv", "history.csv") {
+                if let Err(e) = history::export_csv(&records_csv, &path) {
+                    error!("error: {:?}", e);
+                    fltk::dialog::alert_default(&format!("Export failed: {}", e));
+                }
+            }
+        });
+        let records_jsonl = records.clone();
+        btn_jsonl.set_callback(move |_| {
+            if let Some(path) = choose_save_file("*.jsonl", "history.jsonl") {
+                if let Err(e) = history::export_jsonl(&records_jsonl, &path) {
+                    error!("error: {:?}", e);
+                    fltk::dialog::alert_default(&format!("Export failed: {}", e));
+                }
+            }
+        });
+        let mut wind_close = wind.clone();
+        btn_close.set_callback(move |_| wind_close.hide());
+    }
+
+    // Non-modal window listing the devices found on the local networks
+    fn show_scan_window(&mut self) {
+        let port: u16 = self.state.lock().unwrap().port.parse().unwrap_or(22);
+        let targets = discovery::scan_targets();
+
+        let (x, y) = center();
+        let (w, h) = (640, 360);
+        let mut wind = Window::new(x - w / 2, y - h / 2, w, h, "Network scan");
+        let mut col = Flex::default().size_of_parent().column();
+        col.set_margin(10);
+        let mut status = Frame::default()
+            .with_label(&format!("{} addresses on port {}.", targets.len(), port))
+            .with_align(enums::Align::Inside | enums::Align::Left);
+        col.fixed(&status, 20);
+        // Off by default, logging in sends the known passwords to every OpenIPC device on the network
+        let login = CheckButton::default().with_label("Log in to OpenIPC devices to read their SoC and hostname");
+        col.fixed(&login, 22);
+        let mut header = Frame::default()
+            .with_label("IP address / SoC / hostname / SSH server")
+            .with_align(enums::Align::Inside | enums::Align::Left);
+        header.set_label_size(12);
+        col.fixed(&header, 18);
+        let mut browser = HoldBrowser::default();
+        browser.set_column_widths(&[130, 90, 170]);
+        browser.set_column_char('\t');
+        let mut row = Flex::default().row();
+        Frame::default();
+        let mut btn_close = Button::default().with_label("Close");
+        let mut btn_scan = Button::default().with_label("Scan");
+        let mut btn_use = Button::default().with_label("Use selected");
+        row.fixed(&btn_close, 90);
+        row.fixed(&btn_scan, 90);
+        row.fixed(&btn_use, 110);
+        row.end();
+        col.fixed(&row, 29);
+        col.end();
+        wind.end();
+        wind.make_resizable(true);
+        wind.show();
+
+        let devices: Arc<Mutex<Vec<discovery::FoundDevice>>> = Arc::new(Mutex::new(Vec::new()));
+
+        let mut wind_close = wind.clone();
+        btn_close.set_callback(move |_| wind_close.hide());
+
+        // Fill in the main window and let the usual change handlers pick it up
+        let mut wind_use = wind.clone();
+        let browser_use = browser.clone();
+        let devices_use = devices.clone();
+        let mut ip_input = self.ip_input.clone();
+        let mut port_input = self.port_input.clone();
+        let sender = self.sender;
+        let mut use_selected = move || {
+            let index = browser_use.value();
+            if index < 1 {
+                return;
+            }
+            if let Some(device) = devices_use.lock().unwrap().get(index as usize - 1) {
+                ip_input.set_value(&device.ip.to_string());
+                port_input.set_value(&device.port.to_string());
+                sender.send(Message::IpChanged);
+                sender.send(Message::PortChanged);
+                wind_use.hide();
+            }
+        };
+        let mut use_on_double_click = use_selected.clone();
+        btn_use.set_callback(move |_| use_selected());
+        browser.set_callback(move |_| {
+            if app::event_clicks() {
+                use_on_double_click();
+            }
+        });
+
+        let state = self.state.clone();
+        btn_scan.set_callback(move |btn| {
+            btn.deactivate();
+            browser.clear();
+            devices.lock().unwrap().clear();
+            status.set_label(&format!("Scanning {} addresses on port {}...", targets.len(), port));
+            let (targets, login) = (targets.clone(), login.is_checked());
+            let (mut btn, mut browser, mut status) = (btn.clone(), browser.clone(), status.clone());
+            let (devices, state) = (devices.clone(), state.clone());
+            tokio::spawn(async move {
+                let result = discovery::scan(
+                    targets,
+                    port,
+                    login,
+                    |ip| credentials_for_device(&state.lock().unwrap(), &ip.to_string(), port),
+                    |device| {
+                        browser.add(&format!(
+                            "{}\t{}\t{}\t{}",
+                            device.ip,
+                            device.soc.as_deref().unwrap_or("?"),
+                            device.hostname.as_deref().unwrap_or(""),
+                            device.banner
+                        ));
+                        devices.lock().unwrap().push(device.clone());
+                        app::awake();
+                    },
+                )
+                .await;
+                match result {
+                    Ok(found) => status.set_label(&format!("Scan finished, {} devices found.", found.len())),
+                    Err(e) => status.set_label(&format!("Scan failed: {}", e)),
+                }
+                btn.activate();
+                app::awake();
+                app::redraw();
+            });
+        });
+    }
+
+    // Flash many devices at once, each with the firmware matching its SoC
+    fn show_batch_window(&mut self) {
+        let (x, y) = center();
+        let (w, h) = (720, 520);
+        let mut wind = Window::new(x - w / 2, y - h / 2, w, h, "Batch flash");
+        let mut col = Flex::default().size_of_parent().column();
+        col.set_margin(10);
+        let label = Frame::default()
+            .with_label("Devices, one per line: host[,port[,password to the end of the line]]")
+            .with_align(enums::Align::Inside | enums::Align::Left);
+        col.fixed(&label, 20);
+        let mut devices_input = MultilineInput::default();
+        col.fixed(&devices_input, 120);
+        {
+            let state = self.state.lock().unwrap();
+            let list: Vec<String> = state
+                .config
+                .devices
+                .iter()
+                .map(|d| format!("{},{}", d.ip, d.port))
+                .collect();
+            devices_input.set_value(&list.join("\n"));
+        }
+
+        let mut row = Flex::default().row();
+        let frame = Frame::default().with_label("Firmware folder:");
+        row.fixed(&frame, 110);
+        let dir_input = Input::default();
+        let mut btn_browse = Button::default().with_label("Browse...");
+        row.fixed(&btn_browse, 90);
+        row.end();
+        col.fixed(&row, 29);
+
+        let mut row = Flex::default().row();
+        let frame = Frame::default().with_label("Parallel jobs:");
+        row.fixed(&frame, 110);
+        let mut jobs_input = Input::default().with_type(InputType::Int);
+        jobs_input.set_value("4");
+        row.fixed(&jobs_input, 60);
+        Frame::default();
+        let mut btn_start = Button::default().with_label("Start");
+        row.fixed(&btn_start, 90);
+        row.end();
+        col.fixed(&row, 29);
+
+        let mut header = Frame::default()
+            .with_label("Device / SoC / Result / Time / Details")
+            .with_align(enums::Align::Inside | enums::Align::Left);
+        header.set_label_size(12);
+        col.fixed(&header, 18);
+        let mut results = HoldBrowser::default();
+        results.set_column_widths(&[160, 80, 70, 60]);
+        results.set_column_char('\t');
+        let summary = Frame::default().with_align(enums::Align::Inside | enums::Align::Left);
+        col.fixed(&summary, 20);
+        col.end();
+        wind.end();
+        wind.make_resizable(true);
+        wind.show();
+
+        let mut dir_browse = dir_input.clone();
+        btn_browse.set_callback(move |_| {
+            let mut dialog = fltk::dialog::NativeFileChooser::new(fltk::dialog::NativeFileChooserType::BrowseDir);
+            if let Ok(fltk::dialog::NativeFileChooserAction::Success) = dialog.try_show() {
+                dir_browse.set_value(&dialog.filename().to_string_lossy());
+            }
+        });
+
+        let state = self.state.clone();
+        let display = self.display.clone();
+        btn_start.set_callback(move |btn| {
+            let devices = match batch::parse_device_list(&devices_input.value()) {
+                Ok(devices) if !devices.is_empty() => devices,
+                Ok(_) => {
+                    fltk::dialog::alert_default("The device list is empty.");
+                    return;
+                }
+                Err(e) => {
+                    fltk::dialog::alert_default(&format!("Invalid device list: {}", e));
+                    return;
+                }
+            };
+            let firmware_dir = std::path::PathBuf::from(dir_input.value());
+            if !firmware_dir.is_dir() {
+                fltk::dialog::alert_default("Please choose the firmware folder.");
+                return;
+            }
+            let jobs: usize = jobs_input.value().parse().unwrap_or(4);
+
+            btn.deactivate();
+            results.clear();
+            let labels: Vec<String> = devices.iter().map(|d| d.label()).collect();
+            for label in labels.iter() {
+                results.add(&format!("{}\t\tqueued", label));
+            }
+            let mut summary = summary.clone();
+            summary.set_label("");
+
+            let mut btn = btn.clone();
+            let mut results = results.clone();
+            let state = state.clone();
+            let display = display.clone();
+            tokio::spawn(async move {
+                let results_live = results.clone();
+                let labels_live = labels.clone();
+                let display_live = display.clone();
+                let recipe = selected_recipe(&state.lock().unwrap());
+                let outcome = batch::run_batch(
+                    devices,
+                    recipe,
+                    firmware_dir,
+                    jobs,
+                    |device| credentials_for_device(&state.lock().unwrap(), &device.host, device.port),
+                    move |index, msg| {
+                        // Widget handles are cheap to clone, the status callback is shared between tasks
+                        let mut results_live = results_live.clone();
+                        results_live.set_text(index as i32 + 1, &format!("{}\t\trunning\t\t{}", labels_live[index], msg));
+                        update_status(&mut display_live.lock().unwrap(), &format!("[{}] {}", labels_live[index], msg));
+                    },
+                )
+                .await;
+
+                for (index, result) in outcome.iter().enumerate() {
+                    let details = match (&result.error, &result.firmware) {
+                        (Some(error), _) => error.clone(),
+                        (None, Some(firmware)) => firmware.file_name().unwrap_or_default().to_string_lossy().to_string(),
+                        (None, None) => String::new(),
+                    };
+                    results.set_text(
+                        index as i32 + 1,
+                        &format!(
+                            "{}{}\t{}\t{}\t{}\t{}",
+                            if result.error.is_some() { "@C1" } else { "@C60" },
+                            result.device.label(),
+                            result.soc.as_deref().unwrap_or("-"),
+                            if result.error.is_none() { "OK" } else { "FAILED" },
+                            batch::format_duration(result.duration),
+                            details
+                        ),
+                    );
+                }
+                let failed = outcome.iter().filter(|r| r.error.is_some()).count();
+                summary.set_label(&format!(
+                    "{} devices, {} ok, {} failed",
+                    outcome.len(),
+                    outcome.len() - failed,
+                    failed
+                ));
+                update_status(&mut display.lock().unwrap(), &batch::summary_table(&outcome));
+                btn.activate();
+                app::awake();
+                app::redraw();
+            });
+        });
+    }
+
+    // Production bench: keep polling and flash every new unit that shows up
+    fn show_watch_window(&mut self) {
+        let (x, y) = center();
+        let (w, h) = (560, 360);
+        let mut wind = Window::new(x - w / 2, y - h / 2, w, h, "Watch and auto-flash");
+        let mut col = Flex::default().size_of_parent().column();
+        col.set_margin(10);
+
+        let mut row = Flex::default().row();
+        let frame = Frame::default().with_label("Address or range:");
+        row.fixed(&frame, 120);
+        let mut targets_input = Input::default();
+        targets_input.set_value(&self.state.lock().unwrap().ip);
+        targets_input.set_tooltip("A single address, or a range of the last octet like 192.168.1.10-20");
+        row.end();
+        col.fixed(&row, 29);
+
+        let mut row = Flex::default().row();
+        let frame = Frame::default().with_label("Firmware folder:");
+        row.fixed(&frame, 120);
+        let dir_input = Input::default();
+        let mut btn_browse = Button::default().with_label("Browse...");
+        row.fixed(&btn_browse, 90);
+        row.end();
+        col.fixed(&row, 29);
+
+        let mut row = Flex::default().row();
+        Frame::default();
+        let mut btn_stop = Button::default().with_label("Stop");
+        btn_stop.deactivate();
+        let mut btn_start = Button::default().with_label("Start");
+        row.fixed(&btn_stop, 90);
+        row.fixed(&btn_start, 90);
+        row.end();
+        col.fixed(&row, 29);
+
+        // The big light the operator watches from across the bench
+        let mut result = Frame::default().with_label("Idle");
+        result.set_frame(FrameType::FlatBox);
+        result.set_label_size(36);
+        let mut counters = Frame::default().with_align(enums::Align::Inside | enums::Align::Left);
+        col.fixed(&counters, 20);
+        counters.set_label("Flashed: 0   Failed: 0");
+        col.end();
+        wind.end();
+        wind.make_resizable(true);
+        wind.show();
+
+        let mut dir_browse = dir_input.clone();
+        btn_browse.set_callback(move |_| {
+            let mut dialog = fltk::dialog::NativeFileChooser::new(fltk::dialog::NativeFileChooserType::BrowseDir);
+            if let Ok(fltk::dialog::NativeFileChooserAction::Success) = dialog.try_show() {
+                dir_browse.set_value(&dialog.filename().to_string_lossy());
+            }
+        });
+
+        let stop = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let stop_btn = stop.clone();
+        btn_stop.set_callback(move |btn| {
+            stop_btn.store(true, std::sync::atomic::Ordering::Relaxed);
+            btn.deactivate();
+        });
+        // Closing the window ends the watch as well
+        let stop_close = stop.clone();
+        wind.set_callback(move |w| {
+            stop_close.store(true, std::sync::atomic::Ordering::Relaxed);
+            w.hide();
+        });
+
+        let state = self.state.clone();
+        let display = self.display.clone();
+        let port: u16 = self.state.lock().unwrap().port.parse().unwrap_or(22);
+        btn_start.set_callback(move |btn| {
+            let targets = match watch::parse_targets(&targets_input.value()) {
+                Ok(targets) => targets,
+                Err(e) => {
+                    fltk::dialog::alert_default(&format!("{}", e));
+                    return;
+                }
+            };
+            let firmware_dir = std::path::PathBuf::from(dir_input.value());
+            if !firmware_dir.is_dir() {
+                fltk::dialog::alert_default("Please choose the firmware folder.");
+                return;
+            }
+            stop.store(false, std::sync::atomic::Ordering::Relaxed);
+            btn.deactivate();
+            btn_stop.activate();
+
+            let mut btn = btn.clone();
+            let mut btn_stop = btn_stop.clone();
+            let mut result = result.clone();
+            let mut counters = counters.clone();
+            let state = state.clone();
+            let display = display.clone();
+            let stop = stop.clone();
+            tokio::spawn(async move {
+                let (mut ok, mut failed) = (0, 0);
+                let recipe = selected_recipe(&state.lock().unwrap());
+                watch::watch(
+                    targets,
+                    port,
+                    recipe,
+                    firmware_dir,
+                    stop,
+                    |host| credentials_for_device(&state.lock().unwrap(), host, port),
+                    |event| {
+                        match event {
+                            watch::WatchEvent::Waiting => {
+                                if result.label() != "PASS" && result.label() != "FAIL" {
+                                    result.set_label("Waiting for a unit...");
+                                    result.set_color(Color::Background);
+                                    result.set_label_color(Color::Foreground);
+                                }
+                            }
+                            watch::WatchEvent::Started { host, identity, soc } => {
+                                result.set_label(&format!("Flashing {}", identity));
+                                result.set_color(Color::from_rgb(0, 120, 215));
+                                result.set_label_color(Color::White);
+                                update_status(
+                                    &mut display.lock().unwrap(),
+                                    &format!("New unit {} ({}) at {}", identity, soc, host),
+                                );
+                            }
+                            watch::WatchEvent::Log(msg) => {
+                                update_status(&mut display.lock().unwrap(), &msg);
+                            }
+                            watch::WatchEvent::Succeeded { identity } => {
+                                ok += 1;
+                                result.set_label("PASS");
+                                result.set_color(Color::from_rgb(0, 170, 0));
+                                result.set_label_color(Color::White);
+                                fltk::dialog::beep(fltk::dialog::BeepType::Message);
+                                update_status(
+                                    &mut display.lock().unwrap(),
+                                    &format!("\x1b[32m{} flashed successfully, connect the next unit.\x1b[0m", identity),
+                                );
+                            }
+                            watch::WatchEvent::Failed { identity, error } => {
+                                failed += 1;
+                                result.set_label("FAIL");
+                                result.set_color(Color::from_rgb(200, 0, 0));
+                                result.set_label_color(Color::White);
+                                fltk::dialog::beep(fltk::dialog::BeepType::Error);
+                                update_status(
+                                    &mut display.lock().unwrap(),
+                                    &format!("\x1b[31m{} failed: {}\x1b[0m", identity, error),
+                                );
+                            }
+                        }
+                        counters.set_label(&format!("Flashed: {}   Failed: {}", ok, failed));
+                        app::awake();
+                        app::redraw();
+                    },
+                )
+                .await;
+                result.set_label("Stopped");
+                result.set_color(Color::Background);
+                result.set_label_color(Color::Foreground);
+                btn.activate();
+                btn_stop.deactivate();
+                app::awake();
+                app::redraw();
+            });
+        });
+    }
+
+    pub fn run(mut self) {
+        if vault::Vault::exists() {
+            self.sender.send(Message::UnlockVault);
+        }
+        while self.app.wait() {
+            if let Some(msg) = self.receiver.recv() {
+                match msg {
+                    Message::IpChanged => {
+                        let value = self.ip_input.value().unwrap_or_default();
+                        let mut state = self.state.lock().unwrap();
+                        // Picking a recent device puts its label into the input, swap in the details
+                        match state.config.devices.iter().find(|d| d.label() == value).cloned() {
+                            Some(device) => {
+                                self.ip_input.set_value(&device.ip);
+                                self.port_input.set_value(&device.port.to_string());
+                                state.ip = device.ip;
+                                state.port = device.port.to_string();
+                                state.password = None;
+                            }
+                            None => state.ip = value,
+                        }
+                    }
+                    Message::RefreshRecentDevices => {
+                        let state = self.state.lock().unwrap();
+                        fill_recent_devices(&mut self.ip_input, &state.config);
+                    }
+                    Message::RenameDevice => {
+                        let device = {
+                            let state = self.state.lock().unwrap();
+                            let port: u16 = state.port.parse().unwrap_or(0);
+                            state.config.devices.iter().find(|d| d.ip == state.ip && d.port == port).cloned()
+                        };
+                        let device = match device {
+                            Some(device) => device,
+                            None => {
+                                let mut display = self.display.lock().unwrap();
+                                update_status(&mut display, "Error: Please identify the device first.");
+                                continue;
+                            }
+                        };
+                        let prompt = format!("Nickname for {}:{}:", device.ip, device.port);
+                        if let Some(nickname) = fltk::dialog::input_default(&prompt, &device.nickname) {
+                            let mut state = self.state.lock().unwrap();
+                            state.config.remember(config::Device {
+                                nickname: nickname.trim().to_string(),
+                                ..device
+                            });
+                            save_config(&state.config);
+                            fill_recent_devices(&mut self.ip_input, &state.config);
+                        }
+                    }
+                    Message::UnlockVault => {
+                        if let Some(vault) = unlock_vault() {
+                            self.state.lock().unwrap().vault = Some(vault);
+                            let mut display = self.display.lock().unwrap();
+                            update_status(&mut display, "Credential vault unlocked.");
+                        }
+                    }
+                    Message::ManageVault => {
+                        if !open_vault(&self.state) {
+                            continue;
+                        }
+                        let current = match &self.state.lock().unwrap().vault {
+                            Some(vault) => vault.data.fleet_passwords.clone(),
+                            None => continue,
+                        };
+                        if let Some(passwords) = edit_fleet_passwords(&current) {
+                            let result = match self.state.lock().unwrap().vault.as_mut() {
+                                Some(vault) => {
+                                    vault.data.fleet_passwords = passwords;
+                                    vault.save()
+                                }
+                                None => continue,
+                            };
+                            if let Err(e) = result {
+                                error!("error: {:?}", e);
+                                let mut display = self.display.lock().unwrap();
+                                update_status(&mut display, format!("Error: {}", e).as_str());
+                            }
+                        }
+                    }
+                    Message::ToggleRememberPasswords => {
+                        let remember = self
+                            .menu_btn
+                            .find_item("Remember passwords")
+                            .map(|item| item.value())
+                            .unwrap_or(false);
+                        // Passwords are remembered in the vault, so remembering needs one
+                        let remember = remember && open_vault(&self.state);
+                        let mut state = self.state.lock().unwrap();
+                        if let Some(mut item) = self.menu_btn.find_item("Remember passwords") {
+                            if !remember {
+                                item.clear();
+                            }
+                        }
+                        state.config.remember_passwords = remember;
+                        // Store the password of the current device right away
+                        if let Some(password) = state.password.clone().filter(|_| remember) {
+                            store_device_password(&mut state, &password);
+                        }
+                        save_config(&state.config);
+                    }
+                    Message::PortChanged => {
+                        self.state.lock().unwrap().port = self.port_input.value();
+                    }
+                    Message::RunScript(index) => {
+                        let (script, ip, port) = {
+                            let state = self.state.lock().unwrap();
+                            match (state.scripts.get(index), state.port.parse::<u16>()) {
+                                (Some(script), Ok(port)) => (script.clone(), state.ip.clone(), port),
+                                _ => continue,
+                            }
+                        };
+                        self.btn_detect.deactivate();
+                        self.btn_flash.deactivate();
+                        self.menu_btn.deactivate();
+
+                        let state_clone = self.state.clone();
+                        let display_clone = self.display.clone();
+                        let mut btn_detect_clone = self.btn_detect.clone();
+                        let mut btn_flash_clone = self.btn_flash.clone();
+                        let mut menu_btn_clone = self.menu_btn.clone();
+                        tokio::spawn(async move {
+                            let session = device_session(&mut state_clone.lock().unwrap(), &ip, port);
+                            update_status(&mut display_clone.lock().unwrap(), &format!("Running script {}...", script.name));
+
+                            // The log lines go to the history record as well
+                            let log = Arc::new(Mutex::new(String::new()));
+                            let (log_lines, display_log) = (log.clone(), display_clone.clone());
+                            let host = script::ScriptHost {
+                                log: Arc::new(move |msg: &str| {
+                                    let mut log = log_lines.lock().unwrap();
+                                    log.push_str(msg);
+                                    log.push('\n');
+                                    update_status(&mut display_log.lock().unwrap(), msg);
+                                }),
+                                // Dialogs have to run on the main thread, the script waits for the answer
+                                prompt: Arc::new(|message: &str| {
+                                    let (tx, rx) = std::sync::mpsc::channel();
+                                    let message = message.to_string();
+                                    app::awake_callback(move || {
+                                        let _ = tx.send(fltk::dialog::input_default(&message, ""));
+                                    });
+                                    rx.recv().ok().flatten()
+                                }),
+                            };
+                            let mut operation = history::Operation::new(&format!("script {}", script.name), &ip, port);
+                            let result = script::run(&script, session.clone(), host).await;
+                            operation.log(log.lock().unwrap().trim_end());
+                            operation.finish(result.as_ref().err());
+                            match result {
+                                Ok(()) => {
+                                    store_working_password(&mut state_clone.lock().unwrap(), &session.credentials());
+                                    update_status(
+                                        &mut display_clone.lock().unwrap(),
+                                        &format!("\x1b[32mScript {} finished.\x1b[0m", script.name),
+                                    );
+                                }
+                                Err(e) => {
+                                    error!("error: {:?}", e);
+                                    if flasher::is_auth_error(&e) {
+                                        state_clone.lock().unwrap().password = None;
+                                    }
+                                    update_status(&mut display_clone.lock().unwrap(), &format!("Error: {}", e));
+                                }
+                            }
+                            btn_detect_clone.activate();
+                            btn_flash_clone.activate();
+                            menu_btn_clone.activate();
+                        });
+                    }
+                    Message::RecipeChanged => {
+                        let mut state = self.state.lock().unwrap();
+                        if let Some(recipe) = state.recipes.get(self.recipe_choice.value().max(0) as usize) {
+                            self.recipe_choice.set_tooltip(&recipe.description);
+                            state.recipe = self.recipe_choice.value() as usize;
+                        }
+                    }
+                    Message::PromptPasswordAndRetry(action) => {
+                        // Handle password prompting in main thread
+                        if let Some(new_password) = prompt_for_password() {
+                            self.state.lock().unwrap().password = Some(new_password);
+                            // Retry the operation
+                            match action {
+                                RetryAction::DetectSoc => self.sender.send(Message::DetectSoc),
+                                RetryAction::Flash => self.sender.send(Message::Flash),
+                                RetryAction::ResetDevice => self.sender.send(Message::ResetDevice),
+                            }
+                        } else {
+                            let mut display = self.display.lock().unwrap();
+                            update_status(&mut display, "Authentication failed and no password provided.");
+                            self.btn_detect.activate();
+                            self.btn_flash.activate();
+                            self.menu_btn.activate();
+                        }
+                    }
+                    Message::DetectSoc => {
+                        let state = self.state.lock().unwrap();
+                        let mut display = self.display.lock().unwrap();
+                        let port: u16 = match state.port.parse() {
+                            Ok(v) => v,
+                            Err(e) => {
+                                error!("error: {:?}", e);
+                                update_status(&mut display, "Error: invalid port specified.");
+                                continue;
+                            }
+                        };
+                        self.btn_detect.deactivate();
+                        let state_clone = self.state.clone();
+                        let display_clone = self.display.clone();
+                        let mut btn_detect_clone = self.btn_detect.clone();
+                        let mut btn_flash_clone = self.btn_flash.clone();
+                        let mut menu_btn_clone = self.menu_btn.clone();
+                        let sender_clone = self.sender.clone();
+                        tokio::spawn(async move {
+                            let ip = state_clone.lock().unwrap().ip.clone();
+                            let session = device_session(&mut state_clone.lock().unwrap(), &ip, port);
+
+                            let mut operation = history::Operation::new("detect", &ip, port);
+                            let result = flasher::detect_soc(&session, |msg| {
+                                operation.log(msg);
+                                update_status(&mut display_clone.lock().unwrap(), msg);
+                            })
+                            .await;
+                            if let Ok(soc) = &result {
+                                operation.set_soc(soc);
+                            }
+                            operation.finish(result.as_ref().err());
+                            match result {
+                                Ok(soc) => {
+                                    {
+                                        let mut state = state_clone.lock().unwrap();
+                                        store_working_password(&mut state, &session.credentials());
+                                        state.soc = soc;
+                                        remember_device(&mut state);
+                                    }
+                                    sender_clone.send(Message::RefreshRecentDevices);
+                                    update_status(&mut display_clone.lock().unwrap(), "Done.");
+                                    btn_detect_clone.activate();
+                                    btn_flash_clone.activate();
+                                    menu_btn_clone.activate();
+                                }
+                                Err(e) if flasher::is_auth_error(&e) => {
+                                    // Clear failed password
+                                    state_clone.lock().unwrap().password = None;
+                                    update_status(
+                                        &mut display_clone.lock().unwrap(),
+                                        "Authentication failed. Please enter password when prompted.",
+                                    );
+                                    btn_detect_clone.activate();
+                                    btn_flash_clone.deactivate();
+                                    menu_btn_clone.deactivate();
+
+                                    // Send message to main thread to handle password input
+                                    app::awake();
+                                    sender_clone.send(Message::PromptPasswordAndRetry(RetryAction::DetectSoc));
+                                }
+                                Err(e) => {
+                                    error!("error: {:?}", e);
+                                    update_status(
+                                        &mut display_clone.lock().unwrap(),
+                                        format!("Error: {}", e).as_str(),
+                                    );
+                                    btn_detect_clone.activate();
+                                    btn_flash_clone.deactivate();
+                                    menu_btn_clone.deactivate();
+                                }
+                            }
+                        });
+                    }
+                    Message::Flash => {
+                        // The dialogs run without the state lock so running operations are not blocked
+                        let (recipe, pattern) = {
+                            let state = self.state.lock().unwrap();
+                            let recipe = selected_recipe(&state);
+                            let pattern = recipe.firmware_pattern(&state.soc);
+                            (recipe, pattern)
+                        };
+                        let path = match choose_file(&pattern) {
+                            Some(path) => path,
+                            None => continue,
+                        };
+                        // Optional post-flash step, ask for the password before anything starts
+                        let set_password_after = self
+                            .menu_btn
+                            .find_item("Set password after flash")
+                            .map(|item| item.value())
+                            .unwrap_or(false);
+                        let new_password = if set_password_after {
+                            match prompt_new_password() {
+                                Some(password) => Some(password),
+                                None => continue,
+                            }
+                        } else {
+                            None
+                        };
+                        let state = self.state.lock().unwrap();
+                        let mut display = self.display.lock().unwrap();
+                        let port: u16 = match state.port.parse() {
+                            Ok(v) => v,
+                            Err(e) => {
+                                error!("error: {:?}", e);
+                                update_status(&mut display, "Error: invalid port specified.");
+                                continue;
+                            }
+                        };
+                        self.btn_detect.deactivate();
+                        self.btn_flash.deactivate();
+                        self.menu_btn.deactivate();
+
+                        let state_clone = self.state.clone();
+                        let display_clone = self.display.clone();
+                        let progress_clone = self.progress.clone();
+                        let mut btn_detect_clone = self.btn_detect.clone();
+                        let mut btn_flash_clone = self.btn_flash.clone();
+                        let mut menu_btn_clone = self.menu_btn.clone();
+                        let sender_clone = self.sender.clone();
+                        progress_clone.lock().unwrap().reset();
+                        tokio::spawn(async move {
+                            let ip = state_clone.lock().unwrap().ip.clone();
+                            let session = device_session(&mut state_clone.lock().unwrap(), &ip, port);
+
+                            let mut operation = history::Operation::new("flash", &ip, port);
+                            operation.set_firmware(&path);
+                            match flasher::flash(&session, &recipe, &path, |msg| {
+                                operation.log(msg);
+                                update_status(&mut display_clone.lock().unwrap(), msg);
+                            }, |p| progress_clone.lock().unwrap().update(p))
+                            .await
+                            {
+                                Ok(unit) => {
+                                    operation.set_unit_before(&unit);
+                                    store_working_password(&mut state_clone.lock().unwrap(), &session.credentials());
+                                    // Recipes that do not reboot are done once their steps are
+                                    let reboot_result = if recipe.reboot {
+                                        update_status(&mut display_clone.lock().unwrap(),"\n\
+                                              \x1b[32mReview the log above to ensure everything went well.\n\
+                                              The last log line should be like '\x1b[0m\x1b[1mUnconditional reboot\x1b[0m\x1b[32m'.\n\
+                                              \x1b[1m\x1b[34mThe device is rebooting now, please wait until it comes back \
+                                              and do not disconnect power during this time.\x1b[0m"
+                                        );
+                                        flasher::wait_for_reboot(&session, &unit.soc, |msg| {
+                                            operation.log(msg);
+                                            update_status(&mut display_clone.lock().unwrap(), msg);
+                                        }, |p| progress_clone.lock().unwrap().update(p))
+                                        .await
+                                    } else {
+                                        Ok(unit.clone())
+                                    };
+                                    if let Ok(after) = &reboot_result {
+                                        operation.set_unit_after(after);
+                                    }
+                                    operation.finish(reboot_result.as_ref().err());
+                                    match reboot_result {
+                                        Ok(_) => {
+                                            store_working_password(&mut state_clone.lock().unwrap(), &session.credentials());
+                                            progress_clone.lock().unwrap().finish();
+                                            let done = if recipe.reboot {
+                                                "\x1b[32mThe device is back online, the firmware flash is completed.\x1b[0m"
+                                            } else {
+                                                "\x1b[32mThe firmware flash is completed.\x1b[0m"
+                                            };
+                                            update_status(&mut display_clone.lock().unwrap(), done);
+                                            if let Some(new_password) = new_password {
+                                                match password::set_password(&session, &new_password, |msg| {
+                                                    update_status(&mut display_clone.lock().unwrap(), msg);
+                                                })
+                                                .await
+                                                {
+                                                    Ok(_) => record_new_password(&mut state_clone.lock().unwrap(), &session.credentials()),
+                                                    Err(e) => {
+                                                        error!("error: {:?}", e);
+                                                        update_status(
+                                                            &mut display_clone.lock().unwrap(),
+                                                            format!("Error: setting the password failed: {}", e).as_str(),
+                                                        );
+                                                    }
+                                                }
+                                            }
+                                        }
+                                        Err(e) => {
+                                            error!("error: {:?}", e);
+                                            progress_clone.lock().unwrap().fail();
+                                            update_status(
+                                                &mut display_clone.lock().unwrap(),
+                                                format!("Error: verification failed: {}", e).as_str(),
+                                            );
+                                        }
+                                    }
+                                    btn_detect_clone.activate();
+                                    btn_flash_clone.activate();
+                                    menu_btn_clone.activate();
+                                }
+                                Err(e) if flasher::is_auth_error(&e) => {
+                                    operation.finish(Some(&e));
+                                    progress_clone.lock().unwrap().fail();
+                                    // Clear failed password
+                                    state_clone.lock().unwrap().password = None;
+                                    update_status(
+                                        &mut display_clone.lock().unwrap(),
+                                        "Authentication failed. Please enter password when prompted.",
+                                    );
+                                    btn_detect_clone.activate();
+                                    btn_flash_clone.activate();
+                                    menu_btn_clone.activate();
+
+                                    // Send message to main thread to handle password input
+                                    app::awake();
+                                    sender_clone.send(Message::PromptPasswordAndRetry(RetryAction::Flash));
+                                }
+                                Err(e) => {
+                                    error!("error: {:?}", e);
+                                    operation.finish(Some(&e));
+                                    progress_clone.lock().unwrap().fail();
+                                    update_status(
+                                        &mut display_clone.lock().unwrap(),
+                                        format!("Error: {}", e).as_str(),
+                                    );
+                                    btn_detect_clone.activate();
+                                    btn_flash_clone.activate();
+                                    menu_btn_clone.activate();
+                                }
+                            }
+                        });
+                    }
+                    Message::ResetDevice => {
+                        // Show confirmation dialog
+                        let choice = fltk::dialog::choice2_default(
+                            "Are you sure you want to reset the device?\n\nThis will clear all settings from the device and make it appear as newly flashed.\n\nThe device will need 2-3 minutes to completely initialize after reset and should not be disconnected from power during this time.",
+                            "Cancel",
+                            "Reset Device",
+                            ""
+                        );
+
+                        // If user chose "Cancel" (returns Some(0)) or closed dialog (returns None), don't proceed
+                        match choice {
+                            Some(1) => {}  // User chose "Reset Device", proceed
+                            _ => continue, // User chose "Cancel" or closed dialog, don't proceed
+                        }
+
+                        let state = self.state.lock().unwrap();
+                        let mut display = self.display.lock().unwrap();
+                        let port: u16 = match state.port.parse() {
+                            Ok(v) => v,
+                            Err(e) => {
+                                error!("error: {:?}", e);
+                                update_status(&mut display, "Error: invalid port specified.");
+                                continue;
+                            }
+                        };
+                        self.btn_detect.deactivate();
+                        self.btn_flash.deactivate();
+                        self.menu_btn.deactivate();
+
+                        let state_clone = self.state.clone();
+                        let display_clone = self.display.clone();
+                        let mut btn_detect_clone = self.btn_detect.clone();
+                        let mut btn_flash_clone = self.btn_flash.clone();
+                        let mut menu_btn_clone = self.menu_btn.clone();
+                        let sender_clone = self.sender.clone();
+                        tokio::spawn(async move {
+                            let ip = state_clone.lock().unwrap().ip.clone();
+                            let session = device_session(&mut state_clone.lock().unwrap(), &ip, port);
+
+                            let mut operation = history::Operation::new("reset", &ip, port);
+                            let result = flasher::reset_device(&session, |msg| {
+                                operation.log(msg);
+                                update_status(&mut display_clone.lock().unwrap(), msg);
+                            })
+                            .await;
+                            operation.finish(result.as_ref().err());
+                            match result {
+                                Ok(_) => {
+                                    store_working_password(&mut state_clone.lock().unwrap(), &session.credentials());
+                                    update_status(&mut display_clone.lock().unwrap(),"\n\
+                                          \x1b[32mReview the log above to ensure everything went well.\n\
+                                          The last log line should be like '\x1b[0m\x1b[1mUnconditional reboot\x1b[0m\x1b[32m'.\n\
+                                          If the log shows no errors, the reset is completed.\n\
+                                          \x1b[1m\x1b[34mPlease wait 2-3 minutes for the device to completely initialize \
+                                          and do not disconnect power during this time.\x1b[0m"
+                                    );
+                                    btn_detect_clone.activate();
+                                    btn_flash_clone.activate();
+                                    menu_btn_clone.activate();
+                                }
+                                Err(e) if flasher::is_auth_error(&e) => {
+                                    // Clear failed password
+                                    state_clone.lock().unwrap().password = None;
+                                    update_status(
+                                        &mut display_clone.lock().unwrap(),
+                                        "Authentication failed. Please enter password when prompted.",
+                                    );
+                                    btn_detect_clone.activate();
+                                    btn_flash_clone.activate();
+                                    menu_btn_clone.activate();
+
+                                    // Send message to main thread to handle password input
+                                    app::awake();
+                                    sender_clone.send(Message::PromptPasswordAndRetry(RetryAction::ResetDevice));
+                                }
+                                Err(e) => {
+                                    error!("error: {:?}", e);
+                                    update_status(
+                                        &mut display_clone.lock().unwrap(),
+                                        format!("Error: {}", e).as_str(),
+                                    );
+                                    btn_detect_clone.activate();
+                                    btn_flash_clone.activate();
+                                    menu_btn_clone.activate();
+                                }
+                            }
+                        });
+                    }
+                    Message::SetDevicePassword => {
+                        let new_password = match prompt_new_password() {
+                            Some(password) => password,
+                            None => continue,
+                        };
+                        let state = self.state.lock().unwrap();
+                        let mut display = self.display.lock().unwrap();
+                        let port: u16 = match state.port.parse() {
+                            Ok(v) => v,
+                            Err(e) => {
+                                error!("error: {:?}", e);
+                                update_status(&mut display, "Error: invalid port specified.");
+                                continue;
+                            }
+                        };
+                        self.btn_detect.deactivate();
+                        self.btn_flash.deactivate();
+                        self.menu_btn.deactivate();
+
+                        let state_clone = self.state.clone();
+                        let display_clone = self.display.clone();
+                        let mut btn_detect_clone = self.btn_detect.clone();
+                        let mut btn_flash_clone = self.btn_flash.clone();
+                        let mut menu_btn_clone = self.menu_btn.clone();
+                        tokio::spawn(async move {
+                            let ip = state_clone.lock().unwrap().ip.clone();
+                            let session = device_session(&mut state_clone.lock().unwrap(), &ip, port);
+
+                            match password::set_password(&session, &new_password, |msg| {
+                                update_status(&mut display_clone.lock().unwrap(), msg);
+                            })
+                            .await
+                            {
+                                Ok(_) => {
+                                    record_new_password(&mut state_clone.lock().unwrap(), &session.credentials());
+                                }
+                                Err(e) => {
+                                    error!("error: {:?}", e);
+                                    update_status(
+                                        &mut display_clone.lock().unwrap(),
+                                        format!("Error: {}", e).as_str(),
+                                    );
+                                }
+                            }
+                            btn_detect_clone.activate();
+                            btn_flash_clone.activate();
+                            menu_btn_clone.activate();
+                        });
+                    }
+                    Message::ScanNetwork => {
+                        self.show_scan_window();
+                    }
+                    Message::BatchFlash => {
+                        self.show_batch_window();
+                    }
+                    Message::WatchMode => {
+                        self.show_watch_window();
+                    }
+                    Message::History => {
+                        self.show_history_window();
+                    }
+                    Message::DeviceLogs => {
+                        self.show_logs_window();
+                    }
+                    Message::SaveLog => {
+                        let path = match choose_save_file("Text\t*.txt\nHTML\t*.{html,htm}", "ruby-flasher-log.txt") {
+                            Some(path) => path,
+                            None => continue,
+                        };
+                        let as_html = path
+                            .extension()
+                            .map(|ext| ext.eq_ignore_ascii_case("html") || ext.eq_ignore_ascii_case("htm"))
+                            .unwrap_or(false);
+                        let display = self.display.lock().unwrap();
+                        let result = if as_html { display.save_html(&path) } else { display.save_text(&path) };
+                        if let Err(e) = result {
+                            error!("error: {:?}", e);
+                            fltk::dialog::alert_default(&format!("Saving the log failed: {}", e));
+                        }
+                    }
+                    Message::EventLog => {
+                        let current = self.state.lock().unwrap().config.event_log.clone();
+                        if let Some(path) = &current {
+                            let choice = fltk::dialog::choice2_default(
+                                &format!("Events are appended to\n{}\nas JSON lines.", path.display()),
+                                "Keep",
+                                "Turn off",
+                                "Change...",
+                            );
+                            match choice {
+                                Some(1) => {
+                                    events::set_sink(None);
+                                    let mut state = self.state.lock().unwrap();
+                                    state.config.event_log = None;
+                                    save_config(&state.config);
+                                    continue;
+                                }
+                                Some(2) => {}
+                                _ => continue,
+                            }
+                        }
+                        let path = match choose_save_file("JSON lines\t*.jsonl", "ruby-flasher-events.jsonl") {
+                            Some(path) => path,
+                            None => continue,
+                        };
+                        if let Err(e) = open_event_log(&path) {
+                            error!("error: {:?}", e);
+                            fltk::dialog::alert_default(&format!("Opening the event log failed: {:#}", e));
+                            continue;
+                        }
+                        update_status(&mut self.display.lock().unwrap(), &format!("Writing events to {}", path.display()));
+                        let mut state = self.state.lock().unwrap();
+                        state.config.event_log = Some(path);
+                        save_config(&state.config);
+                    }
+                    Message::SupportBundle => {
+                        let path = match choose_save_file("*.zip", &support::default_file_name()) {
+                            Some(path) => path,
+                            None => continue,
+                        };
+                        let (ip, port) = {
+                            let state = self.state.lock().unwrap();
+                            (state.ip.clone(), state.port.parse::<u16>().unwrap_or(22))
+                        };
+                        let state_clone = self.state.clone();
+                        let display_clone = self.display.clone();
+                        tokio::spawn(async move {
+                            let mut bundle = support::Bundle {
+                                session_log: String::new(),
+                                device: None,
+                                device_files: Vec::new(),
+                                device_error: None,
+                            };
+                            // Without a device the bundle still carries the host info and the log
+                            if ip.is_empty() {
+                                bundle.device_error = Some("no device address entered".to_string());
+                            } else {
+                                bundle.device = Some(format!("{}:{}", ip, port));
+                                let session = device_session(&mut state_clone.lock().unwrap(), &ip, port);
+                                update_status(&mut display_clone.lock().unwrap(), "Collecting the device snapshot for the support bundle...");
+                                match support::device_snapshot(&session, |msg| {
+                                    update_status(&mut display_clone.lock().unwrap(), msg);
+                                })
+                                .await
+                                {
+                                    Ok(files) => {
+                                        store_working_password(&mut state_clone.lock().unwrap(), &session.credentials());
+                                        bundle.device_files = files;
+                                    }
+                                    Err(e) => {
+                                        error!("error: {:?}", e);
+                                        update_status(
+                                            &mut display_clone.lock().unwrap(),
+                                            format!("Error: device snapshot failed: {}", e).as_str(),
+                                        );
+                                        bundle.device_error = Some(e.to_string());
+                                    }
+                                }
+                            }
+                            bundle.session_log = display_clone.lock().unwrap().text_buf.text();
+                            let msg = match support::write(&bundle, &path) {
+                                Ok(_) => format!("\x1b[32mSupport bundle saved to {}\x1b[0m", path.display()),
+                                Err(e) => {
+                                    error!("error: {:?}", e);
+                                    format!("Error: saving the support bundle failed: {}", e)
+                                }
+                            };
+                            update_status(&mut display_clone.lock().unwrap(), &msg);
+                        });
+                    }
+                    Message::EnterManualMode => {
+                        let state = self.state.lock().unwrap();
+                        if !state.ip.is_empty() {
+                            drop(state); // Release the lock before acquiring it again
+                            self.btn_detect.deactivate();
+                            self.btn_flash.deactivate();
+                            self.menu_btn.deactivate();
+                            // Show the manual flex and the console instead of the log
+                            self.manual_flex.show();
+                            self.display.lock().unwrap().disp.hide();
+                            self.terminal.widget.show();
+                            self.container.layout();
+                            // Use a safe focus operation instead of unwrap
+                            if let Err(e) = self.manual_input.take_focus() {
+                                error!("Failed to take focus: {:?}", e);
+                            }
+                            app::redraw();
+
+                            let port: u16 = self.state.lock().unwrap().port.parse().unwrap_or(22);
+                            let (cols, rows) = self.terminal.reset();
+                            let state_clone = self.state.clone();
+                            let display_clone = self.display.clone();
+                            let mut terminal_clone = self.terminal.clone();
+                            let manual_flex_clone = self.manual_flex.clone();
+                            let sender_clone = self.sender;
+                            tokio::spawn(async move {
+                                let ip = state_clone.lock().unwrap().ip.clone();
+                                let session = device_session(&mut state_clone.lock().unwrap(), &ip, port);
+                                update_status(
+                                    &mut display_clone.lock().unwrap(),
+                                    format!("Opening a shell on {}:{}...", ip, port).as_str(),
+                                );
+                                let display_output = display_clone.clone();
+                                let state_output = state_clone.clone();
+                                let result = console::open(session.clone(), cols as u32, rows as u32, move |event| match event {
+                                    shell::ShellEvent::Output(text) => {
+                                        let responses = terminal_clone.feed(&text);
+                                        if !responses.is_empty() {
+                                            if let Some(shell) = &state_output.lock().unwrap().shell {
+                                                shell.send(&responses);
+                                            }
+                                        }
+                                    }
+                                    shell::ShellEvent::Closed(exit_status) => {
+                                        let msg = match exit_status {
+                                            Some(code) => format!("\nShell closed (exit status {}).", code),
+                                            None => "\nShell closed.".to_string(),
+                                        };
+                                        update_status(&mut display_output.lock().unwrap(), &msg);
+                                        sender_clone.send(Message::ExitManualMode);
+                                    }
+                                })
+                                .await;
+                                match result {
+                                    // Manual mode may have been left while connecting
+                                    Ok(shell) if !manual_flex_clone.visible() => shell.close(),
+                                    Ok(shell) => {
+                                        let mut state = state_clone.lock().unwrap();
+                                        store_working_password(&mut state, &session.credentials());
+                                        state.shell = Some(shell);
+                                    }
+                                    Err(e) if flasher::is_auth_error(&e) => {
+                                        // Clear failed password and show message
+                                        state_clone.lock().unwrap().password = None;
+                                        update_status(
+                                            &mut display_clone.lock().unwrap(),
+                                            "Authentication failed. Please set password in a regular operation first.",
+                                        );
+                                        sender_clone.send(Message::ExitManualMode);
+                                    }
+                                    Err(e) => {
+                                        error!("error: {:?}", e);
+                                        update_status(
+                                            &mut display_clone.lock().unwrap(),
+                                            format!("Error: {}", e).as_str(),
+                                        );
+                                        sender_clone.send(Message::ExitManualMode);
+                                    }
+                                }
+                            });
+                        } else {
+                            drop(state); // Release the lock
+                            let mut display = self.display.lock().unwrap();
+                            update_status(&mut display, "Error: Please enter an IP address first.");
+                        }
+                    }
+                    Message::ExitManualMode => {
+                        if let Some(shell) = self.state.lock().unwrap().shell.take() {
+                            shell.close();
+                        }
+                        // Also sent when the shell closes, which may be after the user left manual mode
+                        if !self.manual_flex.visible() {
+                            continue;
+                        }
+                        // Hide the manual flex and bring the log back
+                        self.manual_flex.hide();
+                        self.terminal.widget.hide();
+                        self.display.lock().unwrap().disp.show();
+                        self.container.layout();
+                        self.btn_detect.activate();
+                        self.btn_flash.activate();
+                        self.menu_btn.activate();
+                        self.manual_input.set_value("");
+                        app::redraw();
+                    }
+                    Message::StopManualCommand => {
+                        let mut state = self.state.lock().unwrap();
+                        let shell = match &state.shell {
+                            Some(shell) => shell.clone(),
+                            None => continue,
+                        };
+                        let escalate = state.last_stop.map(|t| t.elapsed().as_secs() < 3).unwrap_or(false);
+                        if escalate {
+                            state.last_stop = None;
+                            drop(state);
+                            update_status(&mut self.display.lock().unwrap(), "Terminating the running command...");
+                            shell.terminate();
+                        } else {
+                            state.last_stop = Some(Instant::now());
+                            shell.interrupt();
+                        }
+                    }
+                    Message::CompleteCommand => {
+                        let shell = match &self.state.lock().unwrap().shell {
+                            Some(shell) => shell.clone(),
+                            None => continue,
+                        };
+                        let line = self.manual_input.value();
+                        let state_clone = self.state.clone();
+                        let sender_clone = self.sender;
+                        tokio::spawn(async move {
+                            match console::complete(&shell, &line).await {
+                                Ok(candidates) => {
+                                    state_clone.lock().unwrap().completions = Some((line, candidates));
+                                    sender_clone.send(Message::ShowCompletions);
+                                }
+                                Err(e) => error!("completion failed: {:?}", e),
+                            }
+                        });
+                    }
+                    Message::ShowCompletions => {
+                        let (line, candidates) = match self.state.lock().unwrap().completions.take() {
+                            Some(completions) => completions,
+                            None => continue,
+                        };
+                        // The user kept typing while the device was asked
+                        if self.manual_input.value() != line || candidates.is_empty() {
+                            if candidates.is_empty() {
+                                fltk::dialog::beep(fltk::dialog::BeepType::Default);
+                            }
+                            continue;
+                        }
+                        let word = line.rsplit(char::is_whitespace).next().unwrap_or("");
+                        let prefix = console::common_prefix(&candidates);
+                        let completed = if candidates.len() == 1 {
+                            Some(console::replace_last_word(&line, &candidates[0], true))
+                        } else if prefix.len() > word.len() {
+                            Some(console::replace_last_word(&line, &prefix, false))
+                        } else {
+                            // Nothing in common, let the user pick
+                            let mut menu = MenuButton::new(
+                                self.manual_input.x(),
+                                self.manual_input.y(),
+                                self.manual_input.w(),
+                                self.manual_input.h(),
+                                None,
+                            );
+                            for candidate in &candidates {
+                                menu.add_choice(&escape_menu_label(candidate));
+                            }
+                            let picked = menu.popup().map(|_| menu.value());
+                            MenuButton::delete(menu);
+                            picked
+                                .and_then(|index| candidates.get(index as usize))
+                                .map(|candidate| console::replace_last_word(&line, candidate, true))
+                        };
+                        if let Some(completed) = completed {
+                            self.manual_input.set_value(&completed);
+                            self.manual_input.set_position(completed.len() as i32).ok();
+                        }
+                        let _ = self.manual_input.take_focus();
+                    }
+                    Message::ExecuteManualCommand => {
+                        // Only allow command execution if manual_flex is visible (manual mode)
+                        if !self.manual_flex.visible() {
+                            continue;
+                        }
+                        let state = self.state.lock().unwrap();
+                        let shell = match &state.shell {
+                            Some(shell) => shell,
+                            None => {
+                                let mut display = self.display.lock().unwrap();
+                                update_status(&mut display, "The shell is not connected yet.");
+                                continue;
+                            }
+                        };
+                        // An empty line is sent too, prompts on the device may be waiting for it.
+                        // Only commands go into the history, not answers to a password prompt.
+                        let state_clone = self.state.clone();
+                        shell.send_line(&self.manual_input.value(), move |line| {
+                            state_clone.lock().unwrap().history.push(line);
+                        });
+                        drop(state);
+
+                        // Clear the input for next command
+                        self.manual_input.set_value("");
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub(crate) fn run() {
+    let a = RubyFlasher::new();
+    a.run();
+}
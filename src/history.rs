@@ -0,0 +1,208 @@
+use anyhow::{Context, Result};
+use log::error;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::{self, OpenOptions};
+use std::io::{BufRead, BufReader};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use crate::config_dir;
+use crate::events::{self, Event};
+use crate::flasher::UnitInfo;
+
+const HISTORY_FILE: &str = "history.jsonl";
+
+// One line of the history file
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct HistoryRecord {
+    pub timestamp: String,
+    pub operation: String,
+    pub host: String,
+    pub port: u16,
+    #[serde(default)]
+    pub identity: Option<String>,
+    #[serde(default)]
+    pub soc: Option<String>,
+    #[serde(default)]
+    pub firmware: Option<String>,
+    #[serde(default)]
+    pub firmware_sha256: Option<String>,
+    #[serde(default)]
+    pub previous_version: Option<String>,
+    #[serde(default)]
+    pub new_version: Option<String>,
+    pub duration_secs: f64,
+    pub success: bool,
+    #[serde(default)]
+    pub error: Option<String>,
+    #[serde(default)]
+    pub log: String,
+}
+
+fn path() -> Option<PathBuf> {
+    config_dir().map(|dir| dir.join(HISTORY_FILE))
+}
+
+fn non_empty(value: &str) -> Option<String> {
+    Some(value.to_string()).filter(|v| !v.is_empty())
+}
+
+pub(crate) fn sha256_file(path: &Path) -> Result<String> {
+    let mut file = fs::File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+// Collects the details of one detect/flash/reset while it runs and appends it to the history when done
+pub(crate) struct Operation {
+    record: HistoryRecord,
+    started: Instant,
+}
+
+impl Operation {
+    pub fn new(operation: &str, host: &str, port: u16) -> Self {
+        Self {
+            record: HistoryRecord {
+                timestamp: chrono::Local::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, false),
+                operation: operation.to_string(),
+                host: host.to_string(),
+                port,
+                ..Default::default()
+            },
+            started: Instant::now(),
+        }
+    }
+
+    pub fn log(&mut self, line: &str) {
+        self.record.log.push_str(line);
+        self.record.log.push('\n');
+    }
+
+    pub fn set_soc(&mut self, soc: &str) {
+        self.record.soc = non_empty(soc);
+    }
+
+    pub fn set_firmware(&mut self, firmware: &str) {
+        self.record.firmware = Some(firmware.to_string());
+        match sha256_file(Path::new(firmware)) {
+            Ok(hash) => self.record.firmware_sha256 = Some(hash),
+            Err(e) => error!("Failed to hash {}: {:?}", firmware, e),
+        }
+    }
+
+    // The unit as it was before the operation
+    pub fn set_unit_before(&mut self, unit: &UnitInfo) {
+        self.record.identity = non_empty(&unit.identity);
+        self.record.soc = non_empty(&unit.soc);
+        self.record.previous_version = non_empty(&unit.version);
+    }
+
+    pub fn set_unit_after(&mut self, unit: &UnitInfo) {
+        if self.record.identity.is_none() {
+            self.record.identity = non_empty(&unit.identity);
+        }
+        self.record.new_version = non_empty(&unit.version);
+    }
+
+    pub fn finish(mut self, error: Option<&anyhow::Error>) {
+        self.record.duration_secs = self.started.elapsed().as_secs_f64();
+        self.record.success = error.is_none();
+        self.record.error = error.map(|e| format!("{:#}", e));
+        let record = &self.record;
+        events::emit(&record.host, record.port, Event::Result {
+            operation: &record.operation,
+            ok: record.success,
+            error: record.error.as_deref(),
+            soc: record.soc.as_deref(),
+            firmware: record.firmware.as_deref(),
+            version: record.new_version.as_deref().or(record.previous_version.as_deref()),
+            duration_secs: record.duration_secs,
+        });
+        if let Err(e) = append(&self.record) {
+            error!("Failed to write history: {:?}", e);
+        }
+    }
+}
+
+pub(crate) fn append(record: &HistoryRecord) -> Result<()> {
+    let path = path().context("No config directory on this platform")?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+    writeln!(file, "{}", serde_json::to_string(record)?)?;
+    Ok(())
+}
+
+// Every record, oldest first. Lines that do not parse are skipped.
+pub(crate) fn load() -> Vec<HistoryRecord> {
+    let file = match path().and_then(|path| fs::File::open(path).ok()) {
+        Some(file) => file,
+        None => return Vec::new(),
+    };
+    BufReader::new(file)
+        .lines()
+        .map_while(|line| line.ok())
+        .filter_map(|line| serde_json::from_str(&line).ok())
+        .collect()
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+pub(crate) fn export_csv(records: &[HistoryRecord], path: &Path) -> Result<()> {
+    let mut out = String::from(
+        "timestamp,operation,host,port,identity,soc,firmware,firmware_sha256,previous_version,new_version,duration_secs,result,error,log\n",
+    );
+    for r in records {
+        let fields = [
+            r.timestamp.clone(),
+            r.operation.clone(),
+            r.host.clone(),
+            r.port.to_string(),
+            r.identity.clone().unwrap_or_default(),
+            r.soc.clone().unwrap_or_default(),
+            r.firmware.clone().unwrap_or_default(),
+            r.firmware_sha256.clone().unwrap_or_default(),
+            r.previous_version.clone().unwrap_or_default(),
+            r.new_version.clone().unwrap_or_default(),
+            format!("{:.1}", r.duration_secs),
+            if r.success { "ok" } else { "failed" }.to_string(),
+            r.error.clone().unwrap_or_default(),
+            r.log.clone(),
+        ];
+        out.push_str(&fields.iter().map(|f| csv_field(f)).collect::<Vec<_>>().join(","));
+        out.push('\n');
+    }
+    fs::write(path, out).with_context(|| format!("Failed to write {}", path.display()))?;
+    Ok(())
+}
+
+pub(crate) fn export_jsonl(records: &[HistoryRecord], path: &Path) -> Result<()> {
+    let mut out = String::new();
+    for r in records {
+        out.push_str(&serde_json::to_string(r)?);
+        out.push('\n');
+    }
+    fs::write(path, out).with_context(|| format!("Failed to write {}", path.display()))?;
+    Ok(())
+}
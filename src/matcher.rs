@@ -0,0 +1,165 @@
+use anyhow::{Context, Result};
+use regex::Regex;
+use std::collections::BTreeMap;
+
+// What happens when a matcher finds its pattern in a line of output
+#[derive(Debug, Clone)]
+pub(crate) enum MatchAction {
+    // The command did what it should, the channel is closed without waiting for it to exit.
+    // For commands that take the connection down, like sysupgrade.
+    Complete,
+    // The command failed, the line becomes the error
+    Fail,
+    // The line is reported as a warning, the command keeps running
+    Warn,
+    // The first group of the pattern, or the whole match, is kept under this name
+    Capture(String),
+}
+
+// A pattern watched for in the output of one command
+#[derive(Debug, Clone)]
+pub(crate) struct OutputMatcher {
+    regex: Regex,
+    action: MatchAction,
+}
+
+impl OutputMatcher {
+    pub fn new(pattern: &str, action: MatchAction) -> Result<Self> {
+        let regex = Regex::new(pattern).with_context(|| format!("Invalid output pattern '{}'", pattern))?;
+        Ok(OutputMatcher { regex, action })
+    }
+
+    pub fn complete(pattern: &str) -> Result<Self> {
+        Self::new(pattern, MatchAction::Complete)
+    }
+
+    pub fn fail(pattern: &str) -> Result<Self> {
+        Self::new(pattern, MatchAction::Fail)
+    }
+
+    pub fn warn(pattern: &str) -> Result<Self> {
+        Self::new(pattern, MatchAction::Warn)
+    }
+
+    pub fn capture(name: &str, pattern: &str) -> Result<Self> {
+        Self::new(pattern, MatchAction::Capture(name.to_string()))
+    }
+}
+
+// What the matchers found in the output of a command
+#[derive(Debug, Clone, Default)]
+pub(crate) struct MatchState {
+    // A completion pattern was seen
+    pub completed: bool,
+    // The line that matched a failure pattern
+    pub failure: Option<String>,
+    pub warnings: Vec<String>,
+    pub captures: BTreeMap<String, String>,
+}
+
+impl MatchState {
+    // Run one line of output through the matchers. Returns true once the command
+    // is decided and its channel should be closed.
+    pub fn feed<F>(&mut self, matchers: &[OutputMatcher], line: &str, mut status_update: F) -> bool
+    where F: FnMut(&str) {
+        let line = line.trim();
+        if line.is_empty() {
+            return false;
+        }
+        for matcher in matchers {
+            let captures = match matcher.regex.captures(line) {
+                Some(captures) => captures,
+                None => continue,
+            };
+            match &matcher.action {
+                MatchAction::Complete => self.completed = true,
+                MatchAction::Fail => {
+                    if self.failure.is_none() {
+                        self.failure = Some(line.to_string());
+                    }
+                }
+                MatchAction::Warn => {
+                    status_update(&format!("warning: {}", line));
+                    self.warnings.push(line.to_string());
+                }
+                MatchAction::Capture(name) => {
+                    let value = captures.get(1).or_else(|| captures.get(0)).map(|m| m.as_str()).unwrap_or_default();
+                    self.captures.entry(name.clone()).or_insert_with(|| value.to_string());
+                }
+            }
+        }
+        self.completed || self.failure.is_some()
+    }
+}
+
+// Cuts a stream of output into whole lines, a partial line waits for the rest
+#[derive(Debug, Default)]
+pub(crate) struct LineSplitter {
+    partial: String,
+}
+
+impl LineSplitter {
+    pub fn push(&mut self, text: &str) -> Vec<String> {
+        self.partial.push_str(text);
+        let mut lines = Vec::new();
+        while let Some(pos) = self.partial.find('\n') {
+            let line: String = self.partial.drain(..=pos).collect();
+            lines.push(line.trim_end_matches(['\r', '\n']).to_string());
+        }
+        lines
+    }
+
+    // Whatever is left when the stream ends
+    pub fn finish(&mut self) -> Option<String> {
+        Some(std::mem::take(&mut self.partial)).filter(|line| !line.is_empty())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decides_on_completion_and_failure() {
+        let matchers = [OutputMatcher::complete("Unconditional reboot").unwrap(), OutputMatcher::fail("^Error").unwrap()];
+        let mut state = MatchState::default();
+        assert!(!state.feed(&matchers, "Writing flash...", |_| {}));
+        assert!(state.feed(&matchers, "  Unconditional reboot\r", |_| {}));
+        assert!(state.completed);
+
+        let mut state = MatchState::default();
+        assert!(state.feed(&matchers, "Error: image too big", |_| {}));
+        state.feed(&matchers, "Error: second", |_| {});
+        assert_eq!(state.failure.as_deref(), Some("Error: image too big"));
+    }
+
+    #[test]
+    fn keeps_warnings_and_first_captures() {
+        let matchers = [
+            OutputMatcher::warn("space is low").unwrap(),
+            OutputMatcher::capture("build_id", r#"^BUILD_ID="?([^"]*)"?$"#).unwrap(),
+        ];
+        let mut state = MatchState::default();
+        let mut reported = Vec::new();
+        assert!(!state.feed(&matchers, "overlay space is low", |msg| reported.push(msg.to_string())));
+        state.feed(&matchers, "BUILD_ID=\"ruby-11.2\"", |_| {});
+        state.feed(&matchers, "BUILD_ID=other", |_| {});
+        assert_eq!(state.warnings, ["overlay space is low"]);
+        assert_eq!(reported, ["warning: overlay space is low"]);
+        assert_eq!(state.captures["build_id"], "ruby-11.2");
+    }
+
+    #[test]
+    fn splits_lines_across_chunks() {
+        let mut splitter = LineSplitter::default();
+        assert!(splitter.push("first li").is_empty());
+        assert_eq!(splitter.push("ne\r\nsecond\nthi"), ["first line", "second"]);
+        assert_eq!(splitter.finish().as_deref(), Some("thi"));
+        assert_eq!(splitter.finish(), None);
+    }
+
+    #[test]
+    fn rejects_invalid_patterns() {
+        assert!(OutputMatcher::fail("(unclosed").is_err());
+    }
+}
@@ -0,0 +1,42 @@
+use anyhow::{Error, Result};
+use log::info;
+use russh::Disconnect;
+
+use crate::flasher::{self, AuthError, CommandOutput, IDLE_TIMEOUT};
+use crate::resolve::resolve;
+use crate::session::DeviceSession;
+
+// Like run_command, but feeds `input` to the command's stdin (kept out of the log)
+async fn run_command_with_input<F>(session: &DeviceSession, command: &str, input: &[u8], status_update: F) -> Result<CommandOutput> where F: FnMut(&str) {
+    flasher::exec_command(session, command, Some(input), &[], Some(IDLE_TIMEOUT), status_update).await?.check(command, false)
+}
+
+// Change the root password, then log in again with it to make sure it took
+pub(crate) async fn set_password<F>(session: &DeviceSession, new_password: &str, mut status_update: F) -> Result<(), Error> where F: FnMut(&str) {
+    if new_password.is_empty() || new_password.contains(['\n', '\r', '\0']) {
+        return Err(anyhow::anyhow!("The new password must not be empty or contain line breaks"));
+    }
+    status_update(&format!("Connecting to {}:{}...", session.host(), session.port()));
+    session.handle().await?; // This can return auth errors
+    status_update("Changing the root password...");
+    // The password goes through stdin so it never shows up in the log or the process list
+    let input = format!("root:{}\n", new_password);
+    if let Err(e) = run_command_with_input(session, "chpasswd", input.as_bytes(), &mut status_update).await {
+        info!("chpasswd failed, falling back to passwd: {}", e);
+        let input = format!("{}\n{}\n", new_password, new_password);
+        run_command_with_input(session, "passwd root", input.as_bytes(), &mut status_update).await?;
+    }
+
+    // A separate login, the shared connection stays as it is
+    status_update("Verifying the new password...");
+    let addrs = resolve(session.host(), session.port()).await?;
+    let mut verify = flasher::open_session(&addrs).await?;
+    if !flasher::authenticate(&mut verify, new_password).await? {
+        return Err(AuthError::new("the new password was not accepted by the device").into());
+    }
+    verify.disconnect(Disconnect::ByApplication, "", "en").await?;
+    session.set_working(new_password);
+    status_update("Password changed successfully.");
+    Ok(())
+}
+
@@ -0,0 +1,276 @@
+use anyhow::{Context, Result};
+use log::{error, info};
+use regex::Regex;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use crate::config_dir;
+use crate::flasher::FlashPhase;
+use crate::matcher::OutputMatcher;
+use crate::remote::quote;
+use crate::Asset;
+
+// Shipped with the app, a user recipe with the same name replaces it
+const BUILTIN_RECIPE: &str = "recipes/ruby.toml";
+// User recipes, *.toml under the config folder
+const RECIPE_DIR: &str = "recipes";
+
+// A flash workflow: which firmware files fit a device and the steps that install one
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Recipe {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    // File name pattern with * and ?, {soc} is filled in
+    pub firmware: String,
+    // The device reboots after the last step and is verified once it is back
+    #[serde(default)]
+    pub reboot: bool,
+    #[serde(rename = "step")]
+    pub steps: Vec<Step>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Step {
+    pub name: String,
+    // Stage shown in the progress stepper while the step runs
+    pub phase: Option<FlashPhase>,
+    // A command to run, or where to upload the firmware file. Exactly one is set.
+    pub run: Option<String>,
+    pub upload: Option<String>,
+    // Variable name to a pattern its value must match for the step to run
+    #[serde(default)]
+    pub when: BTreeMap<String, String>,
+    // Output patterns, see matcher.rs
+    #[serde(default)]
+    pub complete: Vec<String>,
+    #[serde(default)]
+    pub fail: Vec<String>,
+    #[serde(default)]
+    pub warn: Vec<String>,
+    // Variable name to a pattern, its first group becomes the value for later steps
+    #[serde(default)]
+    pub capture: BTreeMap<String, String>,
+}
+
+pub(crate) enum StepAction<'a> {
+    Run(&'a str),
+    Upload(&'a str),
+}
+
+impl Step {
+    pub fn action(&self) -> StepAction<'_> {
+        match (&self.run, &self.upload) {
+            (Some(command), _) => StepAction::Run(command),
+            (None, Some(destination)) => StepAction::Upload(destination),
+            // Ruled out by validate()
+            (None, None) => StepAction::Run("true"),
+        }
+    }
+
+    pub fn matchers(&self) -> Result<Vec<OutputMatcher>> {
+        let mut matchers = Vec::new();
+        for pattern in &self.complete {
+            matchers.push(OutputMatcher::complete(pattern)?);
+        }
+        for pattern in &self.fail {
+            matchers.push(OutputMatcher::fail(pattern)?);
+        }
+        for pattern in &self.warn {
+            matchers.push(OutputMatcher::warn(pattern)?);
+        }
+        for (name, pattern) in &self.capture {
+            matchers.push(OutputMatcher::capture(name, pattern)?);
+        }
+        Ok(matchers)
+    }
+
+    // Whether the conditions hold, an unknown variable counts as not matching
+    pub fn applies(&self, vars: &BTreeMap<String, String>) -> Result<bool> {
+        for (name, pattern) in &self.when {
+            let regex = Regex::new(pattern).with_context(|| format!("Invalid condition pattern '{}'", pattern))?;
+            if !vars.get(name).map(|value| regex.is_match(value)).unwrap_or(false) {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.run.is_some() == self.upload.is_some() {
+            return Err(anyhow::anyhow!("step '{}' needs either 'run' or 'upload'", self.name));
+        }
+        self.matchers()?;
+        self.applies(&BTreeMap::new())?;
+        Ok(())
+    }
+}
+
+impl Recipe {
+    pub fn parse(text: &str) -> Result<Recipe> {
+        let recipe: Recipe = toml::from_str(text)?;
+        if recipe.steps.is_empty() {
+            return Err(anyhow::anyhow!("recipe '{}' has no steps", recipe.name));
+        }
+        for step in &recipe.steps {
+            step.validate().with_context(|| format!("recipe '{}'", recipe.name))?;
+        }
+        Ok(recipe)
+    }
+
+    pub fn builtin() -> Recipe {
+        let file = Asset::get(BUILTIN_RECIPE).expect("the built-in recipe is embedded");
+        let text = String::from_utf8_lossy(&file.data);
+        Recipe::parse(&text).expect("the built-in recipe is valid")
+    }
+
+    // File filter for the firmware of a device with this SoC
+    pub fn firmware_pattern(&self, soc: &str) -> String {
+        self.firmware.replace("{soc}", soc)
+    }
+
+    pub fn matches_firmware(&self, file_name: &str, soc: &str) -> bool {
+        let mut pattern = String::from("^");
+        for c in self.firmware_pattern(soc).chars() {
+            match c {
+                '*' => pattern.push_str(".*"),
+                '?' => pattern.push('.'),
+                c => pattern.push_str(&regex::escape(&c.to_string())),
+            }
+        }
+        pattern.push('$');
+        Regex::new(&pattern).map(|regex| regex.is_match(file_name)).unwrap_or(false)
+    }
+}
+
+pub(crate) fn load_file(path: &Path) -> Result<Recipe> {
+    let text = fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    Recipe::parse(&text).with_context(|| format!("Invalid recipe {}", path.display()))
+}
+
+// The built-in recipe and the user's, sorted by name. Broken files are logged and left out.
+pub(crate) fn load_all() -> Vec<Recipe> {
+    let mut recipes = vec![Recipe::builtin()];
+    let dir = match config_dir() {
+        Some(dir) => dir.join(RECIPE_DIR),
+        None => return recipes,
+    };
+    let entries = match fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(_) => return recipes,
+    };
+    for path in entries.flatten().map(|entry| entry.path()) {
+        if path.extension().map(|ext| ext != "toml").unwrap_or(true) {
+            continue;
+        }
+        match load_file(&path) {
+            Ok(recipe) => {
+                info!("Loaded recipe '{}' from {}", recipe.name, path.display());
+                recipes.retain(|r| r.name != recipe.name);
+                recipes.push(recipe);
+            }
+            Err(e) => error!("error: {:?}", e),
+        }
+    }
+    recipes.sort_by_key(|recipe| recipe.name.to_lowercase());
+    recipes
+}
+
+// A recipe file, or a known recipe by name
+pub(crate) fn find(name_or_path: &str) -> Result<Recipe> {
+    let path = Path::new(name_or_path);
+    if path.is_file() {
+        return load_file(path);
+    }
+    load_all()
+        .into_iter()
+        .find(|recipe| recipe.name.eq_ignore_ascii_case(name_or_path))
+        .with_context(|| format!("No recipe named '{}'", name_or_path))
+}
+
+// Fill {name} with the shell-quoted variable. ${name} is left alone for the remote shell.
+pub(crate) fn render(template: &str, vars: &BTreeMap<String, String>) -> Result<String> {
+    expand(template, vars, quote)
+}
+
+// Like render, for a value used as it is, such as an upload destination
+pub(crate) fn render_plain(template: &str, vars: &BTreeMap<String, String>) -> Result<String> {
+    expand(template, vars, |value| value.to_string())
+}
+
+fn expand(template: &str, vars: &BTreeMap<String, String>, escape: impl Fn(&str) -> String) -> Result<String> {
+    let placeholder = Regex::new(r"\$?\{([A-Za-z_][A-Za-z0-9_]*)\}").expect("valid pattern");
+    let mut out = String::new();
+    let mut last = 0;
+    for captures in placeholder.captures_iter(template) {
+        let whole = captures.get(0).expect("group 0 is the match");
+        out.push_str(&template[last..whole.start()]);
+        last = whole.end();
+        if whole.as_str().starts_with('$') {
+            out.push_str(whole.as_str());
+            continue;
+        }
+        let name = &captures[1];
+        let value = vars.get(name).with_context(|| format!("Unknown variable {{{}}} in '{}'", name, template))?;
+        out.push_str(&escape(value));
+    }
+    out.push_str(&template[last..]);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars() -> BTreeMap<String, String> {
+        BTreeMap::from([
+            ("soc".to_string(), "ssc338q".to_string()),
+            ("file".to_string(), "my fw.tar".to_string()),
+        ])
+    }
+
+    #[test]
+    fn renders_quoted_variables() {
+        assert_eq!(render("tar -xf /tmp/{file} -C /tmp", &vars()).unwrap(), "tar -xf /tmp/'my fw.tar' -C /tmp");
+        assert_eq!(render("echo {soc} ${HOME}", &vars()).unwrap(), "echo ssc338q ${HOME}");
+        assert!(render("echo {missing}", &vars()).is_err());
+    }
+
+    #[test]
+    fn renders_plain_values() {
+        assert_eq!(render_plain("/tmp/{file}", &vars()).unwrap(), "/tmp/my fw.tar");
+    }
+
+    #[test]
+    fn parses_and_checks_recipes() {
+        let recipe = Recipe::parse(
+            r#"
+            name = "Test"
+            firmware = "fw-{soc}-*.tar"
+            [[step]]
+            name = "Upload"
+            upload = "/tmp/{file}"
+            [[step]]
+            name = "Install"
+            run = "sysupgrade /tmp/{file}"
+            when = { soc = "^ssc" }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(recipe.steps.len(), 2);
+        assert!(recipe.steps[1].applies(&vars()).unwrap());
+        assert!(recipe.matches_firmware("fw-ssc338q-1.0.tar", "ssc338q"));
+        assert!(!recipe.matches_firmware("fw-ssc338q.tar.gz", "ssc338q"));
+        assert!(Recipe::parse("name = \"Empty\"\nfirmware = \"*\"\nstep = []").is_err());
+        assert!(Recipe::parse("name = \"Both\"\nfirmware = \"*\"\n[[step]]\nname = \"x\"\nrun = \"true\"\nupload = \"/tmp\"").is_err());
+    }
+
+    #[test]
+    fn builtin_recipe_is_valid() {
+        assert!(!Recipe::builtin().steps.is_empty());
+    }
+}
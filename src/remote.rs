@@ -0,0 +1,82 @@
+use anyhow::Result;
+use std::fmt;
+
+// Longest SoC name accepted from a device, real ones are around ten characters
+const MAX_SOC_LEN: usize = 32;
+
+// A command line for the device's shell. The program and every argument are quoted,
+// so file names and values reported by the device can never turn into shell syntax.
+#[derive(Debug, Clone)]
+pub(crate) struct RemoteCommand {
+    line: String,
+}
+
+impl RemoteCommand {
+    pub fn new(program: &str) -> Self {
+        RemoteCommand { line: quote(program) }
+    }
+
+    pub fn arg(mut self, arg: impl AsRef<str>) -> Self {
+        self.line.push(' ');
+        self.line.push_str(&quote(arg.as_ref()));
+        self
+    }
+}
+
+impl fmt::Display for RemoteCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.line)
+    }
+}
+
+// POSIX shell quoting. Plain words stay as they are so the log stays readable.
+pub(crate) fn quote(arg: &str) -> String {
+    let plain = !arg.is_empty()
+        && arg.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '/' | ':' | '=' | '+' | ',' | '@'));
+    if plain {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+// The SoC ends up in file names on both sides, only a plain identifier is accepted
+pub(crate) fn validate_soc(soc: &str) -> Result<&str> {
+    if soc.is_empty()
+        || soc.len() > MAX_SOC_LEN
+        || !soc.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        return Err(anyhow::anyhow!("The device reported an invalid SoC name '{}'", soc.escape_debug()));
+    }
+    Ok(soc)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quotes_only_what_needs_it() {
+        assert_eq!(quote("/tmp/ruby-fw_1.2.tar"), "/tmp/ruby-fw_1.2.tar");
+        assert_eq!(quote(""), "''");
+        assert_eq!(quote("a b"), "'a b'");
+        assert_eq!(quote("$(reboot)"), "'$(reboot)'");
+        assert_eq!(quote("it's"), "'it'\\''s'");
+    }
+
+    #[test]
+    fn builds_command_lines() {
+        let command = RemoteCommand::new("cat").arg("/tmp/my file").arg("x;y");
+        assert_eq!(command.to_string(), "cat '/tmp/my file' 'x;y'");
+    }
+
+    #[test]
+    fn accepts_only_plain_soc_names() {
+        assert_eq!(validate_soc("ssc338q").unwrap(), "ssc338q");
+        assert!(validate_soc("hi3516ev300_lite").is_ok());
+        assert!(validate_soc("").is_err());
+        assert!(validate_soc("ssc338q; reboot").is_err());
+        assert!(validate_soc("../etc").is_err());
+        assert!(validate_soc(&"a".repeat(MAX_SOC_LEN + 1)).is_err());
+    }
+}
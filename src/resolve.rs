@@ -0,0 +1,211 @@
+use anyhow::{Context, Result};
+use log::{info, warn};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+const MDNS_ADDR: (Ipv4Addr, u16) = (Ipv4Addr::new(224, 0, 0, 251), 5353);
+const TIMEOUT_MDNS: u64 = 2;
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+
+// Turn what the user typed into addresses to connect to. Accepts IP literals,
+// IPv6 link-local addresses with a scope ID (fe80::1%eth0), DNS names and mDNS .local names.
+pub(crate) async fn resolve(host: &str, port: u16) -> Result<Vec<SocketAddr>> {
+    let host = host.trim().trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() {
+        return Err(anyhow::anyhow!("No IP address or hostname specified"));
+    }
+
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(vec![SocketAddr::new(ip, port)]);
+    }
+
+    // Numeric scope IDs are parsed here, interface names are left to the system resolver
+    if let Some((addr, scope)) = host.split_once('%') {
+        if let (Ok(ip), Ok(scope_id)) = (addr.parse::<Ipv6Addr>(), scope.parse::<u32>()) {
+            return Ok(vec![SocketAddr::V6(SocketAddrV6::new(ip, port, 0, scope_id))]);
+        }
+    }
+
+    // Not every system resolves .local names, so ask the network directly first
+    if host.to_ascii_lowercase().trim_end_matches('.').ends_with(".local") {
+        match mdns_lookup(host).await {
+            Ok(ips) if !ips.is_empty() => {
+                info!("mDNS resolved {} to {:?}", host, ips);
+                return Ok(ips.into_iter().map(|ip| SocketAddr::new(ip, port)).collect());
+            }
+            Ok(_) => info!("no mDNS answer for {}", host),
+            Err(e) => warn!("mDNS lookup for {} failed: {}", host, e),
+        }
+    }
+
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port))
+        .await
+        .with_context(|| format!("Invalid IP address or unknown host '{}'", host))?
+        .collect();
+    if addrs.is_empty() {
+        return Err(anyhow::anyhow!("Host '{}' has no addresses", host));
+    }
+    info!("resolved {} to {:?}", host, addrs);
+    Ok(addrs)
+}
+
+// One-shot mDNS query (RFC 6762 5.1): sent from an ephemeral port, answered by unicast
+async fn mdns_lookup(name: &str) -> Result<Vec<IpAddr>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    let mut query = Vec::new();
+    // Header: id, flags, 2 questions, no other records
+    query.extend_from_slice(&[0, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0]);
+    for qtype in [TYPE_A, TYPE_AAAA] {
+        encode_name(&mut query, name)?;
+        query.extend_from_slice(&qtype.to_be_bytes());
+        query.extend_from_slice(&CLASS_IN.to_be_bytes());
+    }
+    socket.send_to(&query, MDNS_ADDR).await?;
+
+    let mut ips = Vec::new();
+    let mut buf = [0u8; 1500];
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(TIMEOUT_MDNS);
+    // Keep collecting until the first answer arrives or the time runs out
+    while ips.is_empty() {
+        let len = match tokio::time::timeout_at(deadline, socket.recv(&mut buf)).await {
+            Ok(res) => res?,
+            Err(_) => break,
+        };
+        ips.extend(parse_answers(&buf[..len]));
+    }
+    Ok(ips)
+}
+
+fn encode_name(out: &mut Vec<u8>, name: &str) -> Result<()> {
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(anyhow::anyhow!("invalid hostname '{}'", name));
+        }
+        out.push(label.len() as u8);
+        out.extend_from_slice(label.as_bytes());
+    }
+    out.push(0);
+    Ok(())
+}
+
+// Returns the offset right after a (possibly compressed) name
+fn skip_name(msg: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *msg.get(pos)? as usize;
+        if len == 0 {
+            return Some(pos + 1);
+        }
+        if len & 0xc0 == 0xc0 {
+            return Some(pos + 2);
+        }
+        pos += len + 1;
+    }
+}
+
+fn read_u16(msg: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([*msg.get(pos)?, *msg.get(pos + 1)?]))
+}
+
+// Collect A and AAAA records from the answer and additional sections
+fn parse_answers(msg: &[u8]) -> Vec<IpAddr> {
+    let mut ips = Vec::new();
+    let (qdcount, ancount, nscount, arcount) = match (read_u16(msg, 4), read_u16(msg, 6), read_u16(msg, 8), read_u16(msg, 10)) {
+        (Some(qd), Some(an), Some(ns), Some(ar)) => (qd, an, ns, ar),
+        _ => return ips,
+    };
+    let mut pos = 12;
+    for _ in 0..qdcount {
+        pos = match skip_name(msg, pos) {
+            Some(p) => p + 4,
+            None => return ips,
+        };
+    }
+    for _ in 0..(ancount as usize + nscount as usize + arcount as usize) {
+        pos = match skip_name(msg, pos) {
+            Some(p) => p,
+            None => break,
+        };
+        let (rtype, rdlen) = match (read_u16(msg, pos), read_u16(msg, pos + 8)) {
+            (Some(t), Some(l)) => (t, l as usize),
+            _ => break,
+        };
+        let rdata = match msg.get(pos + 10..pos + 10 + rdlen) {
+            Some(data) => data,
+            None => break,
+        };
+        match (rtype, rdlen) {
+            (TYPE_A, 4) => ips.push(IpAddr::from([rdata[0], rdata[1], rdata[2], rdata[3]])),
+            (TYPE_AAAA, 16) => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(rdata);
+                let ip = Ipv6Addr::from(octets);
+                // Link-local answers are useless without knowing the interface
+                if ip.segments()[0] & 0xffc0 != 0xfe80 {
+                    ips.push(IpAddr::V6(ip));
+                }
+            }
+            _ => {}
+        }
+        pos += 10 + rdlen;
+    }
+    ips
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A record for the name at offset 12, which the answers point back to
+    fn record(rtype: u16, rdata: &[u8]) -> Vec<u8> {
+        let mut out = vec![0xc0, 12];
+        out.extend_from_slice(&rtype.to_be_bytes());
+        out.extend_from_slice(&CLASS_IN.to_be_bytes());
+        out.extend_from_slice(&[0, 0, 0, 120]);
+        out.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        out.extend_from_slice(rdata);
+        out
+    }
+
+    fn response(answers: &[Vec<u8>], additional: &[Vec<u8>]) -> Vec<u8> {
+        let mut msg = vec![0, 0, 0x84, 0, 0, 1, 0, answers.len() as u8, 0, 0, 0, additional.len() as u8];
+        encode_name(&mut msg, "openipc.local").unwrap();
+        msg.extend_from_slice(&TYPE_A.to_be_bytes());
+        msg.extend_from_slice(&CLASS_IN.to_be_bytes());
+        for r in answers.iter().chain(additional) {
+            msg.extend_from_slice(r);
+        }
+        msg
+    }
+
+    #[test]
+    fn collects_a_and_aaaa_records() {
+        let v6: Ipv6Addr = "fd00::10".parse().unwrap();
+        let msg = response(&[record(TYPE_A, &[192, 168, 1, 10])], &[record(TYPE_AAAA, &v6.octets())]);
+        assert_eq!(parse_answers(&msg), [IpAddr::from([192, 168, 1, 10]), IpAddr::V6(v6)]);
+    }
+
+    #[test]
+    fn skips_link_local_and_other_records() {
+        let link_local: Ipv6Addr = "fe80::1".parse().unwrap();
+        let msg = response(&[record(16, b"\x04text"), record(TYPE_AAAA, &link_local.octets()), record(TYPE_A, &[10, 0, 0, 1])], &[]);
+        assert_eq!(parse_answers(&msg), [IpAddr::from([10, 0, 0, 1])]);
+    }
+
+    #[test]
+    fn stops_at_a_truncated_message() {
+        let msg = response(&[record(TYPE_A, &[10, 0, 0, 1]), record(TYPE_A, &[10, 0, 0, 2])], &[]);
+        assert_eq!(parse_answers(&msg[..msg.len() - 2]), [IpAddr::from([10, 0, 0, 1])]);
+        assert!(parse_answers(&msg[..8]).is_empty());
+    }
+
+    #[tokio::test]
+    async fn accepts_literals_and_numeric_scopes() {
+        assert_eq!(resolve(" [::1] ", 22).await.unwrap(), ["[::1]:22".parse().unwrap()]);
+        let scoped = resolve("fe80::1%3", 2222).await.unwrap();
+        assert_eq!(scoped, [SocketAddr::V6(SocketAddrV6::new("fe80::1".parse().unwrap(), 2222, 0, 3))]);
+        assert!(resolve("  ", 22).await.is_err());
+    }
+}
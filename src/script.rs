@@ -0,0 +1,169 @@
+use anyhow::{Context, Error, Result};
+use log::{error, info};
+use rhai::{Engine, EvalAltResult, Scope};
+use russh::ChannelMsg;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::config_dir;
+use crate::flasher;
+use crate::remote::RemoteCommand;
+use crate::session::DeviceSession;
+
+// User scripts, *.rhai under the config folder
+const SCRIPT_DIR: &str = "scripts";
+
+// A workflow script offered in the Actions menu
+#[derive(Debug, Clone)]
+pub(crate) struct Script {
+    pub name: String,
+    pub path: PathBuf,
+}
+
+type LogFn = Arc<dyn Fn(&str) + Send + Sync>;
+type PromptFn = Arc<dyn Fn(&str) -> Option<String> + Send + Sync>;
+
+// How a script reaches the user: `log` lines go to the main log, `prompt` asks for a value
+#[derive(Clone)]
+pub(crate) struct ScriptHost {
+    pub log: LogFn,
+    pub prompt: PromptFn,
+}
+
+pub(crate) fn script_dir() -> Option<PathBuf> {
+    config_dir().map(|dir| dir.join(SCRIPT_DIR))
+}
+
+// Scripts in the folder, sorted by name
+pub(crate) fn list() -> Vec<Script> {
+    let entries = match script_dir().and_then(|dir| fs::read_dir(dir).ok()) {
+        Some(entries) => entries,
+        None => return Vec::new(),
+    };
+    let mut scripts: Vec<Script> = entries
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().map(|ext| ext == "rhai").unwrap_or(false))
+        .map(|path| Script {
+            name: path.file_stem().unwrap_or_default().to_string_lossy().to_string(),
+            path,
+        })
+        .collect();
+    scripts.sort_by_key(|script| script.name.to_lowercase());
+    scripts
+}
+
+// Run a script against the device session. The script runs on a blocking thread,
+// its device calls wait for the async operations they start.
+pub(crate) async fn run(script: &Script, session: Arc<DeviceSession>, host: ScriptHost) -> Result<()> {
+    let source = tokio::fs::read_to_string(&script.path)
+        .await
+        .with_context(|| format!("Failed to read {}", script.path.display()))?;
+    let base = script.path.parent().map(Path::to_path_buf).unwrap_or_default();
+    let name = script.name.clone();
+    let runtime = tokio::runtime::Handle::current();
+    info!("Running script {}", script.path.display());
+    tokio::task::spawn_blocking(move || {
+        let engine = engine(session.clone(), runtime, base, host);
+        let mut scope = Scope::new();
+        scope.push_constant("host", session.host().to_string());
+        scope.push_constant("port", session.port() as i64);
+        engine.run_with_scope(&mut scope, &source).map_err(|e| anyhow::anyhow!("script '{}': {}", name, e))
+    })
+    .await?
+}
+
+fn script_error(e: anyhow::Error) -> Box<EvalAltResult> {
+    error!("error: {:?}", e);
+    format!("{:#}", e).into()
+}
+
+// Local paths in a script are relative to the script's folder
+fn local_path(base: &Path, path: &str) -> PathBuf {
+    base.join(path)
+}
+
+fn engine(session: Arc<DeviceSession>, runtime: tokio::runtime::Handle, base: PathBuf, host: ScriptHost) -> Engine {
+    let mut engine = Engine::new();
+
+    let log = host.log.clone();
+    engine.on_print(move |text| log(text));
+    let log = host.log.clone();
+    engine.on_debug(move |text, _, _| log(text));
+    let log = host.log.clone();
+    engine.register_fn("log", move |text: &str| log(text));
+
+    // The command's stdout, a failed command stops the script unless caught.
+    // There is no idle timeout, a long quiet command is waited for.
+    let (s, rt, log) = (session.clone(), runtime.clone(), host.log.clone());
+    engine.register_fn("run", move |command: &str| -> Result<String, Box<EvalAltResult>> {
+        rt.block_on(flasher::execute(&s, command, None, |line| log(line)))
+            .and_then(|output| output.check(command, false))
+            .map(|output| output.stdout)
+            .map_err(script_error)
+    });
+
+    let (s, rt, log, dir) = (session.clone(), runtime.clone(), host.log.clone(), base.clone());
+    engine.register_fn("upload", move |local: &str, remote: &str| -> Result<(), Box<EvalAltResult>> {
+        let local = local_path(&dir, local);
+        log(&format!("Uploading {} to {}...", local.display(), remote));
+        rt.block_on(flasher::transfer_file(&local.to_string_lossy(), remote, &s, |line| log(line), |_, _| {}))
+            .map_err(script_error)
+    });
+
+    let (s, rt, log, dir) = (session.clone(), runtime.clone(), host.log.clone(), base);
+    engine.register_fn("download", move |remote: &str, local: &str| -> Result<(), Box<EvalAltResult>> {
+        let local = local_path(&dir, local);
+        let size = rt.block_on(download_file(&s, remote, &local)).map_err(script_error)?;
+        log(&format!("Downloaded {} to {} ({} bytes)", remote, local.display(), size));
+        Ok(())
+    });
+
+    // Empty when the user cancels
+    let prompt = host.prompt.clone();
+    engine.register_fn("prompt", move |message: &str| prompt(message).unwrap_or_default());
+
+    let (s, rt, log) = (session, runtime, host.log);
+    engine.register_fn("reboot_and_wait", move || -> Result<(), Box<EvalAltResult>> {
+        rt.block_on(reboot_and_wait(&s, |line| log(line))).map_err(script_error)
+    });
+
+    engine
+}
+
+// Copy a file from the device, byte for byte
+pub(crate) async fn download_file(session: &DeviceSession, src: &str, dst: &Path) -> Result<usize, Error> {
+    let command = RemoteCommand::new("cat").arg(src).to_string();
+    info!("# {}", command);
+    let mut channel = session.channel().await?;
+    channel.exec(true, command.as_str()).await?;
+    let mut data = Vec::new();
+    let mut stderr = String::new();
+    let mut exit_status = None;
+    while let Some(msg) = tokio::time::timeout(Duration::from_secs(flasher::TIMEOUT_MAIN), channel.wait()).await? {
+        match msg {
+            ChannelMsg::Data { data: chunk } => data.extend_from_slice(&chunk),
+            ChannelMsg::ExtendedData { data: chunk, .. } => stderr.push_str(&String::from_utf8_lossy(&chunk)),
+            ChannelMsg::ExitStatus { exit_status: status } => exit_status = Some(status),
+            _ => {}
+        }
+    }
+    if exit_status != Some(0) {
+        return Err(anyhow::anyhow!("Failed to read {} from the device: {}", src, stderr.trim()));
+    }
+    tokio::fs::write(dst, &data).await.with_context(|| format!("Failed to write {}", dst.display()))?;
+    Ok(data.len())
+}
+
+// Reboot the device and wait until it accepts a login again
+pub(crate) async fn reboot_and_wait<F>(session: &DeviceSession, mut status_update: F) -> Result<(), Error> where F: FnMut(&str) {
+    // The connection usually drops before reboot reports anything
+    if let Err(e) = flasher::run_command(session, "reboot", &mut status_update).await {
+        info!("reboot: {}", e);
+    }
+    flasher::wait_until_back(session, &mut status_update).await?;
+    status_update("Device is back online.");
+    Ok(())
+}
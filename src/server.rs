@@ -0,0 +1,453 @@
+// Local HTTP API for driving the flasher from another process. It only listens on
+// 127.0.0.1. Requests name the device in their JSON body, every device runs one
+// operation at a time.
+//
+// Every request needs `Authorization: Bearer <token>` with the token printed at startup,
+// and a Host header naming this server, so web pages cannot reach the API through the
+// browser, not even with DNS rebinding.
+//
+//   GET  /api/status   devices seen, what runs on them and how far it got, and how their
+//                      last operation went
+//   GET  /api/events   the JSON events as a server-sent event stream
+//   POST /api/detect   {host, port?, password?}
+//   POST /api/flash    {host, port?, password?, firmware, recipe?}
+//   POST /api/upload?name=<file name>   the firmware as an application/octet-stream body,
+//                                       returns its path for /api/flash
+//   POST /api/reset    {host, port?, password?}
+//   POST /api/exec     {host, port?, password?, command, idle_timeout?}
+
+use anyhow::{Context, Result};
+use axum::body::Bytes;
+use axum::extract::{DefaultBodyLimit, Query, Request, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::sse::{Event as SseEvent, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::OsRng;
+use futures_util::stream::{self, Stream};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::broadcast;
+
+use crate::events;
+use crate::flasher::{self, is_auth_error, Credentials, FlashPhase, Progress, UnitInfo};
+use crate::history::Operation;
+use crate::recipe::{self, Recipe};
+use crate::session::DeviceSession;
+
+// Largest firmware file accepted by /api/upload
+const MAX_UPLOAD: usize = 256 * 1024 * 1024;
+// Uploaded firmware goes here, under the system temp folder
+const UPLOAD_DIR: &str = "ruby-flasher-uploads";
+// Longest silence an exec request may allow, so a hung command cannot hold a device forever
+const MAX_EXEC_IDLE: u64 = 3600;
+
+type DeviceKey = (String, u16);
+
+// What the API knows about one device
+#[derive(Debug, Clone, Default, Serialize)]
+struct DeviceStatus {
+    host: String,
+    port: u16,
+    // The operation running now
+    running: Option<String>,
+    // Step of a running flash, counted from 1, and its name
+    step: Option<usize>,
+    phase: Option<String>,
+    // Firmware bytes uploaded so far and in total
+    sent: Option<usize>,
+    total: Option<usize>,
+    soc: Option<String>,
+    version: Option<String>,
+    last_operation: Option<String>,
+    last_ok: Option<bool>,
+    last_error: Option<String>,
+}
+
+struct Server {
+    // Requests must carry it as a bearer token
+    token: String,
+    // Host header values that name this server
+    hosts: [String; 2],
+    passwords: Vec<String>,
+    recipe: Recipe,
+    // One connection per device, kept between requests
+    sessions: Mutex<BTreeMap<DeviceKey, Arc<DeviceSession>>>,
+    devices: Mutex<BTreeMap<DeviceKey, DeviceStatus>>,
+}
+
+#[derive(Clone, Deserialize)]
+struct Target {
+    host: String,
+    #[serde(default = "default_port")]
+    port: u16,
+    // Tried before the passwords the server was started with
+    password: Option<String>,
+}
+
+fn default_port() -> u16 {
+    22
+}
+
+#[derive(Deserialize)]
+struct FlashRequest {
+    #[serde(flatten)]
+    target: Target,
+    // Path of the firmware file on this machine, as returned by /api/upload for uploads
+    firmware: String,
+    // Name of a known recipe or a recipe file, the server's recipe if not given
+    recipe: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ExecRequest {
+    #[serde(flatten)]
+    target: Target,
+    command: String,
+    // Seconds the command may stay silent before it counts as hung, up to MAX_EXEC_IDLE
+    idle_timeout: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct UploadQuery {
+    name: String,
+}
+
+// An error answered as {"error": "..."}
+struct ApiError {
+    status: StatusCode,
+    error: anyhow::Error,
+}
+
+impl ApiError {
+    fn new(status: StatusCode, error: anyhow::Error) -> Self {
+        ApiError { status, error }
+    }
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(error: anyhow::Error) -> Self {
+        let status = if is_auth_error(&error) { StatusCode::UNAUTHORIZED } else { StatusCode::INTERNAL_SERVER_ERROR };
+        ApiError { status, error }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.status, Json(json!({ "error": format!("{:#}", self.error) }))).into_response()
+    }
+}
+
+type ApiResult = Result<Json<Value>, ApiError>;
+
+// Marks a device busy until dropped
+struct Running {
+    server: Arc<Server>,
+    key: DeviceKey,
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        if let Some(device) = self.server.devices.lock().unwrap().get_mut(&self.key) {
+            device.running = None;
+            device.step = None;
+            device.phase = None;
+            device.sent = None;
+            device.total = None;
+        }
+    }
+}
+
+impl Server {
+    // The device's session and a guard that keeps other operations off it
+    fn begin(self: &Arc<Self>, target: &Target, operation: &str) -> Result<(Arc<DeviceSession>, Running), ApiError> {
+        let key = (target.host.clone(), target.port);
+        {
+            let mut devices = self.devices.lock().unwrap();
+            let device = devices.entry(key.clone()).or_insert_with(|| DeviceStatus {
+                host: target.host.clone(),
+                port: target.port,
+                ..Default::default()
+            });
+            if let Some(running) = &device.running {
+                return Err(ApiError::new(
+                    StatusCode::CONFLICT,
+                    anyhow::anyhow!("{}:{} is busy with {}", target.host, target.port, running),
+                ));
+            }
+            device.running = Some(operation.to_string());
+        }
+        let running = Running { server: self.clone(), key: key.clone() };
+
+        let mut candidates: Vec<String> = target.password.iter().cloned().collect();
+        candidates.extend(self.passwords.iter().cloned());
+        let session = self
+            .sessions
+            .lock()
+            .unwrap()
+            .entry(key)
+            .or_insert_with(|| DeviceSession::new(&target.host, target.port, Credentials::new(candidates.clone())))
+            .clone();
+        session.set_candidates(candidates);
+        Ok((session, running))
+    }
+
+    // Where a running flash is, for /api/status
+    fn progress(&self, target: &Target, update: Progress) {
+        let mut devices = self.devices.lock().unwrap();
+        let Some(device) = devices.get_mut(&(target.host.clone(), target.port)) else { return };
+        match update {
+            Progress::Phase(phase) => {
+                device.step = FlashPhase::ALL.iter().position(|p| *p == phase).map(|index| index + 1);
+                device.phase = Some(phase.label().to_string());
+            }
+            Progress::Upload { sent, total } => {
+                device.sent = Some(sent);
+                device.total = Some(total);
+            }
+        }
+    }
+
+    // Record how an operation went, in the history and for /api/status
+    fn finish<T>(&self, target: &Target, operation: Operation, name: &str, unit: Option<&UnitInfo>, result: &Result<T>) {
+        operation.finish(result.as_ref().err());
+        let mut devices = self.devices.lock().unwrap();
+        if let Some(device) = devices.get_mut(&(target.host.clone(), target.port)) {
+            if let Some(unit) = unit {
+                device.soc = Some(unit.soc.clone()).filter(|soc| !soc.is_empty());
+                device.version = Some(unit.version.clone()).filter(|version| !version.is_empty());
+            }
+            device.last_operation = Some(name.to_string());
+            device.last_ok = Some(result.is_ok());
+            device.last_error = result.as_ref().err().map(|e| format!("{:#}", e));
+        }
+        if let Err(e) = result {
+            error!("{} {}:{}: {:?}", name, target.host, target.port, e);
+        }
+    }
+}
+
+// A fresh random token per run
+fn new_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// Turn away requests that do not name this server or lack the token
+async fn authorize(State(server): State<Arc<Server>>, request: Request, next: Next) -> Result<Response, ApiError> {
+    let headers = request.headers();
+    let host = headers.get(header::HOST).and_then(|value| value.to_str().ok()).unwrap_or_default();
+    if !server.hosts.iter().any(|allowed| allowed == host) {
+        return Err(ApiError::new(StatusCode::FORBIDDEN, anyhow::anyhow!("Unexpected Host '{}'", host)));
+    }
+    let token = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    // Compared in full so the time taken does not tell how much matched
+    let valid = token
+        .map(|token| token.len() == server.token.len() && token.bytes().zip(server.token.bytes()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0)
+        .unwrap_or(false);
+    if !valid {
+        return Err(ApiError::new(StatusCode::UNAUTHORIZED, anyhow::anyhow!("Missing or wrong API token")));
+    }
+    Ok(next.run(request).await)
+}
+
+async fn status(State(server): State<Arc<Server>>) -> Json<Value> {
+    let devices: Vec<DeviceStatus> = server.devices.lock().unwrap().values().cloned().collect();
+    Json(json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "recipe": server.recipe.name,
+        "devices": devices,
+    }))
+}
+
+async fn detect(State(server): State<Arc<Server>>, Json(target): Json<Target>) -> ApiResult {
+    let (session, _running) = server.begin(&target, "detect")?;
+    let mut operation = Operation::new("detect", &target.host, target.port);
+    let result = flasher::identify_unit(&session, |line| operation.log(line)).await;
+    if let Ok(unit) = &result {
+        operation.set_unit_before(unit);
+    }
+    server.finish(&target, operation, "detect", result.as_ref().ok(), &result);
+    let unit = result?;
+    Ok(Json(json!({
+        "soc": unit.soc,
+        "identity": unit.identity,
+        "version": unit.version,
+    })))
+}
+
+async fn flash(State(server): State<Arc<Server>>, Json(request): Json<FlashRequest>) -> ApiResult {
+    let recipe = match &request.recipe {
+        Some(name) => recipe::find(name).map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, e))?,
+        None => server.recipe.clone(),
+    };
+    if !PathBuf::from(&request.firmware).is_file() {
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, anyhow::anyhow!("No firmware file {}", request.firmware)));
+    }
+    let FlashRequest { target, firmware, .. } = request;
+    let (session, running) = server.begin(&target, "flash")?;
+    // Detached from the request, a client that goes away must not stop a flash halfway
+    let task_server = server.clone();
+    let job = tokio::spawn(async move {
+        let _running = running;
+        let mut operation = Operation::new("flash", &target.host, target.port);
+        operation.set_firmware(&firmware);
+        let mut before = None;
+        let mut after = None;
+        let result: Result<()> = async {
+            let mut log = |line: &str| operation.log(line);
+            let progress = |update| task_server.progress(&target, update);
+            let unit = flasher::flash(&session, &recipe, &firmware, &mut log, progress).await?;
+            before = Some(unit.clone());
+            if recipe.reboot {
+                after = Some(flasher::wait_for_reboot(&session, &unit.soc, &mut log, progress).await?);
+            }
+            Ok(())
+        }
+        .await;
+        if let Some(unit) = &before {
+            operation.set_unit_before(unit);
+        }
+        if let Some(unit) = &after {
+            operation.set_unit_after(unit);
+        }
+        task_server.finish(&target, operation, "flash", after.as_ref().or(before.as_ref()), &result);
+        (result, before, after)
+    });
+    let (result, before, after) = job.await.context("The flash task failed")?;
+    result?;
+    Ok(Json(json!({
+        "soc": before.as_ref().map(|unit| unit.soc.clone()),
+        "previous_version": before.as_ref().map(|unit| unit.version.clone()),
+        "version": after.as_ref().map(|unit| unit.version.clone()),
+    })))
+}
+
+async fn upload(Query(query): Query<UploadQuery>, headers: HeaderMap, body: Bytes) -> ApiResult {
+    // A form on a web page cannot send this content type without a CORS preflight
+    let content_type = headers.get(header::CONTENT_TYPE).and_then(|value| value.to_str().ok()).unwrap_or_default();
+    if content_type != "application/octet-stream" {
+        return Err(ApiError::new(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            anyhow::anyhow!("The firmware must be sent as application/octet-stream"),
+        ));
+    }
+    // Only a plain file name, the recipe matches firmware by its name
+    let name = query.name.trim();
+    if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\', '\0']) {
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, anyhow::anyhow!("Invalid file name '{}'", query.name)));
+    }
+    let dir = std::env::temp_dir().join(UPLOAD_DIR);
+    tokio::fs::create_dir_all(&dir)
+        .await
+        .with_context(|| format!("Failed to create {}", dir.display()))?;
+    let path = dir.join(name);
+    tokio::fs::write(&path, &body)
+        .await
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    info!("Received firmware {} ({} bytes)", path.display(), body.len());
+    Ok(Json(json!({ "firmware": path, "size": body.len() })))
+}
+
+async fn reset(State(server): State<Arc<Server>>, Json(target): Json<Target>) -> ApiResult {
+    let (session, running) = server.begin(&target, "reset")?;
+    // Detached like flash, firstboot must not be cut off by a client that goes away
+    let task_server = server.clone();
+    let job = tokio::spawn(async move {
+        let _running = running;
+        let mut operation = Operation::new("reset", &target.host, target.port);
+        let result = flasher::reset_device(&session, |line| operation.log(line)).await;
+        // The device reboots, whatever connection is left is gone
+        session.close().await;
+        task_server.finish(&target, operation, "reset", None, &result);
+        result
+    });
+    job.await.context("The reset task failed")??;
+    Ok(Json(json!({})))
+}
+
+async fn exec(State(server): State<Arc<Server>>, Json(request): Json<ExecRequest>) -> ApiResult {
+    let target = &request.target;
+    let (session, _running) = server.begin(target, "exec")?;
+    let mut operation = Operation::new("exec", &target.host, target.port);
+    let idle = request.idle_timeout.map_or(flasher::IDLE_TIMEOUT, |secs| Duration::from_secs(secs.clamp(1, MAX_EXEC_IDLE)));
+    let result = flasher::execute(&session, &request.command, Some(idle), |line| operation.log(line)).await;
+    // A command that ran but failed counts as a failed operation in the history
+    let outcome = match &result {
+        Ok(output) if output.exit_status == Some(0) => Ok(()),
+        Ok(output) => match &output.signal {
+            Some(signal) => Err(anyhow::anyhow!("'{}' was killed by signal {}", request.command, signal)),
+            None => Err(anyhow::anyhow!("'{}' exited with status {:?}", request.command, output.exit_status)),
+        },
+        Err(e) => Err(anyhow::anyhow!("{:#}", e)),
+    };
+    server.finish(target, operation, "exec", None, &outcome);
+    let output = result?;
+    Ok(Json(json!({
+        "stdout": output.stdout,
+        "stderr": output.stderr,
+        "exit_status": output.exit_status,
+        "signal": output.signal,
+        "duration_ms": output.duration.as_millis(),
+    })))
+}
+
+async fn event_stream() -> Sse<impl Stream<Item = Result<SseEvent, Infallible>>> {
+    let receiver = events::subscribe();
+    let stream = stream::unfold(receiver, |mut receiver| async move {
+        let event = match receiver.recv().await {
+            Ok(line) => SseEvent::default().data(line),
+            // Tell the client it missed some instead of dropping the stream
+            Err(broadcast::error::RecvError::Lagged(missed)) => SseEvent::default().comment(format!("{} events missed", missed)),
+            Err(broadcast::error::RecvError::Closed) => return None,
+        };
+        Some((Ok(event), receiver))
+    });
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+// Serve the API on 127.0.0.1:`port` until the process ends. Without `token` a random
+// one is made, either way it is printed for the controlling program.
+pub(crate) async fn serve(port: u16, token: Option<String>, passwords: Vec<String>, recipe: Recipe) -> Result<()> {
+    let token = token.filter(|token| !token.is_empty()).unwrap_or_else(new_token);
+    let server = Arc::new(Server {
+        token: token.clone(),
+        hosts: [format!("127.0.0.1:{}", port), format!("localhost:{}", port)],
+        passwords,
+        recipe,
+        sessions: Mutex::new(BTreeMap::new()),
+        devices: Mutex::new(BTreeMap::new()),
+    });
+    let app = Router::new()
+        .route("/api/status", get(status))
+        .route("/api/events", get(event_stream))
+        .route("/api/detect", post(detect))
+        .route("/api/flash", post(flash))
+        .route("/api/upload", post(upload).layer(DefaultBodyLimit::max(MAX_UPLOAD)))
+        .route("/api/reset", post(reset))
+        .route("/api/exec", post(exec))
+        .layer(middleware::from_fn_with_state(server.clone(), authorize))
+        .with_state(server);
+    let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+    let listener = tokio::net::TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("Failed to listen on {}", addr))?;
+    info!("API listening on http://{}", addr);
+    println!("API listening on http://{}", addr);
+    println!("API token: {}", token);
+    axum::serve(listener, app).await.context("API server failed")
+}
@@ -0,0 +1,98 @@
+use anyhow::Result;
+use log::info;
+use russh::client::{Handle, Msg};
+use russh::{Channel, Disconnect};
+use std::sync::{Arc, Mutex};
+
+use crate::events::{self, Event};
+use crate::flasher::{smart_connect, Client, Credentials};
+use crate::resolve::resolve;
+
+// A long-lived, logged-in connection to one device. Every operation opens its own
+// channel on it, so a command can run while logs are streaming. The connection is
+// made on first use and made again when the device dropped it.
+pub(crate) struct DeviceSession {
+    host: String,
+    port: u16,
+    credentials: Mutex<Credentials>,
+    handle: tokio::sync::Mutex<Option<Arc<Handle<Client>>>>,
+}
+
+impl DeviceSession {
+    pub fn new(host: &str, port: u16, credentials: Credentials) -> Arc<DeviceSession> {
+        Arc::new(DeviceSession {
+            host: host.to_string(),
+            port,
+            credentials: Mutex::new(credentials),
+            handle: tokio::sync::Mutex::new(None),
+        })
+    }
+
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    // The passwords to try, with the one that worked last if any
+    pub fn credentials(&self) -> Credentials {
+        self.credentials.lock().unwrap().clone()
+    }
+
+    // Passwords for the next login, an open connection is kept as it is
+    pub fn set_candidates(&self, candidates: Vec<String>) {
+        self.credentials.lock().unwrap().candidates = candidates;
+    }
+
+    // A password that logged in or that the app just set, tried first from now on
+    pub fn set_working(&self, password: &str) {
+        let mut credentials = self.credentials.lock().unwrap();
+        credentials.candidates.retain(|p| p != password);
+        credentials.candidates.insert(0, password.to_string());
+        credentials.working = Some(password.to_string());
+    }
+
+    // The open connection, logging in again if there is none or it was closed
+    pub async fn handle(&self) -> Result<Arc<Handle<Client>>> {
+        let mut handle = self.handle.lock().await;
+        if let Some(existing) = handle.as_ref() {
+            if !existing.is_closed() {
+                return Ok(existing.clone());
+            }
+            info!("Connection to {}:{} was closed, reconnecting", self.host, self.port);
+        }
+        *handle = None;
+        events::emit(&self.host, self.port, Event::Connecting);
+        let addrs = resolve(&self.host, self.port).await?;
+        let mut credentials = self.credentials();
+        let connected = Arc::new(smart_connect(&addrs, &mut credentials).await?); // This can return auth errors
+        events::emit(&self.host, self.port, Event::Connected);
+        if let Some(password) = &credentials.working {
+            self.set_working(password);
+        }
+        *handle = Some(connected.clone());
+        Ok(connected)
+    }
+
+    // A new channel, with one retry on a fresh connection if the old one turned out to be dead
+    pub async fn channel(&self) -> Result<Channel<Msg>> {
+        let handle = self.handle().await?;
+        match handle.channel_open_session().await {
+            Ok(channel) => Ok(channel),
+            Err(e) => {
+                info!("Opening a channel to {}:{} failed, reconnecting: {}", self.host, self.port, e);
+                self.close().await;
+                Ok(self.handle().await?.channel_open_session().await?)
+            }
+        }
+    }
+
+    // Drop the connection, the next operation logs in again. Used when the device reboots.
+    pub async fn close(&self) {
+        if let Some(handle) = self.handle.lock().await.take() {
+            let _ = handle.disconnect(Disconnect::ByApplication, "", "en").await;
+        }
+    }
+}
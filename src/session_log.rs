@@ -0,0 +1,67 @@
+use anyhow::{Context, Result};
+use log::error;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::config_dir;
+
+const LOG_DIR: &str = "logs";
+// Older session logs are deleted when a new session starts
+const MAX_SESSION_LOGS: usize = 20;
+
+// Everything shown in the log window, also written to a file per session
+pub(crate) struct SessionFile {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl SessionFile {
+    pub fn create() -> Result<SessionFile> {
+        let dir = config_dir().context("No config directory on this platform")?.join(LOG_DIR);
+        fs::create_dir_all(&dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+        rotate(&dir);
+        let name = format!("session-{}.log", chrono::Local::now().format("%Y%m%d-%H%M%S"));
+        let path = dir.join(name);
+        let file = File::create(&path).with_context(|| format!("Failed to create {}", path.display()))?;
+        Ok(SessionFile {
+            path,
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn write(&self, text: &str) {
+        let mut file = self.file.lock().unwrap();
+        if let Err(e) = file.write_all(text.as_bytes()).and_then(|_| file.flush()) {
+            error!("Failed to write {}: {:?}", self.path.display(), e);
+        }
+    }
+}
+
+// Keep the newest logs so there is room for the one about to be created
+fn rotate(dir: &Path) {
+    let mut logs: Vec<PathBuf> = match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| {
+                path.file_name()
+                    .map(|n| n.to_string_lossy().starts_with("session-"))
+                    .unwrap_or(false)
+            })
+            .collect(),
+        Err(_) => return,
+    };
+    // Names carry the start time, so sorting by name sorts by age
+    logs.sort();
+    let excess = (logs.len() + 1).saturating_sub(MAX_SESSION_LOGS);
+    for path in logs.into_iter().take(excess) {
+        if let Err(e) = fs::remove_file(&path) {
+            error!("Failed to remove {}: {:?}", path.display(), e);
+        }
+    }
+}
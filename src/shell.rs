@@ -0,0 +1,140 @@
+use anyhow::Result;
+use log::{error, info};
+use russh::client::Msg;
+use russh::{Channel, ChannelMsg, Sig};
+use tokio::sync::mpsc;
+
+use crate::session::DeviceSession;
+
+const TERM: &str = "xterm";
+// Starts the user's login shell on the device
+pub(crate) const LOGIN_SHELL: &str = "exec \"${SHELL:-/bin/sh}\" -l";
+
+pub(crate) enum ShellEvent {
+    Output(String),
+    // The shell exited or the connection dropped, with the exit status if the device sent one
+    Closed(Option<u32>),
+}
+
+enum ShellInput {
+    Data(Vec<u8>),
+    Resize(u32, u32),
+    Signal(Sig),
+    Close,
+}
+
+// Handle to an interactive shell running on the device. Cheap to clone, the
+// channel stays open until `close` is called or the remote shell exits.
+#[derive(Clone)]
+pub(crate) struct Shell {
+    tx: mpsc::UnboundedSender<ShellInput>,
+}
+
+impl Shell {
+    pub fn send(&self, data: &[u8]) {
+        let _ = self.tx.send(ShellInput::Data(data.to_vec()));
+    }
+
+    pub fn resize(&self, cols: u32, rows: u32) {
+        let _ = self.tx.send(ShellInput::Resize(cols, rows));
+    }
+
+    pub fn close(&self) {
+        let _ = self.tx.send(ShellInput::Close);
+    }
+
+    pub fn signal(&self, signal: Sig) {
+        let _ = self.tx.send(ShellInput::Signal(signal));
+    }
+
+    // Ctrl-C for the foreground command: ETX through the PTY, plus a SIGINT request
+    // for servers that deliver signals
+    pub fn interrupt(&self) {
+        self.send(b"\x03");
+        self.signal(Sig::INT);
+    }
+}
+
+// Run `command` on a PTY, on its own channel of the device session.
+// `event` is called from a background task.
+pub(crate) async fn open<F>(session: &DeviceSession, command: &str, cols: u32, rows: u32, event: F) -> Result<Shell>
+where
+    F: FnMut(ShellEvent) + Send + 'static,
+{
+    let channel = session.channel().await?; // This can return auth errors
+    channel.request_pty(false, TERM, cols, rows, 0, 0, &[]).await?;
+    channel.exec(false, command).await?;
+    info!("Shell opened on {}:{}", session.host(), session.port());
+    let (tx, rx) = mpsc::unbounded_channel();
+    tokio::spawn(run(channel, rx, event));
+    Ok(Shell { tx })
+}
+
+async fn run<F>(mut channel: Channel<Msg>, mut rx: mpsc::UnboundedReceiver<ShellInput>, mut event: F)
+where
+    F: FnMut(ShellEvent),
+{
+    let mut pending: Vec<u8> = Vec::new();
+    let mut exit_status = None;
+    loop {
+        tokio::select! {
+            input = rx.recv() => match input {
+                Some(ShellInput::Data(data)) => {
+                    if let Err(e) = channel.data(&data[..]).await {
+                        error!("shell write failed: {:?}", e);
+                        break;
+                    }
+                }
+                Some(ShellInput::Resize(cols, rows)) => {
+                    if let Err(e) = channel.window_change(cols, rows, 0, 0).await {
+                        error!("shell resize failed: {:?}", e);
+                    }
+                }
+                Some(ShellInput::Signal(signal)) => {
+                    info!("sending {:?} to the shell", signal);
+                    if let Err(e) = channel.signal(signal).await {
+                        error!("shell signal failed: {:?}", e);
+                    }
+                }
+                Some(ShellInput::Close) | None => break,
+            },
+            msg = channel.wait() => match msg {
+                Some(ChannelMsg::Data { data }) | Some(ChannelMsg::ExtendedData { data, .. }) => {
+                    pending.extend_from_slice(&data);
+                    let text = take_utf8(&mut pending);
+                    if !text.is_empty() {
+                        event(ShellEvent::Output(text));
+                    }
+                }
+                Some(ChannelMsg::ExitStatus { exit_status: status }) => exit_status = Some(status),
+                Some(_) => {}
+                None => break,
+            },
+        }
+    }
+    let _ = channel.close().await;
+    info!("Shell closed, exit status {:?}", exit_status);
+    event(ShellEvent::Closed(exit_status));
+}
+
+// Take the decodable part of the buffer, an incomplete sequence at the end waits for the next chunk
+fn take_utf8(buf: &mut Vec<u8>) -> String {
+    match std::str::from_utf8(buf) {
+        Ok(text) => {
+            let text = text.to_string();
+            buf.clear();
+            text
+        }
+        Err(e) if e.error_len().is_none() => {
+            let valid = e.valid_up_to();
+            let text = String::from_utf8_lossy(&buf[..valid]).to_string();
+            buf.drain(..valid);
+            text
+        }
+        Err(_) => {
+            let text = String::from_utf8_lossy(buf).to_string();
+            buf.clear();
+            text
+        }
+    }
+}
@@ -0,0 +1,104 @@
+use anyhow::{Context, Error, Result};
+use log::error;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+use crate::flasher;
+use crate::session::DeviceSession;
+
+// Everything that goes into a support bundle
+pub(crate) struct Bundle {
+    pub session_log: String,
+    // Address of the device the snapshot was taken from
+    pub device: Option<String>,
+    pub device_files: Vec<(String, String)>,
+    // Why there is no device snapshot, if there is none
+    pub device_error: Option<String>,
+}
+
+pub(crate) fn default_file_name() -> String {
+    format!("ruby-flasher-support-{}.zip", chrono::Local::now().format("%Y%m%d-%H%M%S"))
+}
+
+fn host_info() -> String {
+    let mut info = format!(
+        "Ruby Flasher {}\nCreated: {}\nOS: {} ({})\nArchitecture: {}\n",
+        env!("CARGO_PKG_VERSION"),
+        chrono::Local::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, false),
+        std::env::consts::OS,
+        std::env::consts::FAMILY,
+        std::env::consts::ARCH,
+    );
+    info.push_str("Network interfaces:\n");
+    match if_addrs::get_if_addrs() {
+        Ok(interfaces) => {
+            for iface in interfaces {
+                info.push_str(&format!("  {}: {}\n", iface.name, iface.ip()));
+            }
+        }
+        Err(e) => info.push_str(&format!("  unavailable: {}\n", e)),
+    }
+    info
+}
+
+pub(crate) fn write(bundle: &Bundle, path: &Path) -> Result<()> {
+    let file = File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+    let mut zip = ZipWriter::new(file);
+    let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+
+    let mut host = host_info();
+    if let Some(device) = &bundle.device {
+        host.push_str(&format!("Device: {}\n", device));
+    }
+    if let Some(error) = &bundle.device_error {
+        host.push_str(&format!("Device snapshot failed: {}\n", error));
+    }
+    zip.start_file("host.txt", options)?;
+    zip.write_all(host.as_bytes())?;
+    zip.start_file("session.log", options)?;
+    zip.write_all(bundle.session_log.as_bytes())?;
+    for (name, content) in &bundle.device_files {
+        zip.start_file(format!("device/{}", name), options)?;
+        zip.write_all(content.as_bytes())?;
+    }
+    zip.finish()?;
+    Ok(())
+}
+
+// What a support bundle collects from the device: file name in the bundle and the command producing it
+const SNAPSHOT_COMMANDS: &[(&str, &str)] = &[
+    ("dmesg.txt", "dmesg 2>&1"),
+    ("logread.txt", "logread 2>&1"),
+    ("mtd.txt", "cat /proc/mtd 2>&1"),
+    ("fw_printenv.txt", "fw_printenv 2>&1"),
+    ("ps.txt", "ps 2>&1"),
+    ("df.txt", "df -h 2>&1"),
+    ("os-release.txt", "cat /etc/os-release 2>&1"),
+    (
+        "ruby_logs.txt",
+        "for f in /tmp/ruby/logs/* /tmp/logs/*; do [ -f \"$f\" ] && echo \"==> $f <==\" && tail -n 1000 \"$f\"; done; true",
+    ),
+];
+
+// Collect the device state for a bug report. A command that fails leaves its error in its file.
+pub(crate) async fn device_snapshot<F>(session: &DeviceSession, mut status_update: F) -> Result<Vec<(String, String)>, Error> where F: FnMut(&str) {
+    status_update(&format!("Connecting to {}:{}...", session.host(), session.port()));
+    session.handle().await?; // This can return auth errors
+    let mut files = Vec::new();
+    for (name, command) in SNAPSHOT_COMMANDS {
+        status_update(&format!("Collecting {}...", name));
+        // The output goes into the bundle, not the log
+        let content = match flasher::run_command(session, command, |_| {}).await {
+            Ok(output) => output.stdout,
+            Err(e) => {
+                error!("{} failed: {:?}", command, e);
+                format!("# {}\nfailed: {}\n", command, e)
+            }
+        };
+        files.push((name.to_string(), content));
+    }
+    Ok(files)
+}
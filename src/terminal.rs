@@ -0,0 +1,570 @@
+use std::collections::VecDeque;
+
+// Lines kept above the screen for scrolling back
+const SCROLLBACK_LINES: usize = 2000;
+const TAB_WIDTH: usize = 8;
+// Larger CSI parameters are cut down to this, as xterm does, so cursor arithmetic cannot overflow
+const MAX_PARAM: usize = 65535;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum TermColor {
+    Default,
+    Indexed(u8),
+    Rgb(u8, u8, u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Cell {
+    pub ch: char,
+    pub fg: TermColor,
+    pub bg: TermColor,
+    pub bold: bool,
+    pub underline: bool,
+    pub inverse: bool,
+}
+
+impl Default for Cell {
+    fn default() -> Self {
+        Cell {
+            ch: ' ',
+            fg: TermColor::Default,
+            bg: TermColor::Default,
+            bold: false,
+            underline: false,
+            inverse: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ParseState {
+    Ground,
+    Escape,
+    // ESC followed by a character set designator, the next character is skipped
+    Charset,
+    Csi,
+    Osc,
+    OscEscape,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct SavedCursor {
+    x: usize,
+    y: usize,
+    pen: Cell,
+}
+
+// Screen buffer of a VT100/xterm subset: cursor movement, erasing, scroll regions,
+// insert/delete, SGR attributes with 16/256/true colours and the alternate screen.
+pub(crate) struct Screen {
+    cols: usize,
+    rows: usize,
+    cells: Vec<Cell>,
+    scrollback: VecDeque<Vec<Cell>>,
+    // How many lines the view is scrolled back, 0 follows the output
+    view_offset: usize,
+    cursor_x: usize,
+    cursor_y: usize,
+    // The cursor sits past the last column, the next character wraps
+    wrap_pending: bool,
+    pen: Cell,
+    scroll_top: usize,
+    scroll_bottom: usize,
+    saved: SavedCursor,
+    // The main screen while a full-screen program uses the alternate one
+    main_screen: Option<(Vec<Cell>, SavedCursor)>,
+    pub cursor_visible: bool,
+    // DECCKM: arrow keys send ESC O instead of ESC [
+    pub app_cursor_keys: bool,
+    state: ParseState,
+    params: String,
+    // Replies to status queries, to be sent back to the device
+    responses: Vec<u8>,
+}
+
+impl Screen {
+    pub fn new(cols: usize, rows: usize) -> Self {
+        let cols = cols.max(1);
+        let rows = rows.max(1);
+        Screen {
+            cols,
+            rows,
+            cells: vec![Cell::default(); cols * rows],
+            scrollback: VecDeque::new(),
+            view_offset: 0,
+            cursor_x: 0,
+            cursor_y: 0,
+            wrap_pending: false,
+            pen: Cell::default(),
+            scroll_top: 0,
+            scroll_bottom: rows - 1,
+            saved: SavedCursor { x: 0, y: 0, pen: Cell::default() },
+            main_screen: None,
+            cursor_visible: true,
+            app_cursor_keys: false,
+            state: ParseState::Ground,
+            params: String::new(),
+            responses: Vec::new(),
+        }
+    }
+
+    pub fn size(&self) -> (usize, usize) {
+        (self.cols, self.rows)
+    }
+
+    pub fn cursor(&self) -> (usize, usize) {
+        (self.cursor_x.min(self.cols - 1), self.cursor_y)
+    }
+
+    pub fn view_offset(&self) -> usize {
+        self.view_offset
+    }
+
+    pub fn scroll_view(&mut self, lines: isize) {
+        let max = self.scrollback.len() as isize;
+        self.view_offset = (self.view_offset as isize + lines).clamp(0, max) as usize;
+    }
+
+    // Row as it should be displayed, taking the scrollback offset into account
+    pub fn visible_row(&self, row: usize) -> &[Cell] {
+        if row < self.view_offset {
+            let line = &self.scrollback[self.scrollback.len() - self.view_offset + row];
+            &line[..line.len().min(self.cols)]
+        } else {
+            let y = row - self.view_offset;
+            &self.cells[y * self.cols..(y + 1) * self.cols]
+        }
+    }
+
+    pub fn take_responses(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.responses)
+    }
+
+    // Keep the text when the view changes size, anchored at the bottom like xterm does
+    pub fn resize(&mut self, cols: usize, rows: usize) {
+        let cols = cols.max(1);
+        let rows = rows.max(1);
+        if cols == self.cols && rows == self.rows {
+            return;
+        }
+        let mut lines: Vec<Vec<Cell>> = self.cells.chunks(self.cols).map(|row| row.to_vec()).collect();
+        // Blank lines below the cursor go first when shrinking
+        while lines.len() > rows && lines.len() - 1 > self.cursor_y {
+            if lines.last().map(|l| l.iter().all(|c| *c == Cell::default())).unwrap_or(false) {
+                lines.pop();
+            } else {
+                break;
+            }
+        }
+        while lines.len() > rows {
+            let line = lines.remove(0);
+            self.push_scrollback(line);
+            self.cursor_y = self.cursor_y.saturating_sub(1);
+        }
+        self.cells = Vec::with_capacity(cols * rows);
+        for y in 0..rows {
+            let mut line = lines.get(y).cloned().unwrap_or_default();
+            line.resize(cols, Cell::default());
+            self.cells.extend(line);
+        }
+        if let Some((main, _)) = &mut self.main_screen {
+            let old_cols = self.cols;
+            let mut resized = Vec::with_capacity(cols * rows);
+            for y in 0..rows {
+                let mut line = main.get(y * old_cols..(y + 1) * old_cols).map(|l| l.to_vec()).unwrap_or_default();
+                line.resize(cols, Cell::default());
+                resized.extend(line);
+            }
+            *main = resized;
+        }
+        self.cols = cols;
+        self.rows = rows;
+        self.cursor_x = self.cursor_x.min(cols - 1);
+        self.cursor_y = self.cursor_y.min(rows - 1);
+        self.scroll_top = 0;
+        self.scroll_bottom = rows - 1;
+        self.wrap_pending = false;
+        self.view_offset = 0;
+    }
+
+    pub fn feed(&mut self, text: &str) {
+        // New output brings the view back to the bottom
+        self.view_offset = 0;
+        for ch in text.chars() {
+            match self.state {
+                ParseState::Ground => self.ground(ch),
+                ParseState::Escape => self.escape(ch),
+                ParseState::Charset => self.state = ParseState::Ground,
+                ParseState::Csi => {
+                    if ('\x40'..='\x7e').contains(&ch) {
+                        self.state = ParseState::Ground;
+                        let params = std::mem::take(&mut self.params);
+                        self.csi(&params, ch);
+                    } else if ch == '\x1b' {
+                        self.params.clear();
+                        self.state = ParseState::Escape;
+                    } else if !ch.is_control() {
+                        self.params.push(ch);
+                    }
+                }
+                // Window titles and the like are ignored
+                ParseState::Osc => match ch {
+                    '\x07' => self.state = ParseState::Ground,
+                    '\x1b' => self.state = ParseState::OscEscape,
+                    _ => {}
+                },
+                ParseState::OscEscape => self.state = ParseState::Ground,
+            }
+        }
+    }
+
+    fn ground(&mut self, ch: char) {
+        match ch {
+            '\x1b' => self.state = ParseState::Escape,
+            '\r' => self.carriage_return(),
+            '\n' | '\x0b' | '\x0c' => self.line_feed(),
+            '\x08' => {
+                self.wrap_pending = false;
+                self.cursor_x = self.cursor_x.saturating_sub(1);
+            }
+            '\t' => {
+                self.wrap_pending = false;
+                self.cursor_x = ((self.cursor_x / TAB_WIDTH + 1) * TAB_WIDTH).min(self.cols - 1);
+            }
+            c if c.is_control() => {}
+            c => self.print(c),
+        }
+    }
+
+    fn escape(&mut self, ch: char) {
+        self.state = ParseState::Ground;
+        match ch {
+            '[' => {
+                self.params.clear();
+                self.state = ParseState::Csi;
+            }
+            ']' => self.state = ParseState::Osc,
+            '(' | ')' | '*' | '+' | '#' => self.state = ParseState::Charset,
+            '7' => self.save_cursor(),
+            '8' => self.restore_cursor(),
+            'D' => self.line_feed(),
+            'E' => {
+                self.carriage_return();
+                self.line_feed();
+            }
+            'M' => self.reverse_index(),
+            'c' => *self = Screen::new(self.cols, self.rows),
+            _ => {}
+        }
+    }
+
+    fn print(&mut self, ch: char) {
+        if self.wrap_pending {
+            self.carriage_return();
+            self.line_feed();
+        }
+        let index = self.cursor_y * self.cols + self.cursor_x;
+        self.cells[index] = Cell { ch, ..self.pen };
+        if self.cursor_x + 1 < self.cols {
+            self.cursor_x += 1;
+        } else {
+            self.wrap_pending = true;
+        }
+    }
+
+    fn carriage_return(&mut self) {
+        self.cursor_x = 0;
+        self.wrap_pending = false;
+    }
+
+    fn line_feed(&mut self) {
+        self.wrap_pending = false;
+        if self.cursor_y == self.scroll_bottom {
+            // Full-screen programs on the alternate screen do not fill the scrollback
+            let keep = self.scroll_top == 0 && self.main_screen.is_none();
+            self.shift_up(self.scroll_top, 1, keep);
+        } else if self.cursor_y + 1 < self.rows {
+            self.cursor_y += 1;
+        }
+    }
+
+    fn reverse_index(&mut self) {
+        if self.cursor_y == self.scroll_top {
+            self.shift_down(self.scroll_top, 1);
+        } else {
+            self.cursor_y = self.cursor_y.saturating_sub(1);
+        }
+    }
+
+    fn push_scrollback(&mut self, line: Vec<Cell>) {
+        self.scrollback.push_back(line);
+        if self.scrollback.len() > SCROLLBACK_LINES {
+            self.scrollback.pop_front();
+        }
+    }
+
+    fn blank(&self) -> Cell {
+        // Erased cells keep the current background, like xterm
+        Cell { bg: self.pen.bg, ..Cell::default() }
+    }
+
+    // Move the lines from `top` to the bottom of the scroll region up by `n`.
+    // Lines leaving the top of the screen go to the scrollback if `keep` is set.
+    fn shift_up(&mut self, top: usize, n: usize, keep: bool) {
+        let bottom = self.scroll_bottom;
+        let n = n.min(bottom + 1 - top);
+        if keep {
+            for y in top..top + n {
+                let line = self.cells[y * self.cols..(y + 1) * self.cols].to_vec();
+                self.push_scrollback(line);
+            }
+        }
+        self.cells.copy_within((top + n) * self.cols..(bottom + 1) * self.cols, top * self.cols);
+        let blank = self.blank();
+        self.cells[(bottom + 1 - n) * self.cols..(bottom + 1) * self.cols].fill(blank);
+    }
+
+    fn shift_down(&mut self, top: usize, n: usize) {
+        let bottom = self.scroll_bottom;
+        let n = n.min(bottom + 1 - top);
+        self.cells.copy_within(top * self.cols..(bottom + 1 - n) * self.cols, (top + n) * self.cols);
+        let blank = self.blank();
+        self.cells[top * self.cols..(top + n) * self.cols].fill(blank);
+    }
+
+    fn save_cursor(&mut self) {
+        self.saved = SavedCursor { x: self.cursor_x, y: self.cursor_y, pen: self.pen };
+    }
+
+    fn restore_cursor(&mut self) {
+        self.cursor_x = self.saved.x.min(self.cols - 1);
+        self.cursor_y = self.saved.y.min(self.rows - 1);
+        self.pen = self.saved.pen;
+        self.wrap_pending = false;
+    }
+
+    fn erase(&mut self, from: usize, to: usize) {
+        let blank = self.blank();
+        let to = to.min(self.cells.len());
+        if from < to {
+            self.cells[from..to].fill(blank);
+        }
+    }
+
+    fn set_alternate_screen(&mut self, on: bool) {
+        if on && self.main_screen.is_none() {
+            self.save_cursor();
+            let blank = vec![Cell::default(); self.cols * self.rows];
+            self.main_screen = Some((std::mem::replace(&mut self.cells, blank), self.saved));
+        } else if !on {
+            if let Some((cells, saved)) = self.main_screen.take() {
+                self.cells = cells;
+                self.saved = saved;
+                self.restore_cursor();
+            }
+        }
+    }
+
+    fn csi(&mut self, params: &str, action: char) {
+        let private = params.starts_with('?');
+        let args: Vec<usize> = params
+            .trim_start_matches(['?', '>', '='])
+            .split(';')
+            .map(|p| p.parse::<usize>().unwrap_or(0).min(MAX_PARAM))
+            .collect();
+        let arg = |i: usize, default: usize| match args.get(i) {
+            Some(&0) | None => default,
+            Some(&v) => v,
+        };
+        let row_start = self.cursor_y * self.cols;
+        self.wrap_pending = false;
+        match action {
+            'A' => {
+                let limit = if self.cursor_y >= self.scroll_top { self.scroll_top } else { 0 };
+                self.cursor_y = self.cursor_y.saturating_sub(arg(0, 1)).max(limit);
+            }
+            'B' | 'e' => {
+                let limit = if self.cursor_y <= self.scroll_bottom { self.scroll_bottom } else { self.rows - 1 };
+                self.cursor_y = (self.cursor_y + arg(0, 1)).min(limit);
+            }
+            'C' | 'a' => self.cursor_x = (self.cursor_x + arg(0, 1)).min(self.cols - 1),
+            'D' => self.cursor_x = self.cursor_x.saturating_sub(arg(0, 1)),
+            'E' => {
+                self.cursor_y = (self.cursor_y + arg(0, 1)).min(self.rows - 1);
+                self.cursor_x = 0;
+            }
+            'F' => {
+                self.cursor_y = self.cursor_y.saturating_sub(arg(0, 1));
+                self.cursor_x = 0;
+            }
+            'G' | '`' => self.cursor_x = (arg(0, 1) - 1).min(self.cols - 1),
+            'd' => self.cursor_y = (arg(0, 1) - 1).min(self.rows - 1),
+            'H' | 'f' => {
+                self.cursor_y = (arg(0, 1) - 1).min(self.rows - 1);
+                self.cursor_x = (arg(1, 1) - 1).min(self.cols - 1);
+            }
+            'J' => match args.first().copied().unwrap_or(0) {
+                0 => self.erase(row_start + self.cursor_x, self.cells.len()),
+                1 => self.erase(0, row_start + self.cursor_x + 1),
+                2 => self.erase(0, self.cells.len()),
+                3 => self.scrollback.clear(),
+                _ => {}
+            },
+            'K' => match args.first().copied().unwrap_or(0) {
+                0 => self.erase(row_start + self.cursor_x, row_start + self.cols),
+                1 => self.erase(row_start, row_start + self.cursor_x + 1),
+                2 => self.erase(row_start, row_start + self.cols),
+                _ => {}
+            },
+            'X' => self.erase(row_start + self.cursor_x, row_start + (self.cursor_x + arg(0, 1)).min(self.cols)),
+            '@' => {
+                let n = arg(0, 1).min(self.cols - self.cursor_x);
+                self.cells.copy_within(row_start + self.cursor_x..row_start + self.cols - n, row_start + self.cursor_x + n);
+                self.erase(row_start + self.cursor_x, row_start + self.cursor_x + n);
+            }
+            'P' => {
+                let n = arg(0, 1).min(self.cols - self.cursor_x);
+                self.cells.copy_within(row_start + self.cursor_x + n..row_start + self.cols, row_start + self.cursor_x);
+                self.erase(row_start + self.cols - n, row_start + self.cols);
+            }
+            // Insert/delete lines work on the part of the scroll region below the cursor
+            'L' if (self.scroll_top..=self.scroll_bottom).contains(&self.cursor_y) => {
+                self.shift_down(self.cursor_y, arg(0, 1));
+                self.cursor_x = 0;
+            }
+            'M' if (self.scroll_top..=self.scroll_bottom).contains(&self.cursor_y) => {
+                self.shift_up(self.cursor_y, arg(0, 1), false);
+                self.cursor_x = 0;
+            }
+            'S' => self.shift_up(self.scroll_top, arg(0, 1), false),
+            'T' => self.shift_down(self.scroll_top, arg(0, 1)),
+            'm' => self.sgr(&args),
+            'r' if !private => {
+                let top = arg(0, 1) - 1;
+                let bottom = arg(1, self.rows).min(self.rows) - 1;
+                if top < bottom {
+                    self.scroll_top = top;
+                    self.scroll_bottom = bottom;
+                    self.cursor_x = 0;
+                    self.cursor_y = 0;
+                }
+            }
+            's' if !private => self.save_cursor(),
+            'u' if !private => self.restore_cursor(),
+            'h' | 'l' if private => {
+                let on = action == 'h';
+                for mode in &args {
+                    match mode {
+                        1 => self.app_cursor_keys = on,
+                        25 => self.cursor_visible = on,
+                        47 | 1047 | 1049 => self.set_alternate_screen(on),
+                        _ => {}
+                    }
+                }
+            }
+            'n' if args.first() == Some(&6) => {
+                let reply = format!("\x1b[{};{}R", self.cursor_y + 1, self.cursor_x + 1);
+                self.responses.extend_from_slice(reply.as_bytes());
+            }
+            'n' if args.first() == Some(&5) => self.responses.extend_from_slice(b"\x1b[0n"),
+            'c' if !private => self.responses.extend_from_slice(b"\x1b[?1;2c"),
+            _ => {}
+        }
+    }
+
+    fn sgr(&mut self, args: &[usize]) {
+        let mut i = 0;
+        while i < args.len() {
+            match args[i] {
+                0 => self.pen = Cell::default(),
+                1 => self.pen.bold = true,
+                4 => self.pen.underline = true,
+                7 => self.pen.inverse = true,
+                21 | 22 => self.pen.bold = false,
+                24 => self.pen.underline = false,
+                27 => self.pen.inverse = false,
+                n @ 30..=37 => self.pen.fg = TermColor::Indexed((n - 30) as u8),
+                39 => self.pen.fg = TermColor::Default,
+                n @ 40..=47 => self.pen.bg = TermColor::Indexed((n - 40) as u8),
+                49 => self.pen.bg = TermColor::Default,
+                n @ 90..=97 => self.pen.fg = TermColor::Indexed((n - 90 + 8) as u8),
+                n @ 100..=107 => self.pen.bg = TermColor::Indexed((n - 100 + 8) as u8),
+                n @ (38 | 48) => {
+                    // 38;5;n or 38;2;r;g;b, same for the background with 48
+                    let color = match args.get(i + 1) {
+                        Some(5) => {
+                            let color = args.get(i + 2).map(|&c| TermColor::Indexed(c.min(255) as u8));
+                            i += 2;
+                            color
+                        }
+                        Some(2) => {
+                            let channel = |k: usize| args.get(i + k).copied().unwrap_or(0).min(255) as u8;
+                            let color = TermColor::Rgb(channel(2), channel(3), channel(4));
+                            i += 4;
+                            Some(color)
+                        }
+                        _ => None,
+                    };
+                    if let Some(color) = color {
+                        if n == 38 {
+                            self.pen.fg = color;
+                        } else {
+                            self.pen.bg = color;
+                        }
+                    }
+                }
+                _ => {}
+            }
+            i += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row_text(screen: &Screen, row: usize) -> String {
+        screen.visible_row(row).iter().map(|cell| cell.ch).collect::<String>().trim_end().to_string()
+    }
+
+    #[test]
+    fn moves_the_cursor() {
+        let mut screen = Screen::new(20, 5);
+        screen.feed("\x1b[3;5H");
+        assert_eq!(screen.cursor(), (4, 2));
+        screen.feed("\x1b[2A\x1b[3C");
+        assert_eq!(screen.cursor(), (7, 0));
+        screen.feed("\x1b[H");
+        assert_eq!(screen.cursor(), (0, 0));
+    }
+
+    #[test]
+    fn huge_parameters_stay_on_the_screen() {
+        let mut screen = Screen::new(20, 5);
+        screen.feed("\x1b[2;2H\x1b[18446744073709551615B\x1b[18446744073709551615C");
+        assert_eq!(screen.cursor(), (19, 4));
+        screen.feed("\x1b[18446744073709551615E");
+        assert_eq!(screen.cursor(), (0, 4));
+        screen.feed("\x1b[2;2H\x1b[18446744073709551615X\x1b[18446744073709551615@");
+        assert_eq!(screen.cursor(), (1, 1));
+    }
+
+    #[test]
+    fn erases_and_deletes_characters() {
+        let mut screen = Screen::new(20, 5);
+        screen.feed("hello world\x1b[1;6H\x1b[K");
+        assert_eq!(row_text(&screen, 0), "hello");
+        screen.feed("\x1b[1;1H\x1b[2P");
+        assert_eq!(row_text(&screen, 0), "llo");
+        screen.feed("\x1b[2@");
+        assert_eq!(row_text(&screen, 0), "  llo");
+    }
+
+    #[test]
+    fn answers_cursor_position_reports() {
+        let mut screen = Screen::new(20, 5);
+        screen.feed("\x1b[2;3H\x1b[6n");
+        assert_eq!(screen.take_responses(), b"\x1b[2;3R");
+    }
+}
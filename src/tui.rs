@@ -0,0 +1,1106 @@
+use anyhow::Result;
+use log::{error, info};
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Clear, Gauge, List, ListItem, ListState, Paragraph, Wrap};
+use ratatui::{DefaultTerminal, Frame};
+use std::collections::VecDeque;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::{config, config_dir};
+use crate::flasher::{self, FlashPhase, Progress};
+use crate::history::Operation;
+use crate::recipe::{self, Recipe};
+use crate::session::DeviceSession;
+use crate::session_log::SessionFile;
+use crate::shell::{self, Shell, ShellEvent};
+use crate::terminal::{Cell, Screen, TermColor};
+use crate::vault::{self, Vault};
+
+// Lines kept in the log pane
+const MAX_LOG_LINES: usize = 5000;
+// The screen is redrawn this often while no key is pressed
+const TICK: Duration = Duration::from_millis(100);
+// Where the log macros write while the TUI owns the terminal
+const LOG_FILE: &str = "tui.log";
+const LOG_PAGE: usize = 10;
+
+// Something to start on the device. Also what is retried once a password was entered.
+#[derive(Debug, Clone)]
+enum Task {
+    Identify,
+    Flash(PathBuf),
+    Reset,
+    Console,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    Host,
+    Port,
+}
+
+#[derive(Debug, Clone)]
+struct PickerEntry {
+    name: String,
+    path: PathBuf,
+    is_dir: bool,
+}
+
+// What has the keyboard: the main screen or one of the dialogs on top of it
+enum Mode {
+    Main,
+    // Folders and the firmware files that fit the device, in `dir`
+    Picker { dir: PathBuf, entries: Vec<PickerEntry>, list: ListState },
+    ConfirmReset,
+    // Quitting while a flash or reset runs, dropping the connection can brick the unit
+    ConfirmQuit,
+    Password { input: String, retry: Task },
+    // Master passphrase of the credential vault, asked at startup
+    Unlock { input: String },
+    Console,
+}
+
+// Where the last flash got to, for the progress pane
+#[derive(Debug, Default)]
+struct FlashView {
+    phase: Option<FlashPhase>,
+    sent: usize,
+    total: usize,
+    // Set when the flash is over
+    succeeded: Option<bool>,
+}
+
+struct State {
+    host: String,
+    port: String,
+    field: Field,
+    soc: String,
+    password: Option<String>,
+    vault: Option<Vault>,
+    config: config::Config,
+    // Index into the recent devices while browsing them with up/down
+    recent: Option<usize>,
+    // Connection to the last device used, shared by every operation on it
+    session: Option<Arc<DeviceSession>>,
+    recipes: Vec<Recipe>,
+    recipe: usize,
+    // The operation running now, one at a time like the GUI's buttons
+    busy: Option<&'static str>,
+    flash: FlashView,
+    log: VecDeque<String>,
+    // Lines scrolled back from the end of the log
+    scroll: usize,
+    session_file: Option<SessionFile>,
+    mode: Mode,
+    // Interactive shell of the console and what it shows
+    shell: Option<Shell>,
+    screen: Screen,
+    // Size of the console's screen as last drawn
+    screen_size: (u16, u16),
+    firmware_dir: PathBuf,
+    quit: bool,
+}
+
+impl State {
+    fn new() -> State {
+        let config = config::Config::load();
+        let recipes = recipe::load_all();
+        let builtin = Recipe::builtin();
+        let session_file = match SessionFile::create() {
+            Ok(file) => Some(file),
+            Err(e) => {
+                error!("error: {:?}", e);
+                None
+            }
+        };
+        let mut state = State {
+            host: String::new(),
+            port: "22".to_string(),
+            field: Field::Host,
+            soc: String::new(),
+            password: None,
+            vault: None,
+            recent: None,
+            session: None,
+            recipe: recipes.iter().position(|r| r.name == builtin.name).unwrap_or(0),
+            recipes,
+            busy: None,
+            flash: FlashView::default(),
+            log: VecDeque::new(),
+            scroll: 0,
+            session_file,
+            mode: if Vault::exists() { Mode::Unlock { input: String::new() } } else { Mode::Main },
+            shell: None,
+            screen: Screen::new(80, 24),
+            screen_size: (80, 24),
+            firmware_dir: std::env::current_dir().unwrap_or_default(),
+            quit: false,
+            config,
+        };
+        if !state.config.devices.is_empty() {
+            state.select_recent(0);
+        }
+        state
+    }
+
+    fn log(&mut self, line: &str) {
+        info!("{}", line);
+        if let Some(file) = &self.session_file {
+            file.write(&format!("{}\n", line));
+        }
+        for line in line.lines() {
+            self.log.push_back(line.to_string());
+        }
+        while self.log.len() > MAX_LOG_LINES {
+            self.log.pop_front();
+        }
+    }
+
+    fn select_recent(&mut self, index: usize) {
+        if let Some(device) = self.config.devices.get(index).cloned() {
+            self.recent = Some(index);
+            self.host = device.ip;
+            self.port = device.port.to_string();
+            self.password = None;
+            self.soc = device.last_soc.unwrap_or_default();
+        }
+    }
+
+    // The recipe chosen for flashing, the built-in one if the list is empty
+    fn selected_recipe(&self) -> Recipe {
+        self.recipes.get(self.recipe).cloned().unwrap_or_else(Recipe::builtin)
+    }
+
+    // The connection to a device, kept between operations. Switching to another
+    // device closes the previous one.
+    fn device_session(&mut self, host: &str, port: u16) -> Arc<DeviceSession> {
+        let candidates = vault::credentials(self.password.as_deref(), self.vault.as_ref(), host, port).candidates;
+        if let Some(session) = &self.session {
+            if session.host() == host && session.port() == port {
+                session.set_candidates(candidates);
+                return session.clone();
+            }
+        }
+        let session = DeviceSession::new(host, port, flasher::Credentials::new(candidates));
+        if let Some(previous) = self.session.replace(session.clone()) {
+            tokio::spawn(async move { previous.close().await });
+        }
+        session
+    }
+
+    // Mark the device busy and hand out its session, None if something already runs
+    fn begin(&mut self, operation: &'static str) -> Option<(Arc<DeviceSession>, String, u16)> {
+        let (host, port) = self.target()?;
+        self.busy = Some(operation);
+        self.scroll = 0;
+        Some((self.device_session(&host, port), host, port))
+    }
+
+    // The device entered, None while something runs
+    fn target(&mut self) -> Option<(String, u16)> {
+        if let Some(running) = self.busy {
+            self.log(&format!("Busy with {}, wait until it is done.", running));
+            return None;
+        }
+        let port: u16 = match self.port.parse() {
+            Ok(port) => port,
+            Err(e) => {
+                error!("error: {:?}", e);
+                self.log("Error: invalid port specified.");
+                return None;
+            }
+        };
+        if self.host.trim().is_empty() {
+            self.log("Error: enter the device address first.");
+            return None;
+        }
+        Some((self.host.trim().to_string(), port))
+    }
+
+    // Keep the password that worked for the next operation, and in the vault if remembering is on
+    fn store_working_password(&mut self, session: &DeviceSession) {
+        if let Some(password) = session.credentials().working {
+            if let Some(vault) = self.vault.as_mut().filter(|_| self.config.remember_passwords) {
+                vault.keep_password(&vault::device_key(session.host(), session.port()), &password);
+            }
+            self.password = Some(password).filter(|p| p != flasher::DEFAULT_PASSWORD);
+        }
+    }
+
+    // Put the device on top of the recent list and persist it
+    fn remember_device(&mut self, host: &str, port: u16) {
+        let device = config::Device {
+            nickname: String::new(),
+            ip: host.to_string(),
+            port,
+            last_soc: Some(self.soc.clone()).filter(|soc| !soc.is_empty()),
+            auth: match self.password {
+                Some(_) => config::AuthMethod::Password,
+                None => config::AuthMethod::DefaultPassword,
+            },
+        };
+        self.config.remember(device);
+        self.recent = None;
+        if let Err(e) = self.config.save() {
+            error!("Failed to save config: {:?}", e);
+        }
+    }
+
+    // Report a failed task, a rejected password asks for another and retries
+    fn failed(&mut self, e: anyhow::Error, retry: Task) {
+        if flasher::is_auth_error(&e) {
+            self.password = None;
+            self.log("Authentication failed. Please enter the password.");
+            self.mode = Mode::Password { input: String::new(), retry };
+        } else {
+            error!("error: {:?}", e);
+            self.log(&format!("Error: {:#}", e));
+        }
+    }
+
+    fn open_picker(&mut self) {
+        if self.soc.is_empty() {
+            self.log("Identify the SoC first (F2), the firmware is picked by it.");
+            return;
+        }
+        let dir = self.firmware_dir.clone();
+        self.show_folder(dir);
+    }
+
+    fn show_folder(&mut self, dir: PathBuf) {
+        let entries = firmware_entries(&dir, &self.selected_recipe(), &self.soc);
+        let list = ListState::default().with_selected(Some(0));
+        self.mode = Mode::Picker { dir, entries, list };
+    }
+
+    fn on_key(&mut self, key: KeyEvent) -> Option<Task> {
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        // The console takes every key, Ctrl-Q included
+        if matches!(self.mode, Mode::Console) {
+            self.on_console_key(key, ctrl);
+            return None;
+        }
+        if ctrl && key.code == KeyCode::Char('q') {
+            self.request_quit();
+            return None;
+        }
+        match key.code {
+            KeyCode::PageUp => {
+                self.scroll = (self.scroll + LOG_PAGE).min(self.log.len());
+                return None;
+            }
+            KeyCode::PageDown => {
+                self.scroll = self.scroll.saturating_sub(LOG_PAGE);
+                return None;
+            }
+            _ => {}
+        }
+        match std::mem::replace(&mut self.mode, Mode::Main) {
+            Mode::Main => self.on_main_key(key, ctrl),
+            Mode::Picker { dir, entries, list } => self.on_picker_key(key, dir, entries, list),
+            Mode::ConfirmReset => match key.code {
+                KeyCode::Char('y') | KeyCode::Char('Y') => Some(Task::Reset),
+                _ => None,
+            },
+            Mode::ConfirmQuit => {
+                if matches!(key.code, KeyCode::Char('y') | KeyCode::Char('Y')) {
+                    self.quit = true;
+                }
+                None
+            }
+            Mode::Password { mut input, retry } => match key.code {
+                KeyCode::Enter => {
+                    self.password = Some(input).filter(|p| !p.is_empty());
+                    Some(retry)
+                }
+                KeyCode::Esc => None,
+                KeyCode::Backspace => {
+                    input.pop();
+                    self.mode = Mode::Password { input, retry };
+                    None
+                }
+                KeyCode::Char(c) if !ctrl => {
+                    input.push(c);
+                    self.mode = Mode::Password { input, retry };
+                    None
+                }
+                _ => {
+                    self.mode = Mode::Password { input, retry };
+                    None
+                }
+            },
+            Mode::Unlock { mut input } => {
+                match key.code {
+                    KeyCode::Enter => self.unlock_vault(input),
+                    KeyCode::Esc => self.log("The credential vault stays locked, its passwords are not tried."),
+                    KeyCode::Backspace => {
+                        input.pop();
+                        self.mode = Mode::Unlock { input };
+                    }
+                    KeyCode::Char(c) if !ctrl => {
+                        input.push(c);
+                        self.mode = Mode::Unlock { input };
+                    }
+                    _ => self.mode = Mode::Unlock { input },
+                }
+                None
+            }
+            Mode::Console => None,
+        }
+    }
+
+    fn unlock_vault(&mut self, passphrase: String) {
+        match Vault::open(&passphrase) {
+            Ok(vault) => {
+                self.vault = Some(vault);
+                self.log("Credential vault unlocked.");
+            }
+            Err(e) => {
+                error!("error: {:?}", e);
+                self.log(&format!("Could not unlock the vault: {}", e));
+                self.mode = Mode::Unlock { input: String::new() };
+            }
+        }
+    }
+
+    fn on_main_key(&mut self, key: KeyEvent, ctrl: bool) -> Option<Task> {
+        match key.code {
+            KeyCode::Char('c') if ctrl => self.request_quit(),
+            KeyCode::Enter | KeyCode::F(2) => return Some(Task::Identify),
+            KeyCode::F(3) => self.open_picker(),
+            KeyCode::F(4) => self.mode = Mode::ConfirmReset,
+            KeyCode::F(5) => return Some(Task::Console),
+            KeyCode::F(6) if !self.recipes.is_empty() => {
+                self.recipe = (self.recipe + 1) % self.recipes.len();
+                let recipe = self.selected_recipe();
+                self.log(&format!("Recipe: {} {}", recipe.name, recipe.description));
+            }
+            KeyCode::Tab | KeyCode::BackTab => {
+                self.field = if self.field == Field::Host { Field::Port } else { Field::Host };
+            }
+            // Browse the recent devices, oldest last
+            KeyCode::Up | KeyCode::Down if self.field == Field::Host && !self.config.devices.is_empty() => {
+                let last = self.config.devices.len() - 1;
+                let index = match (key.code, self.recent) {
+                    (KeyCode::Down, None) => 0,
+                    (KeyCode::Down, Some(i)) => (i + 1).min(last),
+                    (_, None) => last,
+                    (_, Some(i)) => i.saturating_sub(1),
+                };
+                self.select_recent(index);
+            }
+            KeyCode::Backspace => {
+                self.field_mut().pop();
+                self.device_changed();
+            }
+            KeyCode::Char(c) if !ctrl => {
+                if self.field == Field::Port && !c.is_ascii_digit() {
+                    return None;
+                }
+                self.field_mut().push(c);
+                self.device_changed();
+            }
+            _ => {}
+        }
+        None
+    }
+
+    // The console's shell is simply closed, anything else running is confirmed first
+    fn request_quit(&mut self) {
+        match self.busy {
+            None => self.quit = true,
+            Some(_) => self.mode = Mode::ConfirmQuit,
+        }
+    }
+
+    fn field_mut(&mut self) -> &mut String {
+        match self.field {
+            Field::Host => &mut self.host,
+            Field::Port => &mut self.port,
+        }
+    }
+
+    // A typed address is another device, its SoC is not known yet
+    fn device_changed(&mut self) {
+        self.recent = None;
+        self.soc.clear();
+        self.password = None;
+    }
+
+    fn on_picker_key(&mut self, key: KeyEvent, dir: PathBuf, entries: Vec<PickerEntry>, mut list: ListState) -> Option<Task> {
+        let selected = list.selected().unwrap_or(0);
+        match key.code {
+            KeyCode::Esc => return None,
+            KeyCode::Up => list.select(Some(selected.saturating_sub(1))),
+            KeyCode::Down => list.select(Some((selected + 1).min(entries.len().saturating_sub(1)))),
+            KeyCode::Backspace | KeyCode::Left => {
+                if let Some(parent) = dir.parent() {
+                    self.show_folder(parent.to_path_buf());
+                    return None;
+                }
+            }
+            KeyCode::Enter => match entries.get(selected) {
+                Some(entry) if entry.is_dir => {
+                    self.show_folder(entry.path.clone());
+                    return None;
+                }
+                Some(entry) => {
+                    self.firmware_dir = dir;
+                    return Some(Task::Flash(entry.path.clone()));
+                }
+                None => {}
+            },
+            _ => {}
+        }
+        self.mode = Mode::Picker { dir, entries, list };
+        None
+    }
+
+    // Keys go to the shell like from a terminal, so full screen programs work. Only
+    // Shift-PgUp/PgDn scroll back and Ctrl-] leaves.
+    fn on_console_key(&mut self, key: KeyEvent, ctrl: bool) {
+        let shift = key.modifiers.contains(KeyModifiers::SHIFT);
+        match key.code {
+            KeyCode::PageUp if shift => self.screen.scroll_view(LOG_PAGE as isize),
+            KeyCode::PageDown if shift => self.screen.scroll_view(-(LOG_PAGE as isize)),
+            // Terminals without keyboard enhancements report Ctrl-] as Ctrl-5
+            KeyCode::Char(']') | KeyCode::Char('5') if ctrl => {
+                self.close_shell();
+                self.mode = Mode::Main;
+            }
+            KeyCode::Char('c') if ctrl => {
+                if let Some(shell) = &self.shell {
+                    shell.interrupt();
+                }
+            }
+            _ => {
+                if let (Some(shell), Some(bytes)) = (&self.shell, key_bytes(key, self.screen.app_cursor_keys)) {
+                    shell.send(&bytes);
+                }
+            }
+        }
+    }
+
+    fn close_shell(&mut self) {
+        if let Some(shell) = self.shell.take() {
+            shell.close();
+        }
+    }
+}
+
+// Folders first, then the files the recipe accepts for this SoC
+fn firmware_entries(dir: &Path, recipe: &Recipe, soc: &str) -> Vec<PickerEntry> {
+    let mut folders = Vec::new();
+    let mut files = Vec::new();
+    if let Some(parent) = dir.parent() {
+        folders.push(PickerEntry { name: "..".to_string(), path: parent.to_path_buf(), is_dir: true });
+    }
+    let mut found: Vec<(String, PathBuf, bool)> = match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .flatten()
+            .map(|entry| (entry.file_name().to_string_lossy().to_string(), entry.path(), entry.path().is_dir()))
+            .filter(|(name, _, _)| !name.starts_with('.'))
+            .collect(),
+        Err(e) => {
+            error!("Failed to read {}: {:?}", dir.display(), e);
+            Vec::new()
+        }
+    };
+    found.sort_by_key(|(name, _, _)| name.to_lowercase());
+    for (name, path, is_dir) in found {
+        if is_dir {
+            folders.push(PickerEntry { name: format!("{}/", name), path, is_dir });
+        } else if recipe.matches_firmware(&name, soc) {
+            files.push(PickerEntry { name, path, is_dir });
+        }
+    }
+    folders.extend(files);
+    folders
+}
+
+fn log_line(state: &Arc<Mutex<State>>, line: &str) {
+    state.lock().unwrap().log(line);
+}
+
+fn report_progress(state: &Arc<Mutex<State>>, progress: Progress) {
+    let flash = &mut state.lock().unwrap().flash;
+    match progress {
+        Progress::Phase(phase) => flash.phase = Some(phase),
+        Progress::Upload { sent, total } => {
+            flash.sent = sent;
+            flash.total = total;
+        }
+    }
+}
+
+fn start(state: &Arc<Mutex<State>>, task: Task) {
+    match task {
+        Task::Identify => identify(state),
+        Task::Flash(firmware) => flash(state, firmware),
+        Task::Reset => reset(state),
+        Task::Console => open_console(state),
+    }
+}
+
+fn identify(state: &Arc<Mutex<State>>) {
+    let (session, host, port) = match state.lock().unwrap().begin("identify") {
+        Some(target) => target,
+        None => return,
+    };
+    let state = state.clone();
+    tokio::spawn(async move {
+        let mut operation = Operation::new("detect", &host, port);
+        let result = flasher::detect_soc(&session, |msg| {
+            operation.log(msg);
+            log_line(&state, msg);
+        })
+        .await;
+        if let Ok(soc) = &result {
+            operation.set_soc(soc);
+        }
+        operation.finish(result.as_ref().err());
+        let mut state = state.lock().unwrap();
+        state.busy = None;
+        match result {
+            Ok(soc) => {
+                state.store_working_password(&session);
+                state.log(&format!("SoC: {}. Press F3 to pick the firmware.", soc));
+                state.soc = soc;
+                state.remember_device(&host, port);
+            }
+            Err(e) => state.failed(e, Task::Identify),
+        }
+    });
+}
+
+fn flash(state: &Arc<Mutex<State>>, firmware: PathBuf) {
+    let (session, host, port, recipe) = {
+        let mut state = state.lock().unwrap();
+        let (session, host, port) = match state.begin("flash") {
+            Some(target) => target,
+            None => return,
+        };
+        state.flash = FlashView::default();
+        (session, host, port, state.selected_recipe())
+    };
+    let state = state.clone();
+    tokio::spawn(async move {
+        let path = firmware.to_string_lossy().to_string();
+        let mut operation = Operation::new("flash", &host, port);
+        operation.set_firmware(&path);
+        let result: Result<()> = async {
+            let unit = flasher::flash(&session, &recipe, &path, |msg| {
+                operation.log(msg);
+                log_line(&state, msg);
+            }, |p| report_progress(&state, p))
+            .await?;
+            operation.set_unit_before(&unit);
+            state.lock().unwrap().store_working_password(&session);
+            if recipe.reboot {
+                log_line(&state, "The device is rebooting now, do not disconnect power until it is back.");
+                let after = flasher::wait_for_reboot(&session, &unit.soc, |msg| {
+                    operation.log(msg);
+                    log_line(&state, msg);
+                }, |p| report_progress(&state, p))
+                .await?;
+                operation.set_unit_after(&after);
+            }
+            Ok(())
+        }
+        .await;
+        operation.finish(result.as_ref().err());
+        let mut state = state.lock().unwrap();
+        state.busy = None;
+        state.flash.succeeded = Some(result.is_ok());
+        match result {
+            Ok(()) => {
+                state.store_working_password(&session);
+                state.log(if recipe.reboot {
+                    "The device is back online, the firmware flash is completed."
+                } else {
+                    "The firmware flash is completed."
+                });
+            }
+            Err(e) => state.failed(e, Task::Flash(firmware)),
+        }
+    });
+}
+
+fn reset(state: &Arc<Mutex<State>>) {
+    let (session, host, port) = match state.lock().unwrap().begin("reset") {
+        Some(target) => target,
+        None => return,
+    };
+    let state = state.clone();
+    tokio::spawn(async move {
+        let mut operation = Operation::new("reset", &host, port);
+        let result = flasher::reset_device(&session, |msg| {
+            operation.log(msg);
+            log_line(&state, msg);
+        })
+        .await;
+        operation.finish(result.as_ref().err());
+        // firstboot reboots the device
+        session.close().await;
+        let mut state = state.lock().unwrap();
+        state.busy = None;
+        match result {
+            Ok(()) => {
+                state.store_working_password(&session);
+                state.log("The device is resetting. It needs 2-3 minutes to initialize, do not disconnect power.");
+            }
+            Err(e) => state.failed(e, Task::Reset),
+        }
+    });
+}
+
+// Open a login shell on the device, its screen takes the place of the log
+fn open_console(state: &Arc<Mutex<State>>) {
+    let (session, host, port, (cols, rows)) = {
+        let mut state = state.lock().unwrap();
+        let (host, port) = match state.target() {
+            Some(target) => target,
+            None => return,
+        };
+        state.close_shell();
+        state.screen = Screen::new(state.screen_size.0 as usize, state.screen_size.1 as usize);
+        state.mode = Mode::Console;
+        (state.device_session(&host, port), host, port, state.screen_size)
+    };
+    log_line(state, &format!("Opening a shell on {}:{}...", host, port));
+    let state = state.clone();
+    tokio::spawn(async move {
+        let output = state.clone();
+        let result = shell::open(&session, shell::LOGIN_SHELL, cols as u32, rows as u32, move |event| {
+            let mut state = output.lock().unwrap();
+            match event {
+                ShellEvent::Output(text) => {
+                    state.screen.feed(&text);
+                    let responses = state.screen.take_responses();
+                    if let Some(shell) = state.shell.as_ref().filter(|_| !responses.is_empty()) {
+                        shell.send(&responses);
+                    }
+                }
+                ShellEvent::Closed(exit_status) => {
+                    state.shell = None;
+                    match exit_status {
+                        Some(code) => state.log(&format!("Shell closed (exit status {}).", code)),
+                        None => state.log("Shell closed."),
+                    }
+                    if matches!(state.mode, Mode::Console) {
+                        state.mode = Mode::Main;
+                    }
+                }
+            }
+        })
+        .await;
+        let mut state = state.lock().unwrap();
+        match result {
+            // The console may have been left while connecting
+            Ok(shell) if !matches!(state.mode, Mode::Console) => shell.close(),
+            Ok(shell) => {
+                state.store_working_password(&session);
+                state.shell = Some(shell);
+            }
+            Err(e) => {
+                state.mode = Mode::Main;
+                state.failed(e, Task::Console);
+            }
+        }
+    });
+}
+
+fn centered(area: Rect, width: u16, height: u16) -> Rect {
+    let width = width.min(area.width);
+    let height = height.min(area.height);
+    Rect::new(area.x + (area.width - width) / 2, area.y + (area.height - height) / 2, width, height)
+}
+
+fn field_span(value: &str, focused: bool) -> Span<'static> {
+    if focused {
+        Span::styled(format!("{}_", value), Style::new().add_modifier(Modifier::REVERSED))
+    } else {
+        Span::raw(value.to_string())
+    }
+}
+
+fn log_style(line: &str) -> Style {
+    if line.starts_with("Error") || line.starts_with("stderr:") {
+        Style::new().fg(Color::Red)
+    } else if line.starts_with("warning:") || line.starts_with("Authentication failed") {
+        Style::new().fg(Color::Yellow)
+    } else if line.starts_with("# ") {
+        Style::new().fg(Color::Cyan)
+    } else if line.contains("completed") {
+        Style::new().fg(Color::Green)
+    } else {
+        Style::new()
+    }
+}
+
+fn draw(frame: &mut Frame, state: &mut State) {
+    let [device_area, progress_area, log_area, footer_area] = Layout::vertical([
+        Constraint::Length(3),
+        Constraint::Length(4),
+        Constraint::Min(5),
+        Constraint::Length(1),
+    ])
+    .areas(frame.area());
+
+    let mut device = vec![
+        Span::raw("Host: "),
+        field_span(&state.host, state.field == Field::Host && matches!(state.mode, Mode::Main)),
+        Span::raw("  Port: "),
+        field_span(&state.port, state.field == Field::Port && matches!(state.mode, Mode::Main)),
+        Span::raw("  SoC: "),
+        Span::styled(
+            if state.soc.is_empty() { "-".to_string() } else { state.soc.clone() },
+            Style::new().add_modifier(Modifier::BOLD),
+        ),
+        Span::raw("  Recipe: "),
+        Span::raw(state.selected_recipe().name),
+    ];
+    if let Some(running) = state.busy {
+        device.push(Span::styled(format!("  [{}...]", running), Style::new().fg(Color::Yellow)));
+    }
+    let mut block = Block::bordered().title(" RubyFPV Flasher ");
+    // The recent device being browsed, with its nickname
+    if let Some(recent) = state.recent.and_then(|index| state.config.devices.get(index)) {
+        block = block.title_bottom(format!(" Recent: {} ", recent.label()));
+    }
+    frame.render_widget(Paragraph::new(Line::from(device)).block(block), device_area);
+
+    draw_progress(frame, progress_area, &state.flash);
+
+    if matches!(state.mode, Mode::Console) {
+        draw_screen(frame, log_area, state);
+    } else {
+        let height = log_area.height.saturating_sub(2) as usize;
+        let end = state.log.len().saturating_sub(state.scroll);
+        let lines: Vec<Line> = state
+            .log
+            .iter()
+            .take(end)
+            .skip(end.saturating_sub(height))
+            .map(|line| Line::styled(line.clone(), log_style(line)))
+            .collect();
+        let title = if state.scroll > 0 { format!(" Log (scrolled back {} lines) ", state.scroll) } else { " Log ".to_string() };
+        frame.render_widget(Paragraph::new(lines).block(Block::bordered().title(title)), log_area);
+    }
+
+    let keys = match &state.mode {
+        Mode::Main => "Enter/F2 Identify SoC  F3 Flash  F4 Reset  F5 Console  F6 Recipe  Tab Field  Up/Down Recent  PgUp/PgDn Log  Ctrl-Q Quit",
+        Mode::Console => "Keys go to the shell  Shift-PgUp/PgDn Scroll  Ctrl-] Close shell",
+        Mode::Picker { .. } => "Up/Down Select  Enter Open/Flash  Backspace Parent folder  Esc Cancel",
+        Mode::ConfirmReset => "Y Reset  any other key Cancel",
+        Mode::ConfirmQuit => "Y Quit anyway  any other key Keep running",
+        Mode::Password { .. } => "Enter Retry  Esc Cancel",
+        Mode::Unlock { .. } => "Enter Unlock  Esc Skip",
+    };
+    frame.render_widget(Paragraph::new(keys).style(Style::new().fg(Color::DarkGray)), footer_area);
+
+    let pattern = state.selected_recipe().firmware_pattern(&state.soc);
+    match &mut state.mode {
+        Mode::Picker { dir, entries, list } => {
+            let area = centered(frame.area(), frame.area().width * 4 / 5, frame.area().height * 3 / 4);
+            let items: Vec<ListItem> = entries
+                .iter()
+                .map(|entry| {
+                    let style = if entry.is_dir { Style::new().fg(Color::Blue) } else { Style::new() };
+                    ListItem::new(Line::styled(entry.name.clone(), style))
+                })
+                .collect();
+            let title = format!(" {} in {} ", pattern, dir.display());
+            let picker = List::new(items)
+                .block(Block::bordered().title(title))
+                .highlight_style(Style::new().add_modifier(Modifier::REVERSED));
+            frame.render_widget(Clear, area);
+            frame.render_stateful_widget(picker, area, list);
+        }
+        Mode::ConfirmReset => {
+            let area = centered(frame.area(), 64, 9);
+            let text = "Are you sure you want to reset the device?\n\n\
+                        This will clear all settings from the device and make it appear as newly flashed. \
+                        It needs 2-3 minutes to initialize and must not be disconnected from power meanwhile.";
+            frame.render_widget(Clear, area);
+            frame.render_widget(
+                Paragraph::new(text).wrap(Wrap { trim: true }).block(Block::bordered().title(" Reset device ")),
+                area,
+            );
+        }
+        Mode::ConfirmQuit => {
+            let area = centered(frame.area(), 64, 8);
+            let text = format!(
+                "The {} is still running.\n\n\
+                 Quitting now drops the connection to the device. If it is in the middle of \
+                 writing the firmware it may not boot again. Quit anyway?",
+                state.busy.unwrap_or("operation")
+            );
+            frame.render_widget(Clear, area);
+            frame.render_widget(
+                Paragraph::new(text).wrap(Wrap { trim: true }).block(Block::bordered().title(" Quit ")),
+                area,
+            );
+        }
+        Mode::Password { input, .. } => {
+            let area = centered(frame.area(), 48, 3);
+            let masked = "*".repeat(input.chars().count());
+            frame.render_widget(Clear, area);
+            frame.render_widget(
+                Paragraph::new(Line::from(field_span(&masked, true))).block(Block::bordered().title(" Device password ")),
+                area,
+            );
+        }
+        Mode::Unlock { input } => {
+            let area = centered(frame.area(), 56, 3);
+            let masked = "*".repeat(input.chars().count());
+            frame.render_widget(Clear, area);
+            frame.render_widget(
+                Paragraph::new(Line::from(field_span(&masked, true)))
+                    .block(Block::bordered().title(" Master passphrase of the credential vault ")),
+                area,
+            );
+        }
+        Mode::Main | Mode::Console => {}
+    }
+}
+
+// The shell's screen, sized to the area it is drawn in
+fn draw_screen(frame: &mut Frame, area: Rect, state: &mut State) {
+    let title = match (&state.shell, state.screen.view_offset()) {
+        (None, _) => " Shell (connecting) ".to_string(),
+        (Some(_), 0) => " Shell ".to_string(),
+        (Some(_), offset) => format!(" Shell (scrolled back {} lines) ", offset),
+    };
+    let block = Block::bordered().title(title);
+    let inner = block.inner(area);
+    let size = (inner.width.max(1), inner.height.max(1));
+    if size != state.screen_size {
+        state.screen_size = size;
+        state.screen.resize(size.0 as usize, size.1 as usize);
+        if let Some(shell) = &state.shell {
+            shell.resize(size.0 as u32, size.1 as u32);
+        }
+    }
+    let (cols, rows) = state.screen.size();
+    let cursor = Some(state.screen.cursor()).filter(|_| state.screen.cursor_visible && state.screen.view_offset() == 0);
+    let mut lines = Vec::with_capacity(rows);
+    for row in 0..rows {
+        let mut spans: Vec<Span> = Vec::new();
+        let mut text = String::new();
+        let mut style = Style::new();
+        for (col, cell) in state.screen.visible_row(row).iter().take(cols).enumerate() {
+            let mut cell_style = cell_style(cell);
+            if cursor == Some((col, row)) {
+                cell_style = cell_style.add_modifier(Modifier::REVERSED);
+            }
+            // Runs of cells with the same look become one span
+            if cell_style != style && !text.is_empty() {
+                spans.push(Span::styled(std::mem::take(&mut text), style));
+            }
+            style = cell_style;
+            text.push(cell.ch);
+        }
+        spans.push(Span::styled(text, style));
+        lines.push(Line::from(spans));
+    }
+    frame.render_widget(Paragraph::new(lines).block(block), area);
+}
+
+fn cell_style(cell: &Cell) -> Style {
+    let mut style = Style::new().fg(term_color(cell.fg)).bg(term_color(cell.bg));
+    if cell.bold {
+        style = style.add_modifier(Modifier::BOLD);
+    }
+    if cell.underline {
+        style = style.add_modifier(Modifier::UNDERLINED);
+    }
+    if cell.inverse {
+        style = style.add_modifier(Modifier::REVERSED);
+    }
+    style
+}
+
+fn term_color(color: TermColor) -> Color {
+    match color {
+        TermColor::Default => Color::Reset,
+        TermColor::Indexed(i) => Color::Indexed(i),
+        TermColor::Rgb(r, g, b) => Color::Rgb(r, g, b),
+    }
+}
+
+// Bytes a key press sends to the device, following xterm
+fn key_bytes(key: KeyEvent, app_cursor_keys: bool) -> Option<Vec<u8>> {
+    let cursor = |c: char| {
+        let prefix = if app_cursor_keys { "\x1bO" } else { "\x1b[" };
+        Some(format!("{}{}", prefix, c).into_bytes())
+    };
+    let seq = |s: &str| Some(s.as_bytes().to_vec());
+    let bytes = match key.code {
+        KeyCode::Enter => seq("\r"),
+        KeyCode::Backspace => Some(vec![0x7f]),
+        KeyCode::Tab => seq("\t"),
+        KeyCode::BackTab => seq("\x1b[Z"),
+        KeyCode::Esc => seq("\x1b"),
+        KeyCode::Up => cursor('A'),
+        KeyCode::Down => cursor('B'),
+        KeyCode::Right => cursor('C'),
+        KeyCode::Left => cursor('D'),
+        KeyCode::Home => cursor('H'),
+        KeyCode::End => cursor('F'),
+        KeyCode::Insert => seq("\x1b[2~"),
+        KeyCode::Delete => seq("\x1b[3~"),
+        KeyCode::PageUp => seq("\x1b[5~"),
+        KeyCode::PageDown => seq("\x1b[6~"),
+        KeyCode::F(n) => {
+            const CODES: [&str; 12] = ["OP", "OQ", "OR", "OS", "[15~", "[17~", "[18~", "[19~", "[20~", "[21~", "[23~", "[24~"];
+            let code = CODES.get(n.checked_sub(1)? as usize)?;
+            Some(format!("\x1b{}", code).into_bytes())
+        }
+        KeyCode::Char(c) if key.modifiers.contains(KeyModifiers::CONTROL) => Some(vec![control_char(c)?]),
+        KeyCode::Char(c) => Some(c.to_string().into_bytes()),
+        _ => None,
+    }?;
+    // Alt sends the key with an Esc in front
+    if key.modifiers.contains(KeyModifiers::ALT) {
+        return Some([b"\x1b".as_slice(), &bytes].concat());
+    }
+    Some(bytes)
+}
+
+// Ctrl plus a key. Terminals without keyboard enhancements report the
+// control characters past Ctrl-Z as Ctrl-2 to Ctrl-8.
+fn control_char(c: char) -> Option<u8> {
+    match c {
+        'a'..='z' | 'A'..='Z' => Some(c.to_ascii_lowercase() as u8 - b'a' + 1),
+        '@' | ' ' | '2' => Some(0),
+        '[' | '3' => Some(0x1b),
+        '\\' | '4' => Some(0x1c),
+        '^' | '6' => Some(0x1e),
+        '_' | '/' | '7' => Some(0x1f),
+        '?' | '8' => Some(0x7f),
+        _ => None,
+    }
+}
+
+// The flash phases in order with the current one highlighted, and the upload gauge
+fn draw_progress(frame: &mut Frame, area: Rect, flash: &FlashView) {
+    let block = Block::bordered().title(" Flash ");
+    let inner = block.inner(area);
+    frame.render_widget(block, area);
+    let [phases_area, gauge_area] = Layout::vertical([Constraint::Length(1), Constraint::Length(1)]).areas(inner);
+
+    let current = flash.phase.and_then(|phase| FlashPhase::ALL.iter().position(|p| *p == phase));
+    let mut spans = Vec::new();
+    for (index, phase) in FlashPhase::ALL.iter().enumerate() {
+        if index > 0 {
+            spans.push(Span::styled(" > ", Style::new().fg(Color::DarkGray)));
+        }
+        let style = match (current, flash.succeeded) {
+            (_, Some(true)) => Style::new().fg(Color::Green),
+            (Some(c), Some(false)) if c == index => Style::new().fg(Color::Red).add_modifier(Modifier::BOLD),
+            (Some(c), None) if c == index => Style::new().fg(Color::Yellow).add_modifier(Modifier::BOLD),
+            (Some(c), _) if index < c => Style::new().fg(Color::Green),
+            _ => Style::new().fg(Color::DarkGray),
+        };
+        spans.push(Span::styled(phase.label(), style));
+    }
+    frame.render_widget(Paragraph::new(Line::from(spans)), phases_area);
+
+    if flash.total > 0 {
+        let ratio = (flash.sent as f64 / flash.total as f64).clamp(0.0, 1.0);
+        let gauge = Gauge::default()
+            .gauge_style(Style::new().fg(Color::Green))
+            .ratio(ratio)
+            .label(format!("Upload {:.0}% ({} / {} bytes)", ratio * 100.0, flash.sent, flash.total));
+        frame.render_widget(gauge, gauge_area);
+    }
+}
+
+fn event_loop(terminal: &mut DefaultTerminal, state: &Arc<Mutex<State>>) -> Result<()> {
+    loop {
+        terminal.draw(|frame| draw(frame, &mut state.lock().unwrap()))?;
+        if event::poll(TICK)? {
+            if let Event::Key(key) = event::read()? {
+                if key.kind == KeyEventKind::Press {
+                    // The lock is released before a task starts, tasks take it themselves
+                    let task = state.lock().unwrap().on_key(key);
+                    if let Some(task) = task {
+                        start(state, task);
+                    }
+                }
+            }
+        }
+        if state.lock().unwrap().quit {
+            return Ok(());
+        }
+    }
+}
+
+// Send the log macros to a file, they would garble the screen
+pub(crate) fn log_to_file(logger: &mut env_logger::Builder) {
+    let file = config_dir().map(|dir| dir.join(LOG_FILE)).and_then(|path| {
+        fs::create_dir_all(path.parent()?).ok()?;
+        fs::File::create(path).ok()
+    });
+    match file {
+        Some(file) => {
+            logger.target(env_logger::Target::Pipe(Box::new(file)));
+        }
+        None => {
+            logger.filter_level(log::LevelFilter::Off);
+        }
+    }
+}
+
+// Run the terminal UI until the user quits. Like the GUI's event loop this blocks
+// the calling thread, device operations run as tasks on the runtime.
+pub(crate) fn run() {
+    let state = Arc::new(Mutex::new(State::new()));
+    if let Some(file) = &state.lock().unwrap().session_file {
+        info!("Session log: {}", file.path().display());
+    }
+    let mut terminal = ratatui::init();
+    let result = event_loop(&mut terminal, &state);
+    ratatui::restore();
+    let mut state = state.lock().unwrap();
+    state.close_shell();
+    if let Err(e) = result {
+        error!("error: {:?}", e);
+        eprintln!("Error: {:#}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(code: KeyCode, modifiers: KeyModifiers) -> KeyEvent {
+        KeyEvent::new(code, modifiers)
+    }
+
+    #[test]
+    fn control_keys_become_control_characters() {
+        assert_eq!(key_bytes(key(KeyCode::Char('c'), KeyModifiers::CONTROL), false), Some(vec![0x03]));
+        assert_eq!(key_bytes(key(KeyCode::Esc, KeyModifiers::NONE), false), Some(vec![0x1b]));
+        // Ctrl-\ as reported without keyboard enhancements
+        assert_eq!(key_bytes(key(KeyCode::Char('4'), KeyModifiers::CONTROL), false), Some(vec![0x1c]));
+    }
+
+    #[test]
+    fn cursor_keys_follow_the_application_mode() {
+        assert_eq!(key_bytes(key(KeyCode::Up, KeyModifiers::NONE), false), Some(b"\x1b[A".to_vec()));
+        assert_eq!(key_bytes(key(KeyCode::Up, KeyModifiers::NONE), true), Some(b"\x1bOA".to_vec()));
+    }
+
+    #[test]
+    fn alt_prefixes_escape() {
+        assert_eq!(key_bytes(key(KeyCode::Char('b'), KeyModifiers::ALT), false), Some(b"\x1bb".to_vec()));
+    }
+}
@@ -0,0 +1,155 @@
+use anyhow::{Context, Result};
+use argon2::Argon2;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use thiserror::Error;
+
+use crate::config_dir;
+use crate::flasher::Credentials;
+
+const VAULT_FILE: &str = "vault.bin";
+// File layout: magic | salt | nonce | ciphertext
+const MAGIC: &[u8; 4] = b"RFV1";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+
+#[derive(Debug, Error)]
+pub(crate) enum VaultError {
+    #[error("wrong master passphrase or corrupted vault")]
+    WrongPassphrase,
+    #[error("not a credential vault file")]
+    BadFormat,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct VaultData {
+    // Keyed by "ip:port"
+    #[serde(default)]
+    devices: HashMap<String, String>,
+    // Tried on every device after its own stored password
+    #[serde(default)]
+    pub fleet_passwords: Vec<String>,
+}
+
+pub(crate) struct Vault {
+    key: Key,
+    salt: [u8; SALT_LEN],
+    pub data: VaultData,
+}
+
+pub(crate) fn device_key(ip: &str, port: u16) -> String {
+    format!("{}:{}", ip, port)
+}
+
+fn derive_key(passphrase: &str, salt: &[u8]) -> Result<Key> {
+    let mut key = Key::default();
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| anyhow::anyhow!("key derivation failed: {}", e))?;
+    Ok(key)
+}
+
+impl Vault {
+    fn path() -> Option<PathBuf> {
+        config_dir().map(|dir| dir.join(VAULT_FILE))
+    }
+
+    pub fn exists() -> bool {
+        Self::path().map(|path| path.exists()).unwrap_or(false)
+    }
+
+    // Unlock the vault, or create an empty one protected by the passphrase if there is none yet
+    pub fn open(passphrase: &str) -> Result<Vault> {
+        if Self::exists() {
+            return Self::unlock(passphrase);
+        }
+        let mut salt = [0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        let vault = Vault {
+            key: derive_key(passphrase, &salt)?,
+            salt,
+            data: VaultData::default(),
+        };
+        vault.save()?;
+        Ok(vault)
+    }
+
+    fn unlock(passphrase: &str) -> Result<Vault> {
+        let path = Self::path().context("No config directory on this platform")?;
+        let raw = fs::read(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+        if raw.len() < MAGIC.len() + SALT_LEN + NONCE_LEN || &raw[..MAGIC.len()] != MAGIC {
+            return Err(VaultError::BadFormat.into());
+        }
+        let (salt_bytes, rest) = raw[MAGIC.len()..].split_at(SALT_LEN);
+        let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+        let key = derive_key(passphrase, salt_bytes)?;
+        let plaintext = ChaCha20Poly1305::new(&key)
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| VaultError::WrongPassphrase)?;
+        let data = serde_json::from_slice(&plaintext).map_err(|_| VaultError::BadFormat)?;
+        let mut salt = [0u8; SALT_LEN];
+        salt.copy_from_slice(salt_bytes);
+        info!("Credential vault unlocked");
+        Ok(Vault { key, salt, data })
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let path = Self::path().context("No config directory on this platform")?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+        let plaintext = serde_json::to_vec(&self.data)?;
+        // Fresh nonce on every write, the key stays the same
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = ChaCha20Poly1305::new(&self.key)
+            .encrypt(&nonce, plaintext.as_slice())
+            .map_err(|_| anyhow::anyhow!("vault encryption failed"))?;
+        let mut raw = Vec::with_capacity(MAGIC.len() + SALT_LEN + NONCE_LEN + ciphertext.len());
+        raw.extend_from_slice(MAGIC);
+        raw.extend_from_slice(&self.salt);
+        raw.extend_from_slice(&nonce);
+        raw.extend_from_slice(&ciphertext);
+        fs::write(&path, raw).with_context(|| format!("Failed to write {}", path.display()))?;
+        Ok(())
+    }
+
+    pub fn password_for(&self, device: &str) -> Option<&str> {
+        self.data.devices.get(device).map(|s| s.as_str())
+    }
+
+    // Returns true if the stored password changed
+    pub fn set_password(&mut self, device: &str, password: &str) -> bool {
+        if self.password_for(device) == Some(password) {
+            return false;
+        }
+        self.data.devices.insert(device.to_string(), password.to_string());
+        true
+    }
+
+    // Store the password that worked for a device, written right away when it changed
+    pub fn keep_password(&mut self, device: &str, password: &str) {
+        if self.set_password(device, password) {
+            if let Err(e) = self.save() {
+                error!("Failed to save credential vault: {:?}", e);
+            }
+        }
+    }
+}
+
+// Passwords to try for a device: typed, stored in the vault, then the fleet list
+pub(crate) fn credentials(typed: Option<&str>, vault: Option<&Vault>, ip: &str, port: u16) -> Credentials {
+    let mut candidates: Vec<String> = typed.map(|p| p.to_string()).into_iter().collect();
+    if let Some(vault) = vault {
+        if let Some(password) = vault.password_for(&device_key(ip, port)) {
+            candidates.push(password.to_string());
+        }
+        candidates.extend(vault.data.fleet_passwords.iter().cloned());
+    }
+    Credentials::new(candidates)
+}
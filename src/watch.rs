@@ -0,0 +1,231 @@
+use anyhow::{Context, Result};
+use log::{error, info};
+use std::collections::BTreeSet;
+use std::fs;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::batch::find_firmware;
+use crate::config_dir;
+use crate::discovery::probe;
+use crate::flasher::{self, Credentials, UnitInfo};
+use crate::session::DeviceSession;
+use crate::history::Operation;
+use crate::recipe::Recipe;
+use crate::resolve::resolve;
+
+const FLASHED_FILE: &str = "flashed_units.json";
+const POLL_INTERVAL: u64 = 2;
+
+#[derive(Debug, Clone)]
+pub(crate) enum WatchEvent {
+    // Nothing new on the bench
+    Waiting,
+    // A unit that was not flashed yet showed up
+    Started { host: String, identity: String, soc: String },
+    Log(String),
+    Succeeded { identity: String },
+    Failed { identity: String, error: String },
+}
+
+// Identities of every unit the bench has flashed, kept across restarts
+#[derive(Debug, Default)]
+pub(crate) struct FlashedUnits {
+    units: BTreeSet<String>,
+}
+
+impl FlashedUnits {
+    fn path() -> Option<PathBuf> {
+        config_dir().map(|dir| dir.join(FLASHED_FILE))
+    }
+
+    pub fn load() -> FlashedUnits {
+        let units = Self::path()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .and_then(|data| serde_json::from_str(&data).ok())
+            .unwrap_or_default();
+        FlashedUnits { units }
+    }
+
+    fn save(&self) -> Result<()> {
+        let path = Self::path().context("No config directory on this platform")?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(&path, serde_json::to_string_pretty(&self.units)?)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        Ok(())
+    }
+
+    pub fn contains(&self, identity: &str) -> bool {
+        self.units.contains(identity)
+    }
+
+    pub fn insert(&mut self, identity: &str) {
+        self.units.insert(identity.to_string());
+        if let Err(e) = self.save() {
+            error!("Failed to save flashed units: {:?}", e);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.units.len()
+    }
+}
+
+// A single host or a range of the last octet: `192.168.1.10` or `192.168.1.10-20`.
+// Only an IPv4 address before the '-' makes a range, host names may have hyphens.
+pub(crate) fn parse_targets(text: &str) -> Result<Vec<String>> {
+    let text = text.trim();
+    let range = text
+        .split_once('-')
+        .and_then(|(start, end)| Some((start.trim().parse::<Ipv4Addr>().ok()?, end)));
+    if let Some((start, end)) = range {
+        let end: u8 = end.trim().parse().context("The range must end with the last octet, e.g. 192.168.1.10-20")?;
+        let octets = start.octets();
+        if end < octets[3] {
+            return Err(anyhow::anyhow!("The range end is below its start"));
+        }
+        return Ok((octets[3]..=end)
+            .map(|last| Ipv4Addr::new(octets[0], octets[1], octets[2], last).to_string())
+            .collect());
+    }
+    if text.is_empty() {
+        return Err(anyhow::anyhow!("No address to watch"));
+    }
+    Ok(vec![text.to_string()])
+}
+
+async fn is_up(host: &str, port: u16) -> bool {
+    let addrs: Vec<SocketAddr> = match resolve(host, port).await {
+        Ok(addrs) => addrs,
+        Err(_) => return false,
+    };
+    for addr in addrs {
+        if probe(addr).await.is_some() {
+            return true;
+        }
+    }
+    false
+}
+
+async fn process_unit<F>(session: &DeviceSession, unit: &UnitInfo, recipe: &Recipe, firmware_dir: &Path, operation: &mut Operation, mut log: F) -> Result<()>
+where F: FnMut(&str) {
+    let firmware = find_firmware(firmware_dir, &unit.soc, recipe)?;
+    let src = firmware.to_string_lossy().to_string();
+    operation.set_firmware(&src);
+    log(&format!("Flashing {} ({}) with {}", unit.identity, unit.soc, src));
+    let before = flasher::flash(session, recipe, &src, &mut log, |_| {}).await?;
+    operation.set_unit_before(&before);
+    if !recipe.reboot {
+        return Ok(());
+    }
+    // Health check: the unit must come back with the same SoC and identity
+    let after = flasher::wait_for_reboot(session, &before.soc, &mut log, |_| {}).await?;
+    operation.set_unit_after(&after);
+    if after.identity != unit.identity {
+        return Err(anyhow::anyhow!("A different unit ({}) answered after the reboot", after.identity));
+    }
+    Ok(())
+}
+
+// Poll the targets forever and flash every unit that has not been flashed yet, until `stop` is set
+pub(crate) async fn watch<C, F>(targets: Vec<String>, port: u16, recipe: Recipe, firmware_dir: PathBuf, stop: Arc<AtomicBool>, credentials_for: C, mut event: F)
+where
+    C: Fn(&str) -> Credentials,
+    F: FnMut(WatchEvent),
+{
+    let mut flashed = FlashedUnits::load();
+    // Units handled in this session, failed ones are not retried until the watch restarts
+    let mut attempted = BTreeSet::new();
+    info!("Watching {:?}, {} units flashed so far", targets, flashed.len());
+    let mut waiting_reported = false;
+    while !stop.load(Ordering::Relaxed) {
+        for host in targets.iter() {
+            if stop.load(Ordering::Relaxed) {
+                break;
+            }
+            if !is_up(host, port).await {
+                continue;
+            }
+            let session = DeviceSession::new(host, port, credentials_for(host));
+            let unit = match flasher::identify_unit(&session, |_| {}).await {
+                Ok(unit) => unit,
+                Err(e) => {
+                    session.close().await;
+                    // Usually the unit is still booting
+                    info!("{} is up but could not be identified: {}", host, e);
+                    continue;
+                }
+            };
+            let identity = unit.identity.clone();
+            if flashed.contains(&identity) || attempted.contains(&identity) {
+                session.close().await;
+                continue;
+            }
+            attempted.insert(identity.clone());
+
+            waiting_reported = false;
+            event(WatchEvent::Started {
+                host: host.clone(),
+                identity: identity.clone(),
+                soc: unit.soc.clone(),
+            });
+            // The log lines go to the history record as well as to the caller
+            let mut log = String::new();
+            let mut operation = Operation::new("flash", host, port);
+            operation.set_unit_before(&unit);
+            let result = process_unit(&session, &unit, &recipe, &firmware_dir, &mut operation, |msg| {
+                log.push_str(msg);
+                log.push('\n');
+                event(WatchEvent::Log(msg.to_string()))
+            })
+            .await;
+            session.close().await;
+            operation.log(log.trim_end());
+            operation.finish(result.as_ref().err());
+            match result {
+                Ok(()) => {
+                    flashed.insert(&identity);
+                    event(WatchEvent::Succeeded { identity });
+                }
+                Err(e) => {
+                    error!("{}: {:?}", identity, e);
+                    event(WatchEvent::Failed {
+                        identity,
+                        error: format!("{:#}", e),
+                    });
+                }
+            }
+        }
+        if !waiting_reported {
+            event(WatchEvent::Waiting);
+            waiting_reported = true;
+        }
+        tokio::time::sleep(Duration::from_secs(POLL_INTERVAL)).await;
+    }
+    info!("Watch mode stopped");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_a_range_of_the_last_octet() {
+        let targets = parse_targets("192.168.1.10-12").unwrap();
+        assert_eq!(targets, ["192.168.1.10", "192.168.1.11", "192.168.1.12"]);
+        assert!(parse_targets("192.168.1.10-5").is_err());
+        assert!(parse_targets("192.168.1.10-x").is_err());
+    }
+
+    #[test]
+    fn keeps_hyphenated_host_names() {
+        assert_eq!(parse_targets(" openipc-ssc338q.local ").unwrap(), ["openipc-ssc338q.local"]);
+        assert_eq!(parse_targets("192.168.1.10").unwrap(), ["192.168.1.10"]);
+        assert!(parse_targets("").is_err());
+    }
+}